@@ -0,0 +1,3 @@
+DROP TRIGGER email_search_delete;
+DROP TABLE email_search;
+DROP TABLE email_search_documents;
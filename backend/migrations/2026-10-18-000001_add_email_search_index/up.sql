@@ -0,0 +1,56 @@
+-- Maps emails to the integer rowids of the full-text index. The rowid of `emails` itself is
+-- not stable because the table has a text primary key and VACUUM may renumber it.
+CREATE TABLE email_search_documents (
+    id INTEGER PRIMARY KEY NOT NULL,
+    email_id TEXT NOT NULL UNIQUE,
+    FOREIGN KEY (email_id) REFERENCES emails (id) ON DELETE CASCADE
+);
+
+-- The trigram tokenizer matches substrings, like the LIKE queries it replaces
+CREATE VIRTUAL TABLE email_search USING fts5(
+    subject,
+    sender,
+    recipients,
+    body_text,
+    body_html,
+    attachments,
+    tokenize = 'trigram'
+);
+
+-- Matches in the subject and addresses rank higher than matches in the body
+INSERT INTO email_search (email_search, rank) VALUES ('rank', 'bm25(10.0, 5.0, 5.0, 1.0, 0.5, 2.0)');
+
+CREATE TRIGGER email_search_delete BEFORE DELETE ON emails
+BEGIN
+    DELETE FROM email_search
+    WHERE rowid IN (SELECT id FROM email_search_documents WHERE email_id = old.id);
+END;
+
+INSERT INTO email_search_documents (email_id)
+SELECT id FROM emails ORDER BY created_at;
+
+INSERT INTO email_search (rowid, subject, sender, recipients, body_text, body_html, attachments)
+SELECT
+    email_search_documents.id,
+    COALESCE(emails.subject, ''),
+    emails.envelope_from || ' ' || COALESCE((
+        SELECT group_concat(headers.value, ' ') FROM headers
+        WHERE headers.email_id = emails.id AND LOWER(headers.name) = 'from'
+    ), ''),
+    COALESCE((
+        SELECT group_concat(envelope_recipients.email, ' ') FROM email_envelope_recipients
+        INNER JOIN envelope_recipients
+            ON envelope_recipients.id = email_envelope_recipients.envelope_recipient_id
+        WHERE email_envelope_recipients.email_id = emails.id
+    ), '') || ' ' || COALESCE((
+        SELECT group_concat(headers.value, ' ') FROM headers
+        WHERE headers.email_id = emails.id AND LOWER(headers.name) IN ('to', 'cc', 'bcc')
+    ), ''),
+    COALESCE(emails.body_text, ''),
+    COALESCE(emails.body_html, ''),
+    COALESCE((
+        SELECT group_concat(attachments.filename, ' ') FROM attachments
+        WHERE attachments.email_id = emails.id
+    ), '')
+FROM email_search_documents
+INNER JOIN emails ON emails.id = email_search_documents.email_id;
@@ -0,0 +1,2 @@
+DROP INDEX idx_emails_created_at_id;
+CREATE INDEX idx_emails_created_at ON emails(created_at DESC);
@@ -0,0 +1,3 @@
+-- Keyset pagination continues after (created_at, id), the old index only covered created_at
+DROP INDEX idx_emails_created_at;
+CREATE INDEX idx_emails_created_at_id ON emails(created_at DESC, id DESC);
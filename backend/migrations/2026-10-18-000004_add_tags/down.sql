@@ -0,0 +1,2 @@
+DROP TABLE email_tags;
+DROP TABLE tags;
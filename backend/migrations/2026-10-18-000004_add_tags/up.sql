@@ -0,0 +1,17 @@
+-- Tag names compare case-insensitively, "Bug" and "bug" are the same tag
+CREATE TABLE tags (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE email_tags (
+    email_id TEXT NOT NULL,
+    tag_id TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (email_id, tag_id),
+    FOREIGN KEY (email_id) REFERENCES emails (id) ON DELETE CASCADE,
+    FOREIGN KEY (tag_id) REFERENCES tags (id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_email_tags_tag_id ON email_tags(tag_id);
@@ -0,0 +1 @@
+ALTER TABLE emails ADD COLUMN starred BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+DROP INDEX idx_emails_thread_subject;
+DROP INDEX idx_emails_thread_id;
+DROP TABLE email_references;
+ALTER TABLE emails DROP COLUMN thread_subject;
+ALTER TABLE emails DROP COLUMN thread_id;
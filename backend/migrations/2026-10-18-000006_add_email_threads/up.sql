@@ -0,0 +1,17 @@
+-- Emails received before threading start a thread of their own
+ALTER TABLE emails ADD COLUMN thread_id TEXT NOT NULL DEFAULT '';
+UPDATE emails SET thread_id = id;
+-- Lowercased subject without Re:/Fwd: prefixes, for replies that lack In-Reply-To
+ALTER TABLE emails ADD COLUMN thread_subject TEXT;
+
+-- Message-IDs from In-Reply-To and References, so a late parent finds its replies
+CREATE TABLE email_references (
+    email_id TEXT NOT NULL,
+    message_id TEXT NOT NULL,
+    PRIMARY KEY (email_id, message_id),
+    FOREIGN KEY (email_id) REFERENCES emails (id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_email_references_message_id ON email_references(message_id);
+CREATE INDEX idx_emails_thread_id ON emails(thread_id, created_at);
+CREATE INDEX idx_emails_thread_subject ON emails(thread_subject);
@@ -0,0 +1,4 @@
+DROP INDEX idx_headers_email_id_position;
+CREATE INDEX idx_headers_email_id ON headers(email_id);
+ALTER TABLE headers DROP COLUMN raw;
+ALTER TABLE headers DROP COLUMN position;
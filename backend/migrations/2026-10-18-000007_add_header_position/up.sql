@@ -0,0 +1,7 @@
+-- Headers were stored in hash order before, those rows all keep position 0
+ALTER TABLE headers ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+-- The whole field as received, NULL for emails saved before it was kept
+ALTER TABLE headers ADD COLUMN raw BLOB;
+
+DROP INDEX idx_headers_email_id;
+CREATE INDEX idx_headers_email_id_position ON headers(email_id, position);
@@ -0,0 +1 @@
+ALTER TABLE headers DROP COLUMN decoded;
@@ -0,0 +1,2 @@
+-- Unfolded value with RFC 2047 encoded-words decoded, NULL for emails saved before
+ALTER TABLE headers ADD COLUMN decoded TEXT;
@@ -0,0 +1,9 @@
+-- Problems found while parsing an email, e.g. a missing Date header or bare LF line endings
+CREATE TABLE email_warnings (
+    email_id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    code TEXT NOT NULL,
+    message TEXT NOT NULL,
+    PRIMARY KEY (email_id, position),
+    FOREIGN KEY (email_id) REFERENCES emails (id) ON DELETE CASCADE
+);
@@ -0,0 +1,10 @@
+-- RFC 5322 and MIME problems found in an email, e.g. duplicate singleton headers or broken boundaries
+CREATE TABLE email_lint_findings (
+    email_id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    severity TEXT NOT NULL,
+    code TEXT NOT NULL,
+    message TEXT NOT NULL,
+    PRIMARY KEY (email_id, position),
+    FOREIGN KEY (email_id) REFERENCES emails (id) ON DELETE CASCADE
+);
@@ -1,3 +1,4 @@
+use crate::db::retention::RetentionPolicy;
 use clap::Parser;
 use std::io;
 use std::net::SocketAddr;
@@ -42,6 +43,98 @@ pub struct Config {
     )]
     pub smtp_max_connections: usize,
 
+    #[arg(
+        long,
+        env = "SMTP_COMMAND_TIMEOUT",
+        default_value = "300",
+        help = "Seconds to wait for the next SMTP command before closing the connection"
+    )]
+    pub smtp_command_timeout: u64,
+
+    #[arg(
+        long,
+        env = "SMTP_DATA_TIMEOUT",
+        default_value = "180",
+        help = "Seconds to wait for the next line of message data before closing the connection"
+    )]
+    pub smtp_data_timeout: u64,
+
+    #[arg(
+        long,
+        env = "SMTP_SESSION_TIMEOUT",
+        default_value = "1800",
+        help = "Maximum duration of a single SMTP connection in seconds"
+    )]
+    pub smtp_session_timeout: u64,
+
+    #[arg(
+        long,
+        env = "STORAGE_QUEUE_SIZE",
+        default_value = "100",
+        help = "Maximum number of received emails waiting to be saved before SMTP clients are slowed down"
+    )]
+    pub storage_queue_size: usize,
+
+    #[arg(
+        long,
+        env = "RETENTION_MAX_AGE_DAYS",
+        help = "Delete emails older than this many days"
+    )]
+    pub retention_max_age_days: Option<u64>,
+
+    #[arg(
+        long,
+        env = "RETENTION_MAX_EMAILS",
+        help = "Keep only the newest N emails"
+    )]
+    pub retention_max_emails: Option<u64>,
+
+    #[arg(
+        long,
+        env = "RETENTION_MAX_EMAILS_PER_RECIPIENT",
+        help = "Keep only the newest N emails per recipient inbox"
+    )]
+    pub retention_max_emails_per_recipient: Option<u64>,
+
+    #[arg(
+        long,
+        env = "RETENTION_MAX_DATABASE_SIZE",
+        help = "Delete the oldest emails while the database holds more than this many MB"
+    )]
+    pub retention_max_database_size: Option<u64>,
+
+    #[arg(
+        long,
+        env = "MAINTENANCE_INTERVAL",
+        default_value = "60",
+        help = "Seconds between two maintenance runs (retention policy and space reclamation)"
+    )]
+    pub maintenance_interval: u64,
+
+    #[arg(
+        long,
+        env = "VACUUM_THRESHOLD",
+        default_value = "64",
+        help = "Reclaim free database space once it exceeds this many MB"
+    )]
+    pub vacuum_threshold: u64,
+
+    #[arg(
+        long,
+        env = "VACUUM_INTERVAL",
+        default_value = "3600",
+        help = "Seconds after which free database space is reclaimed regardless of its size"
+    )]
+    pub vacuum_interval: u64,
+
+    #[arg(
+        long,
+        env = "SHUTDOWN_TIMEOUT",
+        default_value = "8",
+        help = "Seconds that in-flight SMTP transfers get to finish when stopping"
+    )]
+    pub shutdown_timeout: u64,
+
     #[arg(
         long,
         env = "WEB_HOST",
@@ -60,6 +153,17 @@ pub struct Config {
 }
 
 impl Config {
+    pub fn retention_policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            max_age_days: self.retention_max_age_days,
+            max_emails: self.retention_max_emails,
+            max_emails_per_recipient: self.retention_max_emails_per_recipient,
+            max_database_size: self
+                .retention_max_database_size
+                .map(|mb| mb.saturating_mul(1024 * 1024)),
+        }
+    }
+
     pub fn smtp_socket_addr(&self) -> io::Result<SocketAddr> {
         resolve_socket_addr("SMTP", &self.smtp_host)
     }
@@ -89,11 +193,47 @@ impl Config {
             component = "config",
             "SMTP max connections: {}", self.smtp_max_connections
         );
+        info!(
+            component = "config",
+            "SMTP timeouts: command {}s, data {}s, session {}s",
+            self.smtp_command_timeout,
+            self.smtp_data_timeout,
+            self.smtp_session_timeout
+        );
+        info!(
+            component = "config",
+            "Storage queue size: {}", self.storage_queue_size
+        );
+        info!(
+            component = "config",
+            "Retention: max age {} days, max emails {}, max emails per recipient {}, max database size {} MB",
+            display_limit(self.retention_max_age_days),
+            display_limit(self.retention_max_emails),
+            display_limit(self.retention_max_emails_per_recipient),
+            display_limit(self.retention_max_database_size)
+        );
+        info!(
+            component = "config",
+            "Maintenance: every {}s, vacuum above {} MB free or every {}s",
+            self.maintenance_interval,
+            self.vacuum_threshold,
+            self.vacuum_interval
+        );
+        info!(
+            component = "config",
+            "Shutdown timeout: {}s", self.shutdown_timeout
+        );
         info!(component = "config", "Web host: {}", self.web_host);
         info!(component = "config", "Database URL: {}", self.database_url);
     }
 }
 
+fn display_limit(limit: Option<u64>) -> String {
+    limit
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| "unlimited".to_string())
+}
+
 fn resolve_socket_addr(kind: &str, raw_addr: &str) -> io::Result<SocketAddr> {
     let mut resolved = raw_addr.to_socket_addrs().map_err(|err| {
         io::Error::new(
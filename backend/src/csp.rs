@@ -1,5 +1,7 @@
+/// Policy for email content, also sent as a header with MIME parts
+pub const CSP: &str = "default-src 'none'; img-src 'self' data:; script-src 'none'; style-src 'unsafe-inline'; font-src data:; connect-src 'none'; frame-src 'none'; object-src 'none'; media-src data:; base-uri 'none';";
+
 pub fn inject_csp_meta_tag(html: String) -> String {
-    const CSP: &str = "default-src 'none'; img-src 'self' data:; script-src 'none'; style-src 'unsafe-inline'; font-src data:; connect-src 'none'; frame-src 'none'; object-src 'none'; media-src data:; base-uri 'none';";
     let csp_meta = format!(
         "<meta http-equiv=\"Content-Security-Policy\" content=\"{}\">",
         CSP
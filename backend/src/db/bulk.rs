@@ -0,0 +1,210 @@
+use diesel::prelude::*;
+
+use crate::db::emails::{delete_emails_by_id, existing_email_ids, matching_email_ids};
+use crate::db::search_query::SearchQuery;
+use crate::db::tag::update_tags;
+use crate::{db::DbConnection, schema, web::error::DieselError};
+
+/// Emails per statement, stays below SQLite's limit of bound parameters
+const CHUNK_SIZE: usize = 500;
+
+/// The emails a bulk action applies to
+pub enum BulkSelection {
+    Ids(Vec<String>),
+    Search(SearchQuery),
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum BulkAction {
+    MarkRead,
+    MarkUnread,
+    Delete,
+    AddTag { tag: String },
+    RemoveTag { tag: String },
+}
+
+/// Runs `action` on the selected emails in one transaction. Returns the ids of the emails that
+/// changed, e.g. only the unread ones for `MarkRead`.
+pub fn run_bulk_action(
+    conn: &mut DbConnection,
+    selection: &BulkSelection,
+    action: &BulkAction,
+) -> Result<Vec<String>, DieselError> {
+    conn.transaction(|conn| {
+        let email_ids = match selection {
+            BulkSelection::Ids(ids) => existing_email_ids(conn, ids)?,
+            BulkSelection::Search(search) => matching_email_ids(conn, search.expr.as_ref())?,
+        };
+
+        match action {
+            BulkAction::MarkRead => mark_read(conn, &email_ids, true),
+            BulkAction::MarkUnread => mark_read(conn, &email_ids, false),
+            BulkAction::Delete => {
+                delete_emails_by_id(conn, &email_ids)?;
+                Ok(email_ids)
+            }
+            BulkAction::AddTag { tag } => update_tags(conn, &email_ids, &[tag.as_str()], &[]),
+            BulkAction::RemoveTag { tag } => update_tags(conn, &email_ids, &[], &[tag.as_str()]),
+        }
+    })
+}
+
+fn mark_read(
+    conn: &mut DbConnection,
+    email_ids: &[String],
+    read: bool,
+) -> Result<Vec<String>, DieselError> {
+    let mut changed = Vec::new();
+    for chunk in email_ids.chunks(CHUNK_SIZE) {
+        let emails = schema::emails::table
+            .filter(schema::emails::id.eq_any(chunk))
+            .filter(schema::emails::read.ne(read));
+        let mut chunk_ids: Vec<String> = emails.clone().select(schema::emails::id).load(conn)?;
+        diesel::update(emails)
+            .set(schema::emails::read.eq(read))
+            .execute(conn)?;
+        changed.append(&mut chunk_ids);
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+    use crate::db::{save_email::save_emails, tag::list_tags};
+
+    /// Saves one email per recipient list and returns their ids
+    fn save(conn: &mut DbConnection, recipients: &[&[&str]]) -> Vec<String> {
+        let emails: Vec<_> = recipients
+            .iter()
+            .map(|to| testing::email("Hi", to))
+            .collect();
+        save_emails(conn, &emails.iter().collect::<Vec<_>>()).unwrap();
+        emails.iter().map(|email| email.id.to_string()).collect()
+    }
+
+    fn recipient_counts(conn: &mut DbConnection) -> Vec<(String, i32)> {
+        schema::envelope_recipients::table
+            .select((
+                schema::envelope_recipients::email,
+                schema::envelope_recipients::email_count,
+            ))
+            .order(schema::envelope_recipients::email.asc())
+            .load(conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_mark_read_returns_only_changed_emails() {
+        let pool = testing::pool();
+        let mut conn = pool.get().unwrap();
+        let ids = save(&mut conn, &[&["a@x.com"], &["a@x.com"], &["b@x.com"]]);
+
+        let first = BulkSelection::Ids(vec![ids[0].clone()]);
+        assert_eq!(
+            run_bulk_action(&mut conn, &first, &BulkAction::MarkRead).unwrap(),
+            vec![ids[0].clone()]
+        );
+
+        let all = BulkSelection::Search(SearchQuery::default());
+        let mut changed = run_bulk_action(&mut conn, &all, &BulkAction::MarkRead).unwrap();
+        changed.sort();
+        let mut expected = vec![ids[1].clone(), ids[2].clone()];
+        expected.sort();
+        assert_eq!(changed, expected);
+        assert!(
+            run_bulk_action(&mut conn, &all, &BulkAction::MarkRead)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_delete_keeps_recipient_counts() {
+        let pool = testing::pool();
+        let mut conn = pool.get().unwrap();
+        let ids = save(
+            &mut conn,
+            &[&["a@x.com", "b@x.com"], &["a@x.com"], &["b@x.com"]],
+        );
+
+        let selection = BulkSelection::Ids(vec![ids[0].clone(), ids[1].clone(), "gone".into()]);
+        let mut deleted = run_bulk_action(&mut conn, &selection, &BulkAction::Delete).unwrap();
+        deleted.sort();
+        let mut expected = vec![ids[0].clone(), ids[1].clone()];
+        expected.sort();
+        assert_eq!(deleted, expected);
+        assert_eq!(
+            recipient_counts(&mut conn),
+            vec![("a@x.com".to_string(), 0), ("b@x.com".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_tag_actions_change_only_tags() {
+        let pool = testing::pool();
+        let mut conn = pool.get().unwrap();
+        let ids = save(&mut conn, &[&["a@x.com"], &["a@x.com"]]);
+        let all = BulkSelection::Search(SearchQuery::default());
+
+        let add = BulkAction::AddTag { tag: "bug".into() };
+        assert_eq!(run_bulk_action(&mut conn, &all, &add).unwrap().len(), 2);
+        assert_eq!(list_tags(&mut conn).unwrap()[0].count, 2);
+
+        let first = BulkSelection::Ids(vec![ids[0].clone()]);
+        let remove = BulkAction::RemoveTag { tag: "bug".into() };
+        assert_eq!(
+            run_bulk_action(&mut conn, &first, &remove).unwrap(),
+            vec![ids[0].clone()]
+        );
+        assert!(
+            run_bulk_action(&mut conn, &first, &remove)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(list_tags(&mut conn).unwrap()[0].count, 1);
+        assert_eq!(
+            recipient_counts(&mut conn),
+            vec![("a@x.com".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_failed_action_changes_nothing() {
+        let pool = testing::pool();
+        let mut conn = pool.get().unwrap();
+        let ids = save(&mut conn, &[&["a@x.com"], &["a@x.com"]]);
+        // Fails the action after the tag was created
+        diesel::sql_query(format!(
+            "CREATE TRIGGER fail_tagging BEFORE INSERT ON email_tags \
+             WHEN NEW.email_id = '{}' BEGIN SELECT RAISE(ABORT, 'no tags'); END",
+            ids[1]
+        ))
+        .execute(&mut conn)
+        .unwrap();
+
+        let all = BulkSelection::Search(SearchQuery::default());
+        let add = BulkAction::AddTag { tag: "bug".into() };
+        assert!(run_bulk_action(&mut conn, &all, &add).is_err());
+        assert!(list_tags(&mut conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_actions_are_tagged_by_name() {
+        let action: BulkAction = serde_json::from_str(r#"{"action": "mark_unread"}"#).unwrap();
+        assert_eq!(action, BulkAction::MarkUnread);
+
+        let action: BulkAction =
+            serde_json::from_str(r#"{"action": "add_tag", "tag": "bug"}"#).unwrap();
+        assert_eq!(
+            action,
+            BulkAction::AddTag {
+                tag: "bug".to_string()
+            }
+        );
+
+        assert!(serde_json::from_str::<BulkAction>(r#"{"action": "remove_tag"}"#).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use diesel::{ExpressionMethods, QueryDsl, Queryable, RunQueryDsl};
 
+use crate::db::saved_search::saved_search_stats;
 use crate::{db::DbConnection, schema, web::error::DieselError};
 
 #[derive(serde::Serialize, Clone, Queryable)]
@@ -8,11 +9,20 @@ pub struct RecipientStats {
     pub count: i64,
 }
 
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct SavedSearchStats {
+    pub id: String,
+    pub name: String,
+    pub count: i64,
+    pub unread: i64,
+}
+
 #[derive(serde::Serialize, Clone)]
 pub struct EmailStats {
     pub inbox: i64,
     pub unread: i64,
     pub recipients: Vec<RecipientStats>,
+    pub saved_searches: Vec<SavedSearchStats>,
 }
 
 pub fn get_email_counts(conn: &mut DbConnection) -> Result<EmailStats, DieselError> {
@@ -42,5 +52,6 @@ pub fn get_email_counts(conn: &mut DbConnection) -> Result<EmailStats, DieselErr
         inbox: total_count,
         unread: unread_count,
         recipients: recipients_stats,
+        saved_searches: saved_search_stats(conn)?,
     })
 }
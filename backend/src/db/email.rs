@@ -4,9 +4,12 @@ use crate::{
     compression,
     db::{
         AttachmentPartial, AttachmentRecord, DbConnection, DbError, EmailPartial, EmailRecord,
-        vacuum_database,
+        HeaderRecord,
     },
-    models::Header,
+    diagnostics::ParseWarning,
+    header::AddressHeaders,
+    lint::LintFinding,
+    models::{EmailLintFinding, Header},
     schema,
 };
 
@@ -27,16 +30,35 @@ pub fn get_email(conn: &mut DbConnection, email_id: &str) -> Result<EmailRecord,
 
     let headers: Vec<Header> = schema::headers::table
         .filter(schema::headers::email_id.eq(&email.id))
+        .order(schema::headers::position.asc())
         .load::<Header>(conn)?;
 
+    let warnings = schema::email_warnings::table
+        .filter(schema::email_warnings::email_id.eq(&email.id))
+        .order(schema::email_warnings::position.asc())
+        .select((
+            schema::email_warnings::code,
+            schema::email_warnings::message,
+        ))
+        .load::<(String, String)>(conn)?
+        .into_iter()
+        .map(|(code, message)| ParseWarning { code, message })
+        .collect();
+
+    let tags = crate::db::tag::load_tags_for_emails(conn, std::slice::from_ref(&email.id))?
+        .remove(&email.id)
+        .unwrap_or_default();
+
     let mut grouped_headers: std::collections::HashMap<String, Vec<String>> =
         std::collections::HashMap::new();
-    for header in headers {
+    for header in &headers {
         grouped_headers
-            .entry(header.name)
-            .or_insert_with(Vec::new)
-            .push(header.value);
+            .entry(header.name.clone())
+            .or_default()
+            .push(header.value.clone());
     }
+    let ordered_headers = headers.into_iter().map(HeaderRecord::from).collect();
+    let addresses = AddressHeaders::from_headers(&grouped_headers);
 
     let attachment_records = attachments
         .into_iter()
@@ -57,17 +79,49 @@ pub fn get_email(conn: &mut DbConnection, email_id: &str) -> Result<EmailRecord,
         subject: email.subject,
         date: email.date,
         headers: grouped_headers,
+        ordered_headers,
+        addresses,
         created_at: email.created_at,
         envelope_from: email.envelope_from,
         size: email.size,
         body_text: email.body_text,
         body_html: email.body_html,
         read: email.read,
+        starred: email.starred,
+        thread_id: email.thread_id,
         recipients,
         attachments: attachment_records,
+        tags,
+        warnings,
     })
 }
 
+/// Flags to change on an email, `None` keeps the current value
+#[derive(AsChangeset, serde::Deserialize)]
+#[diesel(table_name = schema::emails)]
+pub struct EmailFlags {
+    pub read: Option<bool>,
+    pub starred: Option<bool>,
+}
+
+impl EmailFlags {
+    pub fn is_empty(&self) -> bool {
+        self.read.is_none() && self.starred.is_none()
+    }
+}
+
+pub fn update_email_flags(
+    conn: &mut DbConnection,
+    email_id: &str,
+    flags: &EmailFlags,
+) -> Result<usize, DbError> {
+    Ok(
+        diesel::update(schema::emails::table.filter(schema::emails::id.eq(email_id)))
+            .set(flags)
+            .execute(conn)?,
+    )
+}
+
 pub fn mark_email_read(
     conn: &mut DbConnection,
     email_id: &str,
@@ -111,13 +165,45 @@ pub fn delete_email(conn: &mut DbConnection, email_id: &str) -> Result<usize, Db
         Ok(affected)
     })?;
 
-    if affected > 0 {
-        vacuum_database(conn).map_err(DbError::from)?;
-    }
-
     Ok(affected)
 }
 
+/// Id of the newest email with this Message-ID, angle brackets are optional
+pub fn find_email_id_by_message_id(
+    conn: &mut DbConnection,
+    message_id: &str,
+) -> Result<String, DbError> {
+    let message_id = crate::db::search_query::strip_angle_brackets(message_id);
+    Ok(schema::emails::table
+        .filter(schema::emails::message_id.eq(message_id))
+        .order((schema::emails::created_at.desc(), schema::emails::id.desc()))
+        .select(schema::emails::id)
+        .first::<String>(conn)?)
+}
+
+/// Lint findings of an email in the order they were found, NotFound if the email does not exist
+pub fn get_lint_findings(
+    conn: &mut DbConnection,
+    email_id: &str,
+) -> Result<Vec<LintFinding>, DbError> {
+    schema::emails::table
+        .filter(schema::emails::id.eq(email_id))
+        .select(schema::emails::id)
+        .first::<String>(conn)?;
+
+    Ok(schema::email_lint_findings::table
+        .filter(schema::email_lint_findings::email_id.eq(email_id))
+        .order(schema::email_lint_findings::position.asc())
+        .load::<EmailLintFinding>(conn)?
+        .into_iter()
+        .map(|finding| LintFinding {
+            severity: finding.severity,
+            code: finding.code,
+            message: finding.message,
+        })
+        .collect())
+}
+
 pub fn get_rendered_data(conn: &mut DbConnection, email_id: &str) -> Result<String, DbError> {
     let data = schema::emails::table
         .filter(schema::emails::id.eq(email_id))
@@ -1,147 +1,358 @@
 use diesel::prelude::*;
 use diesel::query_dsl::methods::FilterDsl;
-use diesel::{
-    BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper,
-};
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl};
 
-use crate::db::search_query::{ParsedSearchQuery, SearchField, parse_search_query};
+use diesel::sql_types::{BigInt, Double, Integer, Nullable};
+use std::collections::HashMap;
+
+use crate::db::ListQuery;
+use crate::db::pagination::{Cursor, SortKey, SortValue};
+use crate::db::search_index::{clear_index, load_snippets, reindex_emails};
+use crate::db::search_query::SearchExpr;
+use crate::db::search_sql::{SearchSql, SqlFragment, build_search_sql};
+use crate::db::tag::load_tags_for_emails;
 use crate::db::{EmailListPartial, EmailListRecord};
-use crate::db::{ListQuery, vacuum_database};
+use crate::header::{ADDRESS_HEADERS, AddressHeaders};
 use crate::{db::DbConnection, schema, web::error::DieselError};
 
-fn build_search_pattern(search_term: &str) -> String {
-    format!("%{}%", search_term)
+/// Emails per delete transaction, keeps the writer lock short while SMTP keeps saving
+const DELETE_CHUNK_SIZE: usize = 500;
+
+#[derive(QueryableByName)]
+struct CountResult {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
 }
 
-fn build_search_sql_condition(
-    search_query: &ParsedSearchQuery,
-    has_envelope_recipients: bool,
-) -> Option<String> {
-    if search_query.field_terms.is_empty() && search_query.default_terms.is_empty() {
-        return None;
-    }
+#[derive(QueryableByName)]
+struct IdResult {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    id: String,
+}
 
-    let mut sql_parts = Vec::new();
+#[derive(QueryableByName)]
+struct InboxLink {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    email_id: String,
+    #[diesel(sql_type = BigInt)]
+    recipient_count: i64,
+}
 
-    for term in &search_query.field_terms {
-        let pattern = build_search_pattern(&term.value);
-        let escaped_pattern = pattern.replace("'", "''");
+/// Result of emptying the inbox of one envelope recipient
+pub struct ClearedInbox {
+    /// Emails that had no other recipient
+    pub deleted: Vec<String>,
+    /// Emails that stay in the inboxes of their other recipients
+    pub unlinked: Vec<String>,
+}
 
-        let condition = match term.field {
-            SearchField::Subject => {
-                format!("emails.subject LIKE '{}'", escaped_pattern)
-            }
-            SearchField::From => {
-                format!(
-                    "(emails.envelope_from LIKE '{}' OR EXISTS (SELECT 1 FROM headers WHERE headers.email_id = emails.id AND LOWER(headers.name) = 'from' AND headers.value LIKE '{}'))",
-                    escaped_pattern, escaped_pattern
-                )
-            }
-            SearchField::Recipient | SearchField::To => {
-                let envelope_part = if has_envelope_recipients {
-                    format!("envelope_recipients.email LIKE '{}'", escaped_pattern)
-                } else {
-                    format!(
-                        "EXISTS (SELECT 1 FROM email_envelope_recipients INNER JOIN envelope_recipients ON email_envelope_recipients.envelope_recipient_id = envelope_recipients.id WHERE email_envelope_recipients.email_id = emails.id AND envelope_recipients.email LIKE '{}')",
-                        escaped_pattern
-                    )
-                };
-                format!(
-                    "({} OR EXISTS (SELECT 1 FROM headers WHERE headers.email_id = emails.id AND LOWER(headers.name) IN ('to', 'cc', 'bcc') AND headers.value LIKE '{}'))",
-                    envelope_part, escaped_pattern
-                )
-            }
-            SearchField::Text => {
-                format!("emails.body_text LIKE '{}'", escaped_pattern)
-            }
-            SearchField::Html => {
-                format!("emails.body_html LIKE '{}'", escaped_pattern)
-            }
-            SearchField::Attachment => {
-                format!(
-                    "EXISTS (SELECT 1 FROM attachments WHERE attachments.email_id = emails.id AND attachments.filename LIKE '{}')",
-                    escaped_pattern
-                )
-            }
-        };
-        sql_parts.push(format!("({})", condition));
+#[derive(QueryableByName)]
+struct EmailListRow {
+    #[diesel(embed)]
+    email: EmailListPartial,
+    #[diesel(sql_type = Nullable<Double>)]
+    rank: Option<f64>,
+    #[diesel(sql_type = Integer)]
+    size: i32,
+}
+
+/// One page of the email list
+pub struct EmailPage {
+    pub emails: Vec<EmailListRecord>,
+    /// `None` unless the query asked for counts
+    pub total_count: Option<u64>,
+    pub total_pages: Option<u64>,
+    /// Cursor for the page after this one, `None` on the last page
+    pub next_cursor: Option<String>,
+}
+
+pub fn get_emails(
+    conn: &mut DbConnection,
+    query_params: &ListQuery,
+) -> Result<EmailPage, DieselError> {
+    list_emails(conn, None, query_params)
+}
+
+/// Lists one page of emails, optionally limited to one envelope recipient.
+///
+/// Searches go through the `email_search` full-text index. When the index can match the
+/// terms, results carry a score and a highlighted snippet.
+///
+/// Pages continue after `query_params.cursor` when given, so emails arriving in between do not
+/// shift them; `page` and its `OFFSET` remain for clients that jump to a page number.
+fn list_emails(
+    conn: &mut DbConnection,
+    recipient_email: Option<&str>,
+    query_params: &ListQuery,
+) -> Result<EmailPage, DieselError> {
+    let search = build_search_sql(query_params.search.expr.as_ref());
+    let (from, mut conditions) = list_filter(recipient_email, &search);
+    if query_params.threads {
+        conditions = vec![latest_in_thread(from.clone(), conditions)];
     }
 
-    if !search_query.default_terms.is_empty() {
-        let mut default_parts = Vec::new();
-        for term in &search_query.default_terms {
-            let pattern = build_search_pattern(term);
-            let escaped_pattern = pattern.replace("'", "''");
-
-            let envelope_part = if has_envelope_recipients {
-                format!("envelope_recipients.email LIKE '{}'", escaped_pattern)
-            } else {
-                format!(
-                    "EXISTS (SELECT 1 FROM email_envelope_recipients INNER JOIN envelope_recipients ON email_envelope_recipients.envelope_recipient_id = envelope_recipients.id WHERE email_envelope_recipients.email_id = emails.id AND envelope_recipients.email LIKE '{}')",
-                    escaped_pattern
-                )
+    let mut page_conditions = conditions.clone();
+    if let Some(cursor) = &query_params.cursor {
+        page_conditions.push(query_params.sort.after(cursor));
+    }
+    let filter = where_clause(from.clone(), conditions);
+    let page_filter = where_clause(from, page_conditions);
+
+    let (total_count, total_pages) = if query_params.count {
+        let mut count_query = SqlFragment::new("SELECT COUNT(*) AS count");
+        count_query.push_fragment(filter);
+        let total_count = count_query
+            .into_query()
+            .get_result::<CountResult>(conn)?
+            .count as u64;
+        (
+            Some(total_count),
+            Some(total_count.div_ceil(query_params.per_page)),
+        )
+    } else {
+        (None, None)
+    };
+
+    let sort = query_params.sort;
+    let rank = if search.match_expression.is_some() {
+        "email_search.rank"
+    } else {
+        "NULL"
+    };
+    let mut page_query = SqlFragment::new(&format!(
+        "SELECT emails.id, emails.subject, emails.date, emails.created_at, \
+         emails.envelope_from, emails.read, emails.starred, emails.has_attachments, \
+         emails.thread_id, emails.size, {} AS rank",
+        rank
+    ));
+    page_query.push_fragment(page_filter);
+    // One extra row tells whether there is a next page
+    page_query
+        .push_sql(&format!(" ORDER BY {} LIMIT ", sort.order_by()))
+        .push_bind(query_params.per_page as i64 + 1);
+    if query_params.cursor.is_none() {
+        page_query
+            .push_sql(" OFFSET ")
+            .push_bind(((query_params.page - 1) * query_params.per_page) as i64);
+    }
+    let mut rows = page_query.into_query().load::<EmailListRow>(conn)?;
+
+    let next_cursor = if rows.len() as u64 > query_params.per_page {
+        rows.truncate(query_params.per_page as usize);
+        rows.last().map(|row| {
+            let value = match sort.key {
+                SortKey::Received => None,
+                SortKey::Date => Some(SortValue::Timestamp(
+                    row.email.date.unwrap_or(row.email.created_at),
+                )),
+                // SQLite's LOWER only folds ASCII
+                SortKey::Subject => Some(SortValue::Text(
+                    row.email
+                        .subject
+                        .clone()
+                        .unwrap_or_default()
+                        .to_ascii_lowercase(),
+                )),
+                SortKey::Sender => Some(SortValue::Text(
+                    row.email.envelope_from.to_ascii_lowercase(),
+                )),
+                SortKey::Size => Some(SortValue::Integer(row.size.into())),
+                SortKey::Relevance => row.rank.map(|rank| SortValue::Float(rank.to_bits())),
             };
+            Cursor {
+                sort,
+                value,
+                created_at: row.email.created_at,
+                id: row.email.id.clone(),
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
 
-            let default_condition = format!(
-                "(emails.subject LIKE '{}' OR emails.envelope_from LIKE '{}' OR emails.body_text LIKE '{}' OR {} OR EXISTS (SELECT 1 FROM headers WHERE headers.email_id = emails.id AND LOWER(headers.name) IN ('from', 'to', 'cc', 'bcc') AND headers.value LIKE '{}'))",
-                escaped_pattern, escaped_pattern, escaped_pattern, envelope_part, escaped_pattern
-            );
-            default_parts.push(format!("({})", default_condition));
+    let ranks: HashMap<String, f64> = rows
+        .iter()
+        .filter_map(|row| row.rank.map(|rank| (row.email.id.clone(), rank)))
+        .collect();
+    let emails = rows.into_iter().map(|row| row.email).collect();
+    let mut records = process_emails_with_recipients(conn, emails)?;
+
+    if query_params.threads {
+        let thread_ids: Vec<String> = records.iter().map(|r| r.thread_id.clone()).collect();
+        let mut sizes = thread_sizes(conn, &thread_ids)?;
+        for record in &mut records {
+            record.thread_size = sizes.remove(&record.thread_id);
         }
+    }
 
-        if !default_parts.is_empty() {
-            sql_parts.push(format!("({})", default_parts.join(" AND ")));
+    if let Some(match_expression) = &search.match_expression {
+        let email_ids: Vec<String> = records.iter().map(|r| r.id.clone()).collect();
+        let mut snippets = load_snippets(conn, match_expression, &email_ids)?;
+        for record in &mut records {
+            // bm25 is negative, with the best match being the most negative
+            record.score = ranks.get(&record.id).map(|rank| -rank);
+            record.snippet = snippets.remove(&record.id);
         }
     }
 
-    if !sql_parts.is_empty() {
-        Some(sql_parts.join(" AND "))
-    } else {
-        None
-    }
+    Ok(EmailPage {
+        emails: records,
+        total_count,
+        total_pages,
+        next_cursor,
+    })
 }
 
-pub fn get_emails(
-    conn: &mut DbConnection,
-    query_params: &ListQuery,
-) -> Result<(Vec<EmailListRecord>, u64), DieselError> {
-    let parsed_query = if let Some(search) = &query_params.search {
-        parse_search_query(search)
-    } else {
-        ParsedSearchQuery {
-            field_terms: Vec::new(),
-            default_terms: Vec::new(),
-        }
-    };
+/// `FROM` clause and conditions that select the listed emails
+pub(super) fn list_filter(
+    recipient_email: Option<&str>,
+    search: &SearchSql,
+) -> (SqlFragment, Vec<SqlFragment>) {
+    let mut from = SqlFragment::new(" FROM emails");
+    let mut conditions = Vec::new();
+    if let Some(recipient_email) = recipient_email {
+        from.push_sql(
+            " INNER JOIN email_envelope_recipients \
+             ON email_envelope_recipients.email_id = emails.id \
+             INNER JOIN envelope_recipients \
+             ON envelope_recipients.id = email_envelope_recipients.envelope_recipient_id",
+        );
+        let mut condition = SqlFragment::new("envelope_recipients.email = ");
+        condition.push_bind(recipient_email);
+        conditions.push(condition);
+    }
+    if search.uses_index() {
+        from.push_sql(
+            " INNER JOIN email_search_documents \
+             ON email_search_documents.email_id = emails.id \
+             INNER JOIN email_search ON email_search.rowid = email_search_documents.id",
+        );
+    }
+    if let Some(match_expression) = &search.match_expression {
+        let mut condition = SqlFragment::new("email_search MATCH ");
+        condition.push_bind(match_expression.as_str());
+        conditions.push(condition);
+    }
+    conditions.extend(search.condition.clone());
+    (from, conditions)
+}
 
-    let search_sql = build_search_sql_condition(&parsed_query, false);
+/// Keeps the latest matching email of each thread, the thread view of the list
+fn latest_in_thread(from: SqlFragment, conditions: Vec<SqlFragment>) -> SqlFragment {
+    let mut condition = SqlFragment::new(
+        "emails.id IN (SELECT id FROM (SELECT emails.id AS id, ROW_NUMBER() OVER (\
+         PARTITION BY emails.thread_id ORDER BY emails.created_at DESC, emails.id DESC\
+         ) AS position",
+    );
+    condition
+        .push_fragment(where_clause(from, conditions))
+        .push_sql(") WHERE position = 1)");
+    condition
+}
 
-    let build_query = move || {
-        use diesel::dsl::sql;
-        use diesel::sql_types::Bool;
+/// Number of emails in each of the threads
+fn thread_sizes(
+    conn: &mut DbConnection,
+    thread_ids: &[String],
+) -> Result<HashMap<String, i64>, DieselError> {
+    Ok(FilterDsl::filter(
+        schema::emails::table,
+        schema::emails::thread_id.eq_any(thread_ids),
+    )
+    .group_by(schema::emails::thread_id)
+    .select((schema::emails::thread_id, diesel::dsl::count_star()))
+    .load::<(String, i64)>(conn)?
+    .into_iter()
+    .collect())
+}
 
-        let mut query = schema::emails::table.into_boxed();
+/// All emails of one thread, oldest first
+pub fn thread_emails(
+    conn: &mut DbConnection,
+    thread_id: &str,
+) -> Result<Vec<EmailListRecord>, DieselError> {
+    let emails = FilterDsl::filter(
+        EmailListPartial::query(),
+        schema::emails::thread_id.eq(thread_id),
+    )
+    .order((schema::emails::created_at.asc(), schema::emails::id.asc()))
+    .load::<EmailListPartial>(conn)?;
+    process_emails_with_recipients(conn, emails)
+}
 
-        if let Some(ref sql_condition) = search_sql {
-            query = FilterDsl::filter(query, sql::<Bool>(sql_condition));
-        }
+#[derive(QueryableByName)]
+struct MatchCounts {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+    #[diesel(sql_type = BigInt)]
+    unread: i64,
+}
 
-        query
-    };
+/// Total and unread emails matching `search`, only among `email_ids` when given
+pub fn count_matching(
+    conn: &mut DbConnection,
+    search: Option<&SearchExpr>,
+    email_ids: Option<&[String]>,
+) -> Result<(i64, i64), DieselError> {
+    let search = build_search_sql(search);
+    let (from, mut conditions) = list_filter(None, &search);
+    if let Some(email_ids) = email_ids {
+        let mut condition = SqlFragment::new("emails.id IN ");
+        condition.push_fragment(SqlFragment::bind_list(email_ids));
+        conditions.push(condition);
+    }
 
-    let total_count: i64 = build_query().count().get_result(conn)?;
-    let num_pages = (total_count as f64 / query_params.per_page as f64).ceil() as u64;
+    let mut query =
+        SqlFragment::new("SELECT COUNT(*) AS count, COALESCE(SUM(emails.read = 0), 0) AS unread");
+    query.push_fragment(where_clause(from, conditions));
+    let counts = query.into_query().get_result::<MatchCounts>(conn)?;
+    Ok((counts.count, counts.unread))
+}
 
-    let emails = build_query()
-        .select(EmailListPartial::as_select())
-        .order(schema::emails::created_at.desc())
-        .offset(((query_params.page - 1) * query_params.per_page) as i64)
-        .limit(query_params.per_page as i64)
-        .load::<EmailListPartial>(conn)?;
+/// Ids of all emails matching `search`
+pub fn matching_email_ids(
+    conn: &mut DbConnection,
+    search: Option<&SearchExpr>,
+) -> Result<Vec<String>, DieselError> {
+    let search = build_search_sql(search);
+    let (from, conditions) = list_filter(None, &search);
+    let mut query = SqlFragment::new("SELECT emails.id AS id");
+    query.push_fragment(where_clause(from, conditions));
+    Ok(query
+        .into_query()
+        .load::<IdResult>(conn)?
+        .into_iter()
+        .map(|row| row.id)
+        .collect())
+}
 
-    let records = process_emails_with_recipients(conn, emails)?;
+/// The given ids that belong to an email, in their original order
+pub fn existing_email_ids(
+    conn: &mut DbConnection,
+    email_ids: &[String],
+) -> Result<Vec<String>, DieselError> {
+    let mut existing = std::collections::HashSet::new();
+    for chunk in email_ids.chunks(DELETE_CHUNK_SIZE) {
+        existing.extend(
+            FilterDsl::filter(schema::emails::table, schema::emails::id.eq_any(chunk))
+                .select(schema::emails::id)
+                .load::<String>(conn)?,
+        );
+    }
+    Ok(email_ids
+        .iter()
+        .filter(|id| existing.remove(*id))
+        .cloned()
+        .collect())
+}
 
-    Ok((records, num_pages))
+pub(super) fn where_clause(mut from: SqlFragment, conditions: Vec<SqlFragment>) -> SqlFragment {
+    if !conditions.is_empty() {
+        from.push_sql(" WHERE ")
+            .push_fragment(SqlFragment::join(conditions, " AND "));
+    }
+    from
 }
 
 fn process_emails_with_recipients(
@@ -150,8 +361,14 @@ fn process_emails_with_recipients(
 ) -> Result<Vec<EmailListRecord>, DieselError> {
     let email_ids: Vec<String> = emails.iter().map(|e| e.id.clone()).collect();
     let recipients_map = load_recipients_for_emails(conn, &email_ids)?;
-    let to_headers_map = load_to_headers_for_emails(conn, &email_ids)?;
-    Ok(emails_to_records(emails, recipients_map, to_headers_map))
+    let address_headers_map = load_address_headers_for_emails(conn, &email_ids)?;
+    let tags_map = load_tags_for_emails(conn, &email_ids)?;
+    Ok(emails_to_records(
+        emails,
+        recipients_map,
+        address_headers_map,
+        tags_map,
+    ))
 }
 
 fn load_recipients_for_emails(
@@ -173,33 +390,40 @@ fn load_recipients_for_emails(
     for (email_id, recipient_email) in all_recipients {
         recipients_map
             .entry(email_id)
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(recipient_email);
     }
 
     Ok(recipients_map)
 }
 
-fn load_to_headers_for_emails(
+/// Values of the From, To, Cc and Reply-To headers by name, per email
+fn load_address_headers_for_emails(
     conn: &mut DbConnection,
     email_ids: &[String],
-) -> Result<std::collections::HashMap<String, Vec<String>>, DieselError> {
-    let to_headers = FilterDsl::filter(
+) -> Result<HashMap<String, HashMap<String, Vec<String>>>, DieselError> {
+    let address_headers = FilterDsl::filter(
         schema::headers::table,
         schema::headers::email_id
             .eq_any(email_ids)
-            .and(schema::headers::name.eq("To")),
+            .and(schema::headers::name.eq_any(ADDRESS_HEADERS)),
     )
-    .select((schema::headers::email_id, schema::headers::value))
-    .load::<(String, String)>(conn)?;
+    .order(schema::headers::position.asc())
+    .select((
+        schema::headers::email_id,
+        schema::headers::name,
+        schema::headers::value,
+    ))
+    .load::<(String, String, String)>(conn)?;
 
-    let mut headers_map: std::collections::HashMap<String, Vec<String>> =
-        std::collections::HashMap::new();
-    for (email_id, header_value) in to_headers {
+    let mut headers_map: HashMap<String, HashMap<String, Vec<String>>> = HashMap::new();
+    for (email_id, name, value) in address_headers {
         headers_map
             .entry(email_id)
-            .or_insert_with(Vec::new)
-            .push(header_value);
+            .or_default()
+            .entry(name)
+            .or_default()
+            .push(value);
     }
 
     Ok(headers_map)
@@ -208,14 +432,17 @@ fn load_to_headers_for_emails(
 fn emails_to_records(
     emails: Vec<EmailListPartial>,
     mut recipients_map: std::collections::HashMap<String, Vec<String>>,
-    mut to_headers_map: std::collections::HashMap<String, Vec<String>>,
+    mut address_headers_map: HashMap<String, HashMap<String, Vec<String>>>,
+    mut tags_map: std::collections::HashMap<String, Vec<String>>,
 ) -> Vec<EmailListRecord> {
     emails
         .into_iter()
         .map(|email| {
             let id_clone = email.id.clone();
             let recipients = recipients_map.remove(&id_clone).unwrap_or_default();
-            let to_header = to_headers_map.remove(&id_clone);
+            let headers = address_headers_map.remove(&id_clone).unwrap_or_default();
+            let to_header = headers.get("To").cloned();
+            let tags = tags_map.remove(&id_clone).unwrap_or_default();
             EmailListRecord {
                 id: email.id,
                 subject: email.subject,
@@ -223,9 +450,16 @@ fn emails_to_records(
                 created_at: email.created_at,
                 envelope_from: email.envelope_from,
                 read: email.read,
+                starred: email.starred,
                 has_attachments: email.has_attachments,
+                thread_id: email.thread_id,
+                thread_size: None,
                 recipients,
                 to_header,
+                addresses: AddressHeaders::from_headers(&headers),
+                tags,
+                score: None,
+                snippet: None,
             }
         })
         .collect()
@@ -251,58 +485,13 @@ pub fn get_emails_by_recipient(
     conn: &mut DbConnection,
     recipient_email: &str,
     query_params: &ListQuery,
-) -> Result<(Vec<EmailListRecord>, u64), DieselError> {
-    let parsed_query = if let Some(search) = &query_params.search {
-        parse_search_query(search)
-    } else {
-        ParsedSearchQuery {
-            field_terms: Vec::new(),
-            default_terms: Vec::new(),
-        }
-    };
-
-    let search_sql = build_search_sql_condition(&parsed_query, true);
-
-    let build_query = move || {
-        use diesel::dsl::sql;
-        use diesel::sql_types::Bool;
-
-        let mut query = FilterDsl::filter(
-            schema::emails::table
-                .inner_join(schema::email_envelope_recipients::table)
-                .inner_join(
-                    schema::envelope_recipients::table
-                        .on(schema::email_envelope_recipients::envelope_recipient_id
-                            .eq(schema::envelope_recipients::id)),
-                ),
-            schema::envelope_recipients::email.eq(recipient_email),
-        )
-        .into_boxed();
-
-        if let Some(ref sql_condition) = search_sql {
-            query = FilterDsl::filter(query, sql::<Bool>(sql_condition));
-        }
-
-        query
-    };
-
-    let total_count: i64 = build_query().count().get_result(conn)?;
-    let num_pages = (total_count as f64 / query_params.per_page as f64).ceil() as u64;
-
-    let emails = build_query()
-        .select(EmailListPartial::as_select())
-        .order(schema::emails::created_at.desc())
-        .offset(((query_params.page - 1) * query_params.per_page) as i64)
-        .limit(query_params.per_page as i64)
-        .load::<EmailListPartial>(conn)?;
-
-    let records = process_emails_with_recipients(conn, emails)?;
-
-    Ok((records, num_pages))
+) -> Result<EmailPage, DieselError> {
+    list_emails(conn, Some(recipient_email), query_params)
 }
 
 pub fn delete_all_emails(conn: &mut DbConnection) -> Result<usize, DieselError> {
-    let affected = conn.transaction::<_, DieselError, _>(|conn| {
+    conn.transaction::<_, DieselError, _>(|conn| {
+        clear_index(conn)?;
         let affected = diesel::delete(schema::emails::table).execute(conn)?;
 
         if affected > 0 {
@@ -312,11 +501,107 @@ pub fn delete_all_emails(conn: &mut DbConnection) -> Result<usize, DieselError>
         }
 
         Ok(affected)
-    })?;
+    })
+}
+
+/// Empties the inbox of one envelope recipient. Emails sent to it alone are deleted, emails
+/// shared with other recipients only lose the link and stay in the other inboxes.
+pub fn clear_recipient_inbox(
+    conn: &mut DbConnection,
+    recipient_email: &str,
+) -> Result<ClearedInbox, DieselError> {
+    conn.transaction(|conn| {
+        let recipient_id: String = FilterDsl::filter(
+            schema::envelope_recipients::table,
+            schema::envelope_recipients::email.eq(recipient_email),
+        )
+        .select(schema::envelope_recipients::id)
+        .first(conn)?;
+
+        let links = diesel::sql_query(
+            "SELECT links.email_id AS email_id, \
+             (SELECT COUNT(*) FROM email_envelope_recipients AS other \
+             WHERE other.email_id = links.email_id) AS recipient_count \
+             FROM email_envelope_recipients AS links WHERE links.envelope_recipient_id = ?",
+        )
+        .bind::<diesel::sql_types::Text, _>(&recipient_id)
+        .load::<InboxLink>(conn)?;
+        let (deleted, unlinked): (Vec<InboxLink>, Vec<InboxLink>) = links
+            .into_iter()
+            .partition(|link| link.recipient_count <= 1);
+        let deleted: Vec<String> = deleted.into_iter().map(|link| link.email_id).collect();
+        let unlinked: Vec<String> = unlinked.into_iter().map(|link| link.email_id).collect();
+
+        delete_emails_by_id(conn, &deleted)?;
+        for chunk in unlinked.chunks(DELETE_CHUNK_SIZE) {
+            diesel::delete(FilterDsl::filter(
+                FilterDsl::filter(
+                    schema::email_envelope_recipients::table,
+                    schema::email_envelope_recipients::envelope_recipient_id.eq(&recipient_id),
+                ),
+                schema::email_envelope_recipients::email_id.eq_any(chunk),
+            ))
+            .execute(conn)?;
+        }
+        refresh_recipient_counts(conn, std::slice::from_ref(&recipient_id))?;
+        // `to:` must no longer find the address on the remaining emails
+        reindex_emails(conn, &unlinked)?;
 
-    if affected > 0 {
-        vacuum_database(conn)?;
+        Ok(ClearedInbox { deleted, unlinked })
+    })
+}
+
+/// Deletes the given emails in chunks and keeps the cached `email_count` of their envelope
+/// recipients in sync. Returns the number of deleted emails.
+pub fn delete_emails_by_id(
+    conn: &mut DbConnection,
+    email_ids: &[String],
+) -> Result<usize, DieselError> {
+    let mut affected = 0;
+
+    for chunk in email_ids.chunks(DELETE_CHUNK_SIZE) {
+        affected += conn.transaction::<_, DieselError, _>(|conn| {
+            let recipient_ids: Vec<String> = FilterDsl::filter(
+                schema::email_envelope_recipients::table,
+                schema::email_envelope_recipients::email_id.eq_any(chunk),
+            )
+            .select(schema::email_envelope_recipients::envelope_recipient_id)
+            .distinct()
+            .load(conn)?;
+
+            let affected = diesel::delete(FilterDsl::filter(
+                schema::emails::table,
+                schema::emails::id.eq_any(chunk),
+            ))
+            .execute(conn)?;
+
+            refresh_recipient_counts(conn, &recipient_ids)?;
+            Ok(affected)
+        })?;
     }
 
     Ok(affected)
 }
+
+/// Recomputes the cached `email_count` of the given envelope recipients from the link table.
+pub fn refresh_recipient_counts(
+    conn: &mut DbConnection,
+    recipient_ids: &[String],
+) -> Result<(), DieselError> {
+    for chunk in recipient_ids.chunks(DELETE_CHUNK_SIZE) {
+        diesel::update(FilterDsl::filter(
+            schema::envelope_recipients::table,
+            schema::envelope_recipients::id.eq_any(chunk),
+        ))
+        .set(
+            schema::envelope_recipients::email_count.eq(diesel::dsl::sql::<
+                diesel::sql_types::Integer,
+            >(
+                "(SELECT COUNT(*) FROM email_envelope_recipients WHERE email_envelope_recipients.envelope_recipient_id = envelope_recipients.id)",
+            )),
+        )
+        .execute(conn)?;
+    }
+
+    Ok(())
+}
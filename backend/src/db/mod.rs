@@ -2,8 +2,10 @@ use crate::schema;
 use crate::web::error::DieselError;
 use ::r2d2::PooledConnection;
 use chrono::NaiveDateTime;
+use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
+use diesel_migrations::{EmbeddedMigrations, embed_migrations};
 use std::sync::Arc;
 
 #[derive(Debug)]
@@ -52,13 +54,22 @@ impl From<DbError> for crate::web::error::WebError {
 }
 
 pub mod attachment;
+pub mod bulk;
 pub mod counts;
 pub mod email;
 pub mod emails;
+pub mod pagination;
+pub mod regexp;
+pub mod retention;
 pub mod save_email;
+pub mod saved_search;
+pub mod search_index;
 pub mod search_query;
+pub mod search_sql;
+pub mod tag;
+pub mod thread;
 
-#[derive(HasQuery, Clone)]
+#[derive(HasQuery, QueryableByName, Clone)]
 #[diesel(table_name = schema::emails)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct EmailListPartial {
@@ -68,7 +79,9 @@ pub struct EmailListPartial {
     pub created_at: NaiveDateTime,
     pub envelope_from: String,
     pub read: bool,
+    pub starred: bool,
     pub has_attachments: bool,
+    pub thread_id: String,
 }
 
 #[derive(HasQuery, Clone)]
@@ -85,6 +98,8 @@ pub struct EmailPartial {
     pub body_text: Option<String>,
     pub body_html: Option<String>,
     pub read: bool,
+    pub starred: bool,
+    pub thread_id: String,
 }
 
 #[derive(HasQuery, Clone)]
@@ -103,26 +118,170 @@ pub struct AttachmentPartial {
 pub type DbPool = Arc<r2d2::Pool<ConnectionManager<SqliteConnection>>>;
 pub type DbConnection = PooledConnection<ConnectionManager<SqliteConnection>>;
 
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// Per-connection settings, also registers the REGEXP function used by `re:` searches
+#[derive(Debug)]
+pub struct ConnectionOptions;
+
+impl diesel::r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error>
+    for ConnectionOptions
+{
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        let map_err = |e| diesel::r2d2::Error::QueryError(e);
+
+        let session_queries = [
+            "PRAGMA foreign_keys = ON;",
+            "PRAGMA busy_timeout = 5000;", // Increased to 5s for prod safety
+            "PRAGMA cache_size = -64000;", // ~64MB cache (negative number is in KiB)
+            "PRAGMA mmap_size = 268435456;", // 256MB Memory Mapping
+            "PRAGMA temp_store = MEMORY;", // Fast temp tables
+        ];
+
+        for query in session_queries {
+            conn.batch_execute(query).map_err(map_err)?;
+        }
+
+        regexp::register_regexp(conn).map_err(map_err)?;
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Default, serde::Deserialize)]
 pub struct ListParams {
     pub search: Option<String>,
     pub page: Option<u64>,
     pub per_page: Option<u64>,
+    /// `next_cursor` of the previous page, takes precedence over `page`
+    pub cursor: Option<String>,
+    pub sort: Option<String>,
+    pub order: Option<String>,
+    /// Set to false to skip counting all matching emails
+    pub count: Option<bool>,
+    /// `threads` lists the latest matching email of each thread
+    pub view: Option<String>,
 }
 
 pub struct ListQuery {
-    pub search: Option<String>,
+    pub search: search_query::SearchQuery,
+    pub sort: pagination::ListSort,
+    pub cursor: Option<pagination::Cursor>,
     pub page: u64,
     pub per_page: u64,
+    pub count: bool,
+    pub threads: bool,
 }
 
-impl From<ListParams> for ListQuery {
-    fn from(params: ListParams) -> Self {
-        Self {
-            search: params.search,
+#[derive(Debug, PartialEq)]
+pub enum ListParamsError {
+    Search(search_query::SearchParseError),
+    Sort(String),
+    Order(String),
+    View(String),
+    Cursor,
+}
+
+impl std::fmt::Display for ListParamsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListParamsError::Search(e) => write!(f, "{}", e),
+            ListParamsError::Sort(sort) => write!(
+                f,
+                "Unknown sort {}, use received, date, subject, sender, size or relevance",
+                sort
+            ),
+            ListParamsError::Order(order) => write!(f, "Unknown order {}, use asc or desc", order),
+            ListParamsError::View(view) => {
+                write!(f, "Unknown view {}, use emails or threads", view)
+            }
+            ListParamsError::Cursor => write!(
+                f,
+                "Invalid cursor for this sort order, start again from the first page"
+            ),
+        }
+    }
+}
+
+impl From<search_query::SearchParseError> for ListParamsError {
+    fn from(err: search_query::SearchParseError) -> Self {
+        ListParamsError::Search(err)
+    }
+}
+
+impl TryFrom<ListParams> for ListQuery {
+    type Error = ListParamsError;
+
+    fn try_from(params: ListParams) -> Result<Self, Self::Error> {
+        Self::within(search_query::SearchQuery::default(), params)
+    }
+}
+
+impl ListQuery {
+    /// Lists the emails matching `base`, narrowed down by the search in `params`
+    pub fn within(
+        base: search_query::SearchQuery,
+        params: ListParams,
+    ) -> Result<Self, ListParamsError> {
+        let search = match params.search.as_deref() {
+            Some(search) => base.and(search_query::parse_search_query(search)?),
+            None => base,
+        };
+
+        let key = match params.sort.as_deref() {
+            Some(sort) => Some(
+                pagination::SortKey::parse(sort)
+                    .ok_or_else(|| ListParamsError::Sort(sort.to_string()))?,
+            ),
+            None => None,
+        };
+        let direction = match params.order.as_deref() {
+            Some(order) => pagination::SortDirection::parse(order)
+                .ok_or_else(|| ListParamsError::Order(order.to_string()))?,
+            None => pagination::SortDirection::Desc,
+        };
+
+        // A `sort:` in the search wins over the parameters, relevance needs full-text terms
+        let has_match = search_sql::build_search_sql(search.expr.as_ref())
+            .match_expression
+            .is_some();
+        let mut sort = match (search.sort, key) {
+            (Some(order), _) => order.into(),
+            (None, Some(key)) => pagination::ListSort { key, direction },
+            (None, None) if has_match => pagination::ListSort {
+                key: pagination::SortKey::Relevance,
+                direction,
+            },
+            (None, None) => pagination::ListSort::default(),
+        };
+        if sort.key == pagination::SortKey::Relevance && !has_match {
+            sort = pagination::ListSort::default();
+        }
+
+        let threads = match params.view.as_deref() {
+            None | Some("emails") => false,
+            Some("threads") => true,
+            Some(view) => return Err(ListParamsError::View(view.to_string())),
+        };
+
+        let cursor = match params.cursor.as_deref() {
+            Some(cursor) => Some(
+                pagination::Cursor::decode(cursor)
+                    .filter(|cursor| cursor.fits(sort))
+                    .ok_or(ListParamsError::Cursor)?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            search,
+            sort,
+            cursor,
             page: params.page.unwrap_or(1).max(1),
             per_page: params.per_page.unwrap_or(20).clamp(1, 100),
-        }
+            count: params.count.unwrap_or(true),
+            threads,
+        })
     }
 }
 
@@ -133,14 +292,23 @@ pub struct EmailRecord {
     pub subject: Option<String>,
     pub date: Option<NaiveDateTime>,
     pub headers: std::collections::HashMap<String, Vec<String>>,
+    /// Every header field in message order, repeated names included
+    pub ordered_headers: Vec<HeaderRecord>,
+    #[serde(flatten)]
+    pub addresses: crate::header::AddressHeaders,
     pub created_at: NaiveDateTime,
     pub envelope_from: String,
     pub size: i32,
     pub body_text: Option<String>,
     pub body_html: Option<String>,
     pub read: bool,
+    pub starred: bool,
+    pub thread_id: String,
     pub recipients: Vec<String>,
     pub attachments: Vec<AttachmentRecord>,
+    pub tags: Vec<String>,
+    /// Problems found while parsing the email, empty for emails received before they were kept
+    pub warnings: Vec<crate::diagnostics::ParseWarning>,
 }
 
 #[derive(serde::Serialize, Clone, Debug)]
@@ -151,9 +319,23 @@ pub struct EmailListRecord {
     pub created_at: NaiveDateTime,
     pub envelope_from: String,
     pub read: bool,
+    pub starred: bool,
     pub has_attachments: bool,
+    pub thread_id: String,
+    /// Emails in the thread, only set in the thread view
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_size: Option<i64>,
     pub recipients: Vec<String>,
     pub to_header: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub addresses: crate::header::AddressHeaders,
+    pub tags: Vec<String>,
+    /// Search relevance, higher is better; only set for full-text searches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// Matching text with the search terms wrapped in `<mark>`, HTML escaped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
 }
 
 impl From<EmailRecord> for EmailListRecord {
@@ -166,8 +348,65 @@ impl From<EmailRecord> for EmailListRecord {
             envelope_from: record.envelope_from,
             recipients: record.recipients,
             read: record.read,
+            starred: record.starred,
             has_attachments: !record.attachments.is_empty(),
+            thread_id: record.thread_id,
+            thread_size: None,
             to_header: record.headers.get("To").cloned(),
+            addresses: record.addresses,
+            tags: record.tags,
+            score: None,
+            snippet: None,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct HeaderRecord {
+    /// Name with the casing used in the message
+    pub name: String,
+    pub value: String,
+    /// Unfolded value with RFC 2047 encoded-words decoded
+    pub decoded: String,
+    /// The whole field as received, including folding and the line break
+    pub raw: String,
+}
+
+impl From<&crate::header::RawHeader> for HeaderRecord {
+    fn from(header: &crate::header::RawHeader) -> Self {
+        let raw = String::from_utf8_lossy(&header.raw).into_owned();
+        let name = raw
+            .split_once(':')
+            .map(|(name, _)| name.trim_end().to_string())
+            .unwrap_or_else(|| header.name.clone());
+        Self {
+            name,
+            value: header.value.clone(),
+            decoded: header.decoded.clone(),
+            raw,
+        }
+    }
+}
+
+impl From<crate::models::Header> for HeaderRecord {
+    fn from(header: crate::models::Header) -> Self {
+        // Emails saved before raw fields were kept only have the parsed name and value
+        let raw = match header.raw {
+            Some(raw) => String::from_utf8_lossy(&raw).into_owned(),
+            None => format!("{}:{}", header.name, header.value),
+        };
+        let name = raw
+            .split_once(':')
+            .map(|(name, _)| name.trim_end().to_string())
+            .unwrap_or(header.name);
+        let decoded = header
+            .decoded
+            .unwrap_or_else(|| crate::header::decode_value(&header.value));
+        Self {
+            name,
+            value: header.value,
+            decoded,
+            raw,
         }
     }
 }
@@ -190,7 +429,101 @@ pub struct AttachmentContent {
     pub data: Vec<u8>,
 }
 
-pub fn vacuum_database(conn: &mut DbConnection) -> Result<(), DieselError> {
-    diesel::sql_query("VACUUM").execute(conn)?;
+/// `PRAGMA auto_vacuum` value of a database that can free pages with `incremental_vacuum`
+const AUTO_VACUUM_INCREMENTAL: i64 = 2;
+
+/// Switches the database to `auto_vacuum = INCREMENTAL`.
+///
+/// Files created before this setting need a one-time full `VACUUM` to pick it up, which
+/// happens here. Returns true if the file had to be converted.
+pub fn enable_incremental_vacuum(conn: &mut SqliteConnection) -> Result<bool, DieselError> {
+    let mode = diesel::sql_query("SELECT auto_vacuum AS value FROM pragma_auto_vacuum()")
+        .get_result::<PragmaValue>(conn)?;
+    if mode.value == AUTO_VACUUM_INCREMENTAL {
+        return Ok(false);
+    }
+
+    conn.batch_execute("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+    Ok(true)
+}
+
+/// Returns up to `pages` free pages to the file system.
+///
+/// `batch_execute` steps the pragma to completion, a plain `execute` would free a single page.
+pub fn incremental_vacuum(conn: &mut DbConnection, pages: u64) -> Result<(), DieselError> {
+    conn.batch_execute(&format!("PRAGMA incremental_vacuum({});", pages))?;
     Ok(())
 }
+
+#[derive(QueryableByName)]
+struct PragmaValue {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    value: i64,
+}
+
+/// Bytes of the database file that hold data, free pages waiting to be reclaimed are excluded
+pub fn used_database_size(conn: &mut DbConnection) -> Result<u64, DieselError> {
+    let size = diesel::sql_query(
+        "SELECT (page_count - freelist_count) * page_size AS value \
+         FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+    )
+    .get_result::<PragmaValue>(conn)?;
+    Ok(size.value.max(0) as u64)
+}
+
+/// Bytes held by free pages that `incremental_vacuum` can return to the file system
+pub fn free_database_size(conn: &mut DbConnection) -> Result<u64, DieselError> {
+    let size = diesel::sql_query(
+        "SELECT freelist_count * page_size AS value \
+         FROM pragma_freelist_count(), pragma_page_size()",
+    )
+    .get_result::<PragmaValue>(conn)?;
+    Ok(size.value.max(0) as u64)
+}
+
+/// In-memory databases and emails for tests of the storage code
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::smtp;
+    use diesel_migrations::MigrationHarness;
+
+    /// A migrated in-memory database. The pool holds a single connection that is never closed,
+    /// a second one would open an empty database.
+    pub fn pool() -> DbPool {
+        let manager = ConnectionManager::<SqliteConnection>::new(":memory:");
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connection_customizer(Box::new(ConnectionOptions))
+            .build(manager)
+            .unwrap();
+        let mut conn = pool.get().unwrap();
+        enable_incremental_vacuum(&mut conn).unwrap();
+        conn.run_pending_migrations(MIGRATIONS).unwrap();
+        drop(conn);
+        Arc::new(pool)
+    }
+
+    pub fn email(subject: &str, to: &[&str]) -> smtp::Email {
+        let data = format!("Subject: {}\r\n\r\nBody\r\n", subject);
+        smtp::Email {
+            id: uuid::Uuid::new_v4(),
+            message_id: None,
+            subject: Some(subject.to_string()),
+            date: None,
+            headers: None,
+            raw_headers: Vec::new(),
+            from: "sender@example.com".to_string(),
+            to: to.iter().map(|address| address.to_string()).collect(),
+            size: data.len() as u64,
+            data,
+            body_text: "Body".to_string(),
+            body_html: String::new(),
+            attachments: Vec::new(),
+            warnings: Vec::new(),
+            lint: Vec::new(),
+        }
+    }
+}
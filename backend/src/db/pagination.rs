@@ -0,0 +1,352 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::db::search_query::SortOrder;
+use crate::db::search_sql::{SqlFragment, SqlValue};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    /// When MailFang received the email
+    Received,
+    /// The `Date` header, emails without one use the time they were received
+    Date,
+    Subject,
+    Sender,
+    Size,
+    /// Best full-text matches first, only available for searches
+    Relevance,
+}
+
+impl SortKey {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "received" => Some(SortKey::Received),
+            "date" => Some(SortKey::Date),
+            "subject" => Some(SortKey::Subject),
+            "sender" | "from" => Some(SortKey::Sender),
+            "size" => Some(SortKey::Size),
+            "relevance" => Some(SortKey::Relevance),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortDirection {
+    Asc,
+    Desc,
+}
+
+impl SortDirection {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "asc" => Some(SortDirection::Asc),
+            "desc" => Some(SortDirection::Desc),
+            _ => None,
+        }
+    }
+
+    fn sql(self) -> &'static str {
+        match self {
+            SortDirection::Asc => "ASC",
+            SortDirection::Desc => "DESC",
+        }
+    }
+
+    /// Comparison that selects the rows after the cursor
+    fn after(self) -> &'static str {
+        match self {
+            SortDirection::Asc => ">",
+            SortDirection::Desc => "<",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListSort {
+    pub key: SortKey,
+    /// Ignored for `Relevance`, which always lists the best matches first
+    pub direction: SortDirection,
+}
+
+impl Default for ListSort {
+    fn default() -> Self {
+        Self {
+            key: SortKey::Received,
+            direction: SortDirection::Desc,
+        }
+    }
+}
+
+impl From<SortOrder> for ListSort {
+    fn from(order: SortOrder) -> Self {
+        let (key, direction) = match order {
+            SortOrder::Newest => (SortKey::Received, SortDirection::Desc),
+            SortOrder::Oldest => (SortKey::Received, SortDirection::Asc),
+            SortOrder::Largest => (SortKey::Size, SortDirection::Desc),
+            SortOrder::Smallest => (SortKey::Size, SortDirection::Asc),
+            SortOrder::Relevance => (SortKey::Relevance, SortDirection::Desc),
+        };
+        Self { key, direction }
+    }
+}
+
+impl ListSort {
+    /// Sort expressions with their direction. Every order ends with `created_at, id`, which makes
+    /// it total, so a cursor points at exactly one position.
+    fn columns(&self) -> Vec<(&'static str, SortDirection)> {
+        let direction = self.direction;
+        let value = match self.key {
+            SortKey::Received => None,
+            SortKey::Date => Some("COALESCE(emails.date, emails.created_at)"),
+            SortKey::Subject => Some("LOWER(COALESCE(emails.subject, ''))"),
+            SortKey::Sender => Some("LOWER(emails.envelope_from)"),
+            SortKey::Size => Some("emails.size"),
+            SortKey::Relevance => {
+                // bm25 is negative with the best match being the most negative, equally good
+                // matches list the newest first
+                return vec![
+                    ("email_search.rank", SortDirection::Asc),
+                    ("emails.created_at", SortDirection::Desc),
+                    ("emails.id", SortDirection::Desc),
+                ];
+            }
+        };
+
+        value
+            .map(|value| (value, direction))
+            .into_iter()
+            .chain([("emails.created_at", direction), ("emails.id", direction)])
+            .collect()
+    }
+
+    pub fn order_by(&self) -> String {
+        self.columns()
+            .iter()
+            .map(|(column, direction)| format!("{} {}", column, direction.sql()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Condition for the rows that come after `cursor`
+    pub fn after(&self, cursor: &Cursor) -> SqlFragment {
+        let columns = self.columns();
+        let values: Vec<SqlValue> = cursor
+            .value
+            .clone()
+            .map(SqlValue::from)
+            .into_iter()
+            .chain([
+                SqlValue::from(cursor.created_at),
+                SqlValue::from(cursor.id.as_str()),
+            ])
+            .collect();
+
+        // A row value comparison can use the index, mixed directions need the expanded form
+        if columns
+            .iter()
+            .all(|(_, direction)| *direction == columns[0].1)
+        {
+            let names: Vec<&str> = columns.iter().map(|(column, _)| *column).collect();
+            let mut fragment = SqlFragment::new(&format!(
+                "({}) {} (",
+                names.join(", "),
+                columns[0].1.after()
+            ));
+            for (i, value) in values.into_iter().enumerate() {
+                if i > 0 {
+                    fragment.push_sql(", ");
+                }
+                fragment.push_bind(value);
+            }
+            fragment.push_sql(")");
+            return fragment;
+        }
+
+        let mut condition: Option<SqlFragment> = None;
+        for ((column, direction), value) in columns.iter().zip(values).rev() {
+            let mut fragment = SqlFragment::new(&format!("{} {} ", column, direction.after()));
+            fragment.push_bind(value.clone());
+            if let Some(rest) = condition {
+                fragment
+                    .push_sql(&format!(" OR ({} = ", column))
+                    .push_bind(value)
+                    .push_sql(" AND ")
+                    .push_fragment(rest)
+                    .push_sql(")");
+            }
+            condition = Some(SqlFragment::join(vec![fragment], ""));
+        }
+        condition.unwrap_or_default()
+    }
+}
+
+/// The sort value of the last email on a page, typed so it binds like the column it came from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortValue {
+    Timestamp(NaiveDateTime),
+    Text(String),
+    Integer(i64),
+    /// Bits of an `f64`, JSON numbers would not restore every float exactly
+    Float(u64),
+}
+
+impl From<SortValue> for SqlValue {
+    fn from(value: SortValue) -> Self {
+        match value {
+            SortValue::Timestamp(value) => SqlValue::Timestamp(value),
+            SortValue::Text(value) => SqlValue::Text(value),
+            SortValue::Integer(value) => SqlValue::BigInt(value),
+            SortValue::Float(bits) => SqlValue::Double(f64::from_bits(bits)),
+        }
+    }
+}
+
+/// Position after the last email of a page. Clients only pass it back, the encoding is opaque.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub sort: ListSort,
+    /// `None` when sorting by `created_at` itself
+    pub value: Option<SortValue>,
+    pub created_at: NaiveDateTime,
+    pub id: String,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let json = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    /// True if the cursor was made for `sort` and carries the value type of its key, anything
+    /// else would bind the wrong number or kind of values in [`ListSort::after`]
+    pub fn fits(&self, sort: ListSort) -> bool {
+        self.sort == sort
+            && matches!(
+                (sort.key, &self.value),
+                (SortKey::Received, None)
+                    | (SortKey::Date, Some(SortValue::Timestamp(_)))
+                    | (SortKey::Subject | SortKey::Sender, Some(SortValue::Text(_)))
+                    | (SortKey::Size, Some(SortValue::Integer(_)))
+                    | (SortKey::Relevance, Some(SortValue::Float(_)))
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(sort: ListSort, value: Option<SortValue>) -> Cursor {
+        Cursor {
+            sort,
+            value,
+            created_at: chrono::NaiveDate::from_ymd_opt(2026, 10, 18)
+                .unwrap()
+                .and_hms_micro_opt(12, 0, 0, 123456)
+                .unwrap(),
+            id: "a".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let sort = ListSort {
+            key: SortKey::Relevance,
+            direction: SortDirection::Desc,
+        };
+        let cursor = cursor(
+            sort,
+            Some(SortValue::Float((-1.0574153993152319e-06f64).to_bits())),
+        );
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(Cursor::decode("not a cursor"), None);
+    }
+
+    #[test]
+    fn test_cursor_fits_only_its_sort_and_value_type() {
+        let size = ListSort {
+            key: SortKey::Size,
+            direction: SortDirection::Desc,
+        };
+        assert!(cursor(size, Some(SortValue::Integer(2048))).fits(size));
+        assert!(!cursor(size, None).fits(size));
+        assert!(!cursor(size, Some(SortValue::Text("2048".to_string()))).fits(size));
+        assert!(!cursor(size, Some(SortValue::Integer(2048))).fits(ListSort::default()));
+
+        let received = ListSort::default();
+        assert!(cursor(received, None).fits(received));
+        assert!(!cursor(received, Some(SortValue::Integer(1))).fits(received));
+    }
+
+    #[test]
+    fn test_list_query_rejects_cursor_without_its_value() {
+        let json = r#"{"sort":{"key":"size","direction":"desc"},"value":null,"created_at":"2026-10-18T12:00:00","id":"a"}"#;
+        let params = crate::db::ListParams {
+            sort: Some("size".to_string()),
+            cursor: Some(URL_SAFE_NO_PAD.encode(json)),
+            ..Default::default()
+        };
+        assert_eq!(
+            crate::db::ListQuery::try_from(params).err(),
+            Some(crate::db::ListParamsError::Cursor)
+        );
+    }
+
+    #[test]
+    fn test_default_order_compares_row_values() {
+        let sort = ListSort::default();
+        assert_eq!(sort.order_by(), "emails.created_at DESC, emails.id DESC");
+
+        let after = sort.after(&cursor(sort, None));
+        assert_eq!(after.sql, "(emails.created_at, emails.id) < (?, ?)");
+        assert_eq!(after.binds.len(), 2);
+    }
+
+    #[test]
+    fn test_value_sorts_end_with_created_at_and_id() {
+        let sort = ListSort {
+            key: SortKey::Size,
+            direction: SortDirection::Asc,
+        };
+        assert_eq!(
+            sort.order_by(),
+            "emails.size ASC, emails.created_at ASC, emails.id ASC"
+        );
+
+        let after = sort.after(&cursor(sort, Some(SortValue::Integer(2048))));
+        assert_eq!(
+            after.sql,
+            "(emails.size, emails.created_at, emails.id) > (?, ?, ?)"
+        );
+        assert_eq!(after.binds[0], SqlValue::BigInt(2048));
+    }
+
+    #[test]
+    fn test_relevance_expands_mixed_directions() {
+        let sort = ListSort {
+            key: SortKey::Relevance,
+            direction: SortDirection::Desc,
+        };
+        assert_eq!(
+            sort.order_by(),
+            "email_search.rank ASC, emails.created_at DESC, emails.id DESC"
+        );
+
+        let after = sort.after(&cursor(sort, Some(SortValue::Float((-2.5f64).to_bits()))));
+        assert_eq!(
+            after.sql,
+            "(email_search.rank > ? OR (email_search.rank = ? AND \
+             (emails.created_at < ? OR (emails.created_at = ? AND (emails.id < ?)))))"
+        );
+        assert_eq!(after.binds.len(), 5);
+    }
+}
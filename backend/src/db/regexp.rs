@@ -0,0 +1,119 @@
+use diesel::prelude::*;
+use diesel::sql_types::{Nullable, Text};
+use regex::{Regex, RegexBuilder};
+use std::sync::Mutex;
+
+/// Longest pattern accepted by the search
+pub const MAX_PATTERN_LENGTH: usize = 256;
+/// Upper bound for the compiled program, rejects patterns like `\w{1000}{1000}`
+const MAX_COMPILED_SIZE: usize = 1 << 20;
+/// Maximum nesting of groups and repetitions
+const MAX_NEST_DEPTH: u32 = 16;
+
+define_sql_function! {
+    /// SQLite calls `regexp(pattern, value)` for `value REGEXP pattern`
+    fn regexp(pattern: Text, value: Nullable<Text>) -> Bool;
+}
+
+#[derive(Debug)]
+pub enum RegexError {
+    /// The pattern has more than `MAX_PATTERN_LENGTH` characters
+    TooLong,
+    Regex(regex::Error),
+}
+
+impl std::fmt::Display for RegexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegexError::TooLong => write!(
+                f,
+                "pattern is longer than {} characters",
+                MAX_PATTERN_LENGTH
+            ),
+            RegexError::Regex(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RegexError {}
+
+/// Compiles `pattern` with the limits that keep a search from exhausting memory. Matching
+/// itself runs in linear time.
+pub fn build_regex(pattern: &str) -> Result<Regex, RegexError> {
+    if pattern.chars().count() > MAX_PATTERN_LENGTH {
+        return Err(RegexError::TooLong);
+    }
+    RegexBuilder::new(pattern)
+        .size_limit(MAX_COMPILED_SIZE)
+        .dfa_size_limit(MAX_COMPILED_SIZE)
+        .nest_limit(MAX_NEST_DEPTH)
+        .build()
+        .map_err(RegexError::Regex)
+}
+
+/// Registers the `REGEXP` operator on `conn`. The last pattern is cached, a search compiles it
+/// once instead of once per row.
+pub fn register_regexp(conn: &mut SqliteConnection) -> QueryResult<()> {
+    let cache: Mutex<Option<(String, Regex)>> = Mutex::new(None);
+
+    regexp_utils::register_impl(conn, move |pattern: String, value: Option<String>| {
+        let Some(value) = value else {
+            return false;
+        };
+        let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+        if cache.as_ref().is_none_or(|(cached, _)| *cached != pattern) {
+            // Patterns are validated while parsing the search, an invalid one matches nothing
+            let Ok(regex) = build_regex(&pattern) else {
+                return false;
+            };
+            *cache = Some((pattern, regex));
+        }
+        cache
+            .as_ref()
+            .is_some_and(|(_, regex)| regex.is_match(&value))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::sql_types::Integer;
+
+    #[derive(QueryableByName)]
+    struct MatchResult {
+        #[diesel(sql_type = Integer)]
+        matched: i32,
+    }
+
+    #[test]
+    fn test_regexp_operator() {
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        register_regexp(&mut conn).unwrap();
+
+        let mut matches = |value: Option<&str>, pattern: &str| {
+            diesel::sql_query("SELECT ? REGEXP ? AS matched")
+                .bind::<Nullable<Text>, _>(value)
+                .bind::<Text, _>(pattern)
+                .get_result::<MatchResult>(&mut conn)
+                .unwrap()
+                .matched
+        };
+
+        assert_eq!(matches(Some("Your code is 123456"), r"\b\d{6}\b"), 1);
+        assert_eq!(matches(Some("Your code is 12345"), r"\b\d{6}\b"), 0);
+        assert_eq!(matches(None, r"\d"), 0);
+        assert_eq!(matches(Some("a"), "("), 0);
+    }
+
+    #[test]
+    fn test_complex_patterns_are_rejected() {
+        assert!(build_regex(r"reset\?token=[a-f0-9]{32}").is_ok());
+        assert!(build_regex(&"a".repeat(MAX_PATTERN_LENGTH)).is_ok());
+        assert!(matches!(
+            build_regex(&"a".repeat(MAX_PATTERN_LENGTH + 1)),
+            Err(RegexError::TooLong)
+        ));
+        assert!(build_regex(r"\w{1000}{1000}").is_err());
+        assert!(build_regex(&format!("{}a{}", "(".repeat(20), ")".repeat(20))).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use chrono::Utc;
+use diesel::prelude::*;
+
+use crate::{
+    db::{DbConnection, emails::delete_emails_by_id, used_database_size},
+    schema,
+    web::error::DieselError,
+};
+
+/// Emails looked up and deleted per pruning step
+const PRUNE_CHUNK_SIZE: i64 = 500;
+/// Emails deleted per step while the database is above its size limit
+const SIZE_PRUNE_CHUNK_SIZE: i64 = 100;
+
+/// Limits enforced by the background pruning task. `None` disables a limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetentionPolicy {
+    pub max_age_days: Option<u64>,
+    pub max_emails: Option<u64>,
+    pub max_emails_per_recipient: Option<u64>,
+    pub max_database_size: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_age_days.is_some()
+            || self.max_emails.is_some()
+            || self.max_emails_per_recipient.is_some()
+            || self.max_database_size.is_some()
+    }
+}
+
+/// Deletes every email that falls outside of `policy`, oldest first, and returns their ids.
+///
+/// Deletions run in small transactions so SMTP ingestion is never blocked for long. Emails
+/// removed because one recipient inbox is full are deleted for all of their recipients.
+pub fn prune(
+    conn: &mut DbConnection,
+    policy: &RetentionPolicy,
+) -> Result<Vec<String>, DieselError> {
+    let mut deleted = Vec::new();
+
+    // An age too large for chrono keeps every email
+    if let Some(days) = policy.max_age_days
+        && let Some(cutoff) = chrono::Duration::try_days(saturating_i64(days))
+            .and_then(|age| Utc::now().naive_utc().checked_sub_signed(age))
+    {
+        loop {
+            let ids: Vec<String> = schema::emails::table
+                .filter(schema::emails::created_at.lt(cutoff))
+                .select(schema::emails::id)
+                .limit(PRUNE_CHUNK_SIZE)
+                .load(conn)?;
+            if !delete_chunk(conn, ids, &mut deleted)? {
+                break;
+            }
+        }
+    }
+
+    if let Some(max_emails) = policy.max_emails {
+        loop {
+            let ids: Vec<String> = schema::emails::table
+                .select(schema::emails::id)
+                .order((schema::emails::created_at.desc(), schema::emails::id.desc()))
+                .offset(saturating_i64(max_emails))
+                .limit(PRUNE_CHUNK_SIZE)
+                .load(conn)?;
+            if !delete_chunk(conn, ids, &mut deleted)? {
+                break;
+            }
+        }
+    }
+
+    if let Some(max_per_recipient) = policy.max_emails_per_recipient {
+        // The cached counts tell which inboxes are over the limit without counting every inbox.
+        // No count can exceed i32::MAX, so larger limits select nothing.
+        let max_count = i32::try_from(max_per_recipient).unwrap_or(i32::MAX);
+        let recipient_ids: Vec<String> = schema::envelope_recipients::table
+            .filter(schema::envelope_recipients::email_count.gt(max_count))
+            .select(schema::envelope_recipients::id)
+            .load(conn)?;
+
+        for recipient_id in recipient_ids {
+            loop {
+                let ids: Vec<String> = schema::emails::table
+                    .inner_join(schema::email_envelope_recipients::table)
+                    .filter(
+                        schema::email_envelope_recipients::envelope_recipient_id.eq(&recipient_id),
+                    )
+                    .select(schema::emails::id)
+                    .order((schema::emails::created_at.desc(), schema::emails::id.desc()))
+                    .offset(saturating_i64(max_per_recipient))
+                    .limit(PRUNE_CHUNK_SIZE)
+                    .load(conn)?;
+                if !delete_chunk(conn, ids, &mut deleted)? {
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(max_size) = policy.max_database_size {
+        while used_database_size(conn)? > max_size {
+            let ids: Vec<String> = schema::emails::table
+                .select(schema::emails::id)
+                .order((schema::emails::created_at.asc(), schema::emails::id.asc()))
+                .limit(SIZE_PRUNE_CHUNK_SIZE)
+                .load(conn)?;
+            if !delete_chunk(conn, ids, &mut deleted)? {
+                break;
+            }
+        }
+    }
+
+    Ok(deleted)
+}
+
+fn saturating_i64(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+/// Returns false once there was nothing left to delete
+fn delete_chunk(
+    conn: &mut DbConnection,
+    ids: Vec<String>,
+    deleted: &mut Vec<String>,
+) -> Result<bool, DieselError> {
+    if ids.is_empty() {
+        return Ok(false);
+    }
+
+    delete_emails_by_id(conn, &ids)?;
+    deleted.extend(ids);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{save_email::save_email, testing};
+    use crate::smtp;
+    use chrono::NaiveDateTime;
+
+    /// Saves the emails with `created_at` one minute apart, oldest first
+    fn save_all(conn: &mut DbConnection, emails: &[smtp::Email], start: NaiveDateTime) {
+        for (minutes, email) in emails.iter().enumerate() {
+            save_email(conn, email).unwrap();
+            diesel::update(schema::emails::table.find(email.id.to_string()))
+                .set(
+                    schema::emails::created_at
+                        .eq(start + chrono::Duration::minutes(minutes as i64)),
+                )
+                .execute(conn)
+                .unwrap();
+        }
+    }
+
+    fn ids(emails: &[smtp::Email]) -> Vec<String> {
+        emails.iter().map(|email| email.id.to_string()).collect()
+    }
+
+    /// Cached counts next to the number of links that are actually left
+    fn recipient_counts(conn: &mut DbConnection) -> Vec<(String, i32, i64)> {
+        let recipients: Vec<(String, String, i32)> = schema::envelope_recipients::table
+            .select((
+                schema::envelope_recipients::id,
+                schema::envelope_recipients::email,
+                schema::envelope_recipients::email_count,
+            ))
+            .order(schema::envelope_recipients::email.asc())
+            .load(conn)
+            .unwrap();
+        recipients
+            .into_iter()
+            .map(|(id, email, cached)| {
+                let linked = schema::email_envelope_recipients::table
+                    .filter(schema::email_envelope_recipients::envelope_recipient_id.eq(id))
+                    .count()
+                    .get_result(conn)
+                    .unwrap();
+                (email, cached, linked)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_prune_by_age_and_count() {
+        let pool = testing::pool();
+        let mut conn = pool.get().unwrap();
+        let now = Utc::now().naive_utc();
+        let old = vec![
+            testing::email("Old 1", &["a@x.com"]),
+            testing::email("Old 2", &["a@x.com", "b@x.com"]),
+        ];
+        let recent: Vec<smtp::Email> = (0..4)
+            .map(|i| testing::email(&format!("Recent {}", i), &["a@x.com"]))
+            .collect();
+        save_all(&mut conn, &old, now - chrono::Duration::days(10));
+        save_all(&mut conn, &recent, now - chrono::Duration::hours(1));
+
+        let policy = RetentionPolicy {
+            max_age_days: Some(7),
+            max_emails: Some(3),
+            ..RetentionPolicy::default()
+        };
+        let mut deleted = prune(&mut conn, &policy).unwrap();
+        deleted.sort();
+        let mut expected = ids(&old);
+        expected.push(recent[0].id.to_string());
+        expected.sort();
+        assert_eq!(deleted, expected);
+
+        assert_eq!(
+            recipient_counts(&mut conn),
+            vec![("a@x.com".to_string(), 3, 3), ("b@x.com".to_string(), 0, 0)]
+        );
+        assert!(prune(&mut conn, &policy).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prune_per_recipient() {
+        let pool = testing::pool();
+        let mut conn = pool.get().unwrap();
+        let emails = vec![
+            testing::email("1", &["a@x.com", "b@x.com"]),
+            testing::email("2", &["a@x.com"]),
+            testing::email("3", &["a@x.com", "c@x.com"]),
+            testing::email("4", &["b@x.com"]),
+        ];
+        save_all(&mut conn, &emails, Utc::now().naive_utc());
+
+        let policy = RetentionPolicy {
+            max_emails_per_recipient: Some(2),
+            ..RetentionPolicy::default()
+        };
+        // The first email is gone for b@x.com as well, a@x.com was over the limit
+        assert_eq!(prune(&mut conn, &policy).unwrap(), ids(&emails[..1]));
+        assert_eq!(
+            recipient_counts(&mut conn),
+            vec![
+                ("a@x.com".to_string(), 2, 2),
+                ("b@x.com".to_string(), 1, 1),
+                ("c@x.com".to_string(), 1, 1),
+            ]
+        );
+
+        // Limits beyond what the database can count keep everything
+        let policy = RetentionPolicy {
+            max_age_days: Some(u64::MAX),
+            max_emails: Some(u64::MAX),
+            max_emails_per_recipient: Some(u64::MAX),
+            ..RetentionPolicy::default()
+        };
+        assert!(prune(&mut conn, &policy).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prune_by_database_size() {
+        let pool = testing::pool();
+        let mut conn = pool.get().unwrap();
+        let emails: Vec<smtp::Email> = (0..150)
+            .map(|i| {
+                let mut email = testing::email(&format!("Large {}", i), &["a@x.com"]);
+                email.body_text = format!("{} ", i).repeat(2_000);
+                email
+            })
+            .collect();
+        save_all(&mut conn, &emails, Utc::now().naive_utc());
+
+        // Deleted emails leave search index tombstones behind, so the size shrinks more slowly
+        // than the number of emails
+        let max_size = used_database_size(&mut conn).unwrap() / 4 * 3;
+        let policy = RetentionPolicy {
+            max_database_size: Some(max_size),
+            ..RetentionPolicy::default()
+        };
+        // One step deletes the oldest SIZE_PRUNE_CHUNK_SIZE emails, which is enough here
+        let deleted = prune(&mut conn, &policy).unwrap();
+        assert_eq!(deleted, ids(&emails[..SIZE_PRUNE_CHUNK_SIZE as usize]));
+        assert!(used_database_size(&mut conn).unwrap() <= max_size);
+        assert_eq!(
+            recipient_counts(&mut conn),
+            vec![("a@x.com".to_string(), 50, 50)]
+        );
+    }
+}
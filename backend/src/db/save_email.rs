@@ -1,38 +1,123 @@
 use crate::{
     compression,
-    db::{DbConnection, DbError},
+    db::{DbConnection, DbError, EmailListRecord, search_index, thread},
+    diagnostics::ParseWarning,
+    header::AddressHeaders,
     html,
-    models::{Attachment, Email, Header},
+    lint::LintFinding,
+    models::{
+        Attachment, Email, EmailEnvelopeRecipient, EmailLintFinding, EmailReference, EmailWarning,
+        EnvelopeRecipient, Header,
+    },
     schema, smtp,
 };
 use chrono::Utc;
 use diesel::prelude::*;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use uuid::Uuid;
 
-pub fn save_email(conn: &mut DbConnection, message: &smtp::Email) -> Result<String, DbError> {
+/// Rows per multi-row INSERT, keeps statements well below SQLite's bound parameter limit
+const INSERT_CHUNK_SIZE: usize = 500;
+
+pub fn save_email(
+    conn: &mut DbConnection,
+    message: &smtp::Email,
+) -> Result<EmailListRecord, DbError> {
+    let mut records = save_emails(conn, &[message])?;
+    Ok(records.remove(0))
+}
+
+/// Saves several emails in a single transaction using multi-row inserts.
+///
+/// Returns the list records of the new emails in the same order, ready to be broadcast without
+/// reading them back from the database.
+pub fn save_emails(
+    conn: &mut DbConnection,
+    messages: &[&smtp::Email],
+) -> Result<Vec<EmailListRecord>, DbError> {
     conn.transaction::<_, DbError, _>(|conn| {
-        let attachment_ids = generate_attachment_ids(&message.attachments);
-        let rendered_body_html =
-            process_html_body(&message.body_html, &message.attachments, &attachment_ids);
         let now = Utc::now().naive_utc();
-        let new_email = create_email_record(message, rendered_body_html, now)?;
+        let mut new_emails = Vec::with_capacity(messages.len());
+        let mut new_attachments = Vec::new();
+        let mut new_headers = Vec::new();
+        let mut new_warnings = Vec::new();
+        let mut new_lint_findings = Vec::new();
+        let mut references = Vec::with_capacity(messages.len());
+        let mut records = Vec::with_capacity(messages.len());
 
-        diesel::insert_into(schema::emails::table)
-            .values(&new_email)
-            .execute(conn)?;
+        for message in messages {
+            let attachment_ids = generate_attachment_ids(&message.attachments);
+            let rendered_body_html =
+                process_html_body(&message.body_html, &message.attachments, &attachment_ids);
+            let new_email = create_email_record(message, rendered_body_html, now)?;
+
+            new_attachments.extend(build_attachments(
+                &new_email.id,
+                &message.attachments,
+                &attachment_ids,
+                now,
+            )?);
+            new_headers.extend(build_headers(&new_email.id, &message.raw_headers, now));
+            new_warnings.extend(build_warnings(&new_email.id, &message.warnings));
+            new_lint_findings.extend(build_lint_findings(&new_email.id, &message.lint));
+            references.push(thread::message_references(&message.headers));
+            records.push(create_list_record(message, &new_email));
+            new_emails.push(new_email);
+        }
+
+        for chunk in new_emails.chunks(INSERT_CHUNK_SIZE) {
+            diesel::insert_into(schema::emails::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        for chunk in new_attachments.chunks(INSERT_CHUNK_SIZE) {
+            diesel::insert_into(schema::attachments::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        for chunk in new_headers.chunks(INSERT_CHUNK_SIZE) {
+            diesel::insert_into(schema::headers::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        for chunk in new_warnings.chunks(INSERT_CHUNK_SIZE) {
+            diesel::insert_into(schema::email_warnings::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        for chunk in new_lint_findings.chunks(INSERT_CHUNK_SIZE) {
+            diesel::insert_into(schema::email_lint_findings::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        save_recipients(conn, &records)?;
+
+        let new_references: Vec<EmailReference> = new_emails
+            .iter()
+            .zip(&references)
+            .flat_map(|(email, references)| {
+                references.iter().map(|message_id| EmailReference {
+                    email_id: email.id.clone(),
+                    message_id: message_id.clone(),
+                })
+            })
+            .collect();
+        for chunk in new_references.chunks(INSERT_CHUNK_SIZE) {
+            diesel::insert_into(schema::email_references::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        let mut thread_ids = thread::assign_threads(conn, &new_emails, &references)?;
+        for record in &mut records {
+            if let Some(thread_id) = thread_ids.remove(&record.id) {
+                record.thread_id = thread_id;
+            }
+        }
 
-        save_recipients(conn, &new_email.id, &message.to)?;
-        save_attachments(
-            conn,
-            &new_email.id,
-            &message.attachments,
-            &attachment_ids,
-            now,
-        )?;
-        save_headers(conn, &new_email.id, &message.headers, now)?;
-
-        Ok(new_email.id)
+        let email_ids: Vec<String> = records.iter().map(|record| record.id.clone()).collect();
+        search_index::index_emails(conn, &email_ids)?;
+
+        Ok(records)
     })
 }
 
@@ -48,53 +133,7 @@ fn process_html_body(
     attachments: &[smtp::EmailAttachment],
     attachment_ids: &[String],
 ) -> Option<String> {
-    if body_html.is_empty() {
-        return None;
-    }
-
-    let cid_map = build_cid_map(attachments, attachment_ids);
-    let mut processed_html = if cid_map.is_empty() {
-        body_html.to_string()
-    } else {
-        use regex::Regex;
-        let re = Regex::new(r#"(?i)src\s*=\s*(["']?)cid:([^"'\s>]+)"#).unwrap();
-        re.replace_all(body_html, |caps: &regex::Captures| {
-            let quote = caps.get(1).map(|m| m.as_str()).unwrap_or("");
-            let cid = caps.get(2).map(|m| m.as_str()).unwrap_or("");
-            let cid_clean = cid.trim_start_matches('<').trim_end_matches('>');
-
-            if let Some(attachment_url) = cid_map.get(cid_clean) {
-                format!("src={}{}{}", quote, attachment_url, quote)
-            } else if let Some(attachment_url) = cid_map.get(cid) {
-                format!("src={}{}{}", quote, attachment_url, quote)
-            } else {
-                caps.get(0)
-                    .map(|m| m.as_str().to_string())
-                    .unwrap_or_default()
-            }
-        })
-        .to_string()
-    };
-
-    processed_html = html::normalize_html_document(&processed_html);
-    processed_html = add_base_tag(&processed_html);
-
-    Some(processed_html)
-}
-
-fn add_base_tag(html: &str) -> String {
-    use regex::{Captures, Regex};
-
-    let base_tag = "<base target=\"_blank\">";
-    let base_regex = Regex::new(r"(?i)<base[^>]*>").unwrap();
-
-    if base_regex.is_match(html) {
-        return base_regex
-            .replace(html, |_caps: &Captures| base_tag)
-            .to_string();
-    }
-
-    html::insert_into_head(html, base_tag)
+    html::render_body(body_html, &build_cid_map(attachments, attachment_ids))
 }
 
 fn build_cid_map(
@@ -126,8 +165,12 @@ fn create_email_record(
     now: chrono::NaiveDateTime,
 ) -> Result<Email, DbError> {
     let compressed_data = compression::compress(message.data.as_bytes())?;
+    let id = message.id.to_string();
     Ok(Email {
-        id: message.id.to_string(),
+        // Every email starts a thread of its own until `thread::assign_threads` runs
+        thread_id: id.clone(),
+        thread_subject: thread::thread_subject(message.subject.as_deref()),
+        id,
         message_id: message.message_id.clone(),
         subject: message.subject.clone(),
         date: message.date.map(|d| d.naive_utc()),
@@ -138,115 +181,186 @@ fn create_email_record(
         body_html: Some(message.body_html.clone()),
         rendered_body_html,
         read: false,
+        starred: false,
         has_attachments: !message.attachments.is_empty(),
         created_at: now,
     })
 }
 
-fn save_recipients(
-    conn: &mut DbConnection,
-    email_id: &str,
-    recipient_emails: &[String],
-) -> Result<(), DbError> {
-    for recipient_email in recipient_emails {
-        if recipient_email.trim().is_empty() {
-            continue;
+fn create_list_record(message: &smtp::Email, email: &Email) -> EmailListRecord {
+    let mut recipients: Vec<String> = Vec::new();
+    for recipient in &message.to {
+        if !recipient.trim().is_empty() && !recipients.contains(recipient) {
+            recipients.push(recipient.clone());
         }
-
-        let recipient_id = get_or_create_recipient(conn, recipient_email)?;
-        link_recipient_to_email(conn, email_id, &recipient_id)?;
     }
 
-    Ok(())
+    let headers: HashMap<String, Vec<String>> = message
+        .headers
+        .as_ref()
+        .and_then(|headers| serde_json::from_value(headers.clone()).ok())
+        .unwrap_or_default();
+    let to_header = headers.get("To").cloned();
+
+    EmailListRecord {
+        id: email.id.clone(),
+        subject: email.subject.clone(),
+        date: email.date,
+        created_at: email.created_at,
+        envelope_from: email.envelope_from.clone(),
+        read: email.read,
+        starred: email.starred,
+        has_attachments: email.has_attachments,
+        thread_id: email.thread_id.clone(),
+        thread_size: None,
+        recipients,
+        to_header,
+        addresses: AddressHeaders::from_headers(&headers),
+        tags: Vec::new(),
+        score: None,
+        snippet: None,
+    }
 }
 
-fn get_or_create_recipient(conn: &mut DbConnection, email: &str) -> Result<String, DbError> {
-    match schema::envelope_recipients::table
-        .filter(schema::envelope_recipients::email.eq(email))
-        .select(schema::envelope_recipients::id)
-        .first::<String>(conn)
-    {
-        Ok(id) => Ok(id),
-        Err(_) => {
-            let new_id = Uuid::new_v4().to_string();
-            diesel::insert_into(schema::envelope_recipients::table)
-                .values((
-                    schema::envelope_recipients::id.eq(&new_id),
-                    schema::envelope_recipients::email.eq(email),
-                    schema::envelope_recipients::email_count.eq(0),
+/// Links every email to its envelope recipients and bumps the cached `email_count`s.
+fn save_recipients(conn: &mut DbConnection, records: &[EmailListRecord]) -> Result<(), DbError> {
+    let addresses: BTreeSet<&str> = records
+        .iter()
+        .flat_map(|record| record.recipients.iter().map(String::as_str))
+        .collect();
+    if addresses.is_empty() {
+        return Ok(());
+    }
+
+    let new_recipients: Vec<EnvelopeRecipient> = addresses
+        .iter()
+        .map(|address| EnvelopeRecipient {
+            id: Uuid::new_v4().to_string(),
+            email: address.to_string(),
+            email_count: 0,
+        })
+        .collect();
+    for chunk in new_recipients.chunks(INSERT_CHUNK_SIZE) {
+        // Addresses that already have a row keep their id and count
+        diesel::insert_or_ignore_into(schema::envelope_recipients::table)
+            .values(chunk)
+            .execute(conn)?;
+    }
+
+    let addresses: Vec<&str> = addresses.into_iter().collect();
+    let mut recipient_ids: HashMap<String, String> = HashMap::new();
+    for chunk in addresses.chunks(INSERT_CHUNK_SIZE) {
+        recipient_ids.extend(
+            schema::envelope_recipients::table
+                .filter(schema::envelope_recipients::email.eq_any(chunk))
+                .select((
+                    schema::envelope_recipients::email,
+                    schema::envelope_recipients::id,
                 ))
-                .execute(conn)?;
-            Ok(new_id)
+                .load::<(String, String)>(conn)?,
+        );
+    }
+
+    let mut links = Vec::new();
+    let mut added_counts: HashMap<&str, i32> = HashMap::new();
+    for record in records {
+        for recipient in &record.recipients {
+            let recipient_id = &recipient_ids[recipient];
+            links.push(EmailEnvelopeRecipient {
+                email_id: record.id.clone(),
+                envelope_recipient_id: recipient_id.clone(),
+            });
+            *added_counts.entry(recipient_id).or_default() += 1;
         }
     }
-}
+    for chunk in links.chunks(INSERT_CHUNK_SIZE) {
+        diesel::insert_into(schema::email_envelope_recipients::table)
+            .values(chunk)
+            .execute(conn)?;
+    }
 
-fn link_recipient_to_email(
-    conn: &mut DbConnection,
-    email_id: &str,
-    recipient_id: &str,
-) -> Result<(), DbError> {
-    diesel::insert_into(schema::email_envelope_recipients::table)
-        .values((
-            schema::email_envelope_recipients::email_id.eq(email_id),
-            schema::email_envelope_recipients::envelope_recipient_id.eq(recipient_id),
-        ))
-        .execute(conn)?;
-
-    diesel::update(
-        schema::envelope_recipients::table.filter(schema::envelope_recipients::id.eq(recipient_id)),
-    )
-    .set(schema::envelope_recipients::email_count.eq(
-        diesel::dsl::sql::<diesel::sql_types::Integer>("email_count + 1"),
-    ))
-    .execute(conn)?;
+    // Most recipients get the same number of new emails, so group the count updates by amount
+    let mut ids_by_count: HashMap<i32, Vec<&str>> = HashMap::new();
+    for (recipient_id, count) in added_counts {
+        ids_by_count.entry(count).or_default().push(recipient_id);
+    }
+    for (count, ids) in ids_by_count {
+        for chunk in ids.chunks(INSERT_CHUNK_SIZE) {
+            diesel::update(
+                schema::envelope_recipients::table
+                    .filter(schema::envelope_recipients::id.eq_any(chunk)),
+            )
+            .set(
+                schema::envelope_recipients::email_count
+                    .eq(schema::envelope_recipients::email_count + count),
+            )
+            .execute(conn)?;
+        }
+    }
 
     Ok(())
 }
 
-fn save_headers(
-    conn: &mut DbConnection,
+fn build_headers(
     email_id: &str,
-    headers: &Option<serde_json::Value>,
+    raw_headers: &[smtp::RawHeader],
     now: chrono::NaiveDateTime,
-) -> Result<(), DbError> {
-    if let Some(headers_obj) = headers {
-        if let Some(headers_map) = headers_obj.as_object() {
-            for (header_name, header_values) in headers_map {
-                if let Some(values_array) = header_values.as_array() {
-                    for value in values_array {
-                        if let Some(header_value) = value.as_str() {
-                            let header_id = Uuid::new_v4().to_string();
-                            let new_header = Header {
-                                id: header_id,
-                                email_id: email_id.to_string(),
-                                name: header_name.clone(),
-                                value: header_value.to_string(),
-                                created_at: now,
-                            };
-                            diesel::insert_into(schema::headers::table)
-                                .values(&new_header)
-                                .execute(conn)?;
-                        }
-                    }
-                }
-            }
-        }
-    }
-    Ok(())
+) -> Vec<Header> {
+    raw_headers
+        .iter()
+        .enumerate()
+        .map(|(position, header)| Header {
+            id: Uuid::new_v4().to_string(),
+            email_id: email_id.to_string(),
+            name: header.name.clone(),
+            value: header.value.clone(),
+            created_at: now,
+            position: position as i32,
+            raw: Some(header.raw.clone()),
+            decoded: Some(header.decoded.clone()),
+        })
+        .collect()
 }
 
-fn save_attachments(
-    conn: &mut DbConnection,
+fn build_warnings(email_id: &str, warnings: &[ParseWarning]) -> Vec<EmailWarning> {
+    warnings
+        .iter()
+        .enumerate()
+        .map(|(position, warning)| EmailWarning {
+            email_id: email_id.to_string(),
+            position: position as i32,
+            code: warning.code.clone(),
+            message: warning.message.clone(),
+        })
+        .collect()
+}
+
+fn build_lint_findings(email_id: &str, findings: &[LintFinding]) -> Vec<EmailLintFinding> {
+    findings
+        .iter()
+        .enumerate()
+        .map(|(position, finding)| EmailLintFinding {
+            email_id: email_id.to_string(),
+            position: position as i32,
+            severity: finding.severity.clone(),
+            code: finding.code.clone(),
+            message: finding.message.clone(),
+        })
+        .collect()
+}
+
+fn build_attachments(
     email_id: &str,
     attachments: &[smtp::EmailAttachment],
     attachment_ids: &[String],
     now: chrono::NaiveDateTime,
-) -> Result<(), DbError> {
+) -> Result<Vec<Attachment>, DbError> {
+    let mut new_attachments = Vec::with_capacity(attachments.len());
+
     for (attachment, attachment_id) in attachments.iter().zip(attachment_ids.iter()) {
         let compressed_data = compression::compress(&attachment.data)?;
 
-        let new_attachment = Attachment {
+        new_attachments.push(Attachment {
             id: attachment_id.clone(),
             email_id: email_id.to_string(),
             filename: attachment.filename.clone(),
@@ -256,11 +370,62 @@ fn save_attachments(
             content_id: attachment.content_id.clone(),
             disposition: attachment.disposition.clone(),
             created_at: now,
-        };
-        diesel::insert_into(schema::attachments::table)
-            .values(&new_attachment)
-            .execute(conn)?;
+        });
     }
 
-    Ok(())
+    Ok(new_attachments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+
+    fn recipient_counts(conn: &mut DbConnection) -> Vec<(String, i32)> {
+        schema::envelope_recipients::table
+            .select((
+                schema::envelope_recipients::email,
+                schema::envelope_recipients::email_count,
+            ))
+            .order(schema::envelope_recipients::email.asc())
+            .load(conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_save_emails_counts_repeated_recipients() {
+        let pool = testing::pool();
+        let mut conn = pool.get().unwrap();
+
+        let first = testing::email("First", &["a@x.com", "b@x.com"]);
+        let second = testing::email("Second", &["a@x.com", "c@x.com", "a@x.com", " "]);
+        let records = save_emails(&mut conn, &[&first, &second]).unwrap();
+        assert_eq!(records[0].id, first.id.to_string());
+        assert_eq!(records[1].recipients, vec!["a@x.com", "c@x.com"]);
+
+        // Recipients that already exist keep their row and only count up
+        let third = testing::email("Third", &["a@x.com"]);
+        save_email(&mut conn, &third).unwrap();
+
+        let emails: i64 = schema::emails::table.count().get_result(&mut conn).unwrap();
+        assert_eq!(emails, 3);
+        let links: Vec<(String, String)> = schema::email_envelope_recipients::table
+            .inner_join(schema::envelope_recipients::table)
+            .select((
+                schema::email_envelope_recipients::email_id,
+                schema::envelope_recipients::email,
+            ))
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(links.len(), 5);
+        assert!(links.contains(&(second.id.to_string(), "c@x.com".to_string())));
+        assert_eq!(
+            recipient_counts(&mut conn),
+            vec![
+                ("a@x.com".to_string(), 3),
+                ("b@x.com".to_string(), 1),
+                ("c@x.com".to_string(), 1),
+            ]
+        );
+    }
 }
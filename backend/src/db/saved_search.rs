@@ -0,0 +1,117 @@
+use chrono::Utc;
+use diesel::prelude::*;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::db::counts::SavedSearchStats;
+use crate::db::emails::count_matching;
+use crate::db::search_query::parse_search_query;
+use crate::{db::DbConnection, models::SavedSearch, schema, web::error::DieselError};
+
+pub fn list_saved_searches(conn: &mut DbConnection) -> Result<Vec<SavedSearch>, DieselError> {
+    schema::saved_searches::table
+        .order(schema::saved_searches::name.asc())
+        .select(SavedSearch::as_select())
+        .load(conn)
+}
+
+pub fn get_saved_search(conn: &mut DbConnection, id: &str) -> Result<SavedSearch, DieselError> {
+    schema::saved_searches::table
+        .find(id)
+        .select(SavedSearch::as_select())
+        .first(conn)
+}
+
+/// Stores a named search, `query` must already be validated by `parse_search_query`
+pub fn create_saved_search(
+    conn: &mut DbConnection,
+    name: &str,
+    query: &str,
+) -> Result<SavedSearch, DieselError> {
+    let now = Utc::now().naive_utc();
+    let saved_search = SavedSearch {
+        id: Uuid::new_v4().to_string(),
+        name: name.to_string(),
+        query: query.to_string(),
+        created_at: now,
+        updated_at: now,
+    };
+    diesel::insert_into(schema::saved_searches::table)
+        .values(&saved_search)
+        .execute(conn)?;
+    Ok(saved_search)
+}
+
+pub fn update_saved_search(
+    conn: &mut DbConnection,
+    id: &str,
+    name: &str,
+    query: &str,
+) -> Result<SavedSearch, DieselError> {
+    let updated = diesel::update(schema::saved_searches::table.find(id))
+        .set((
+            schema::saved_searches::name.eq(name),
+            schema::saved_searches::query.eq(query),
+            schema::saved_searches::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)?;
+    if updated == 0 {
+        return Err(DieselError::NotFound);
+    }
+    get_saved_search(conn, id)
+}
+
+pub fn delete_saved_search(conn: &mut DbConnection, id: &str) -> Result<usize, DieselError> {
+    diesel::delete(schema::saved_searches::table.find(id)).execute(conn)
+}
+
+/// Counts of every saved search. Relative dates like `after:1d` count back from now.
+pub fn saved_search_stats(conn: &mut DbConnection) -> Result<Vec<SavedSearchStats>, DieselError> {
+    list_saved_searches(conn)?
+        .iter()
+        .map(|saved_search| stats(conn, saved_search))
+        .collect()
+}
+
+/// Counts of the saved searches that match at least one of `email_ids`
+pub fn saved_search_stats_matching(
+    conn: &mut DbConnection,
+    email_ids: &[String],
+) -> Result<Vec<SavedSearchStats>, DieselError> {
+    let mut matching = Vec::new();
+    for saved_search in list_saved_searches(conn)? {
+        let Ok(search) = parse_search_query(&saved_search.query) else {
+            continue;
+        };
+        let (count, _) = count_matching(conn, search.expr.as_ref(), Some(email_ids))?;
+        if count > 0 {
+            matching.push(stats(conn, &saved_search)?);
+        }
+    }
+    Ok(matching)
+}
+
+fn stats(
+    conn: &mut DbConnection,
+    saved_search: &SavedSearch,
+) -> Result<SavedSearchStats, DieselError> {
+    let (count, unread) = match parse_search_query(&saved_search.query) {
+        Ok(search) => count_matching(conn, search.expr.as_ref(), None)?,
+        Err(e) => {
+            // Only possible if the query syntax changed since the search was saved
+            warn!(
+                component = "db",
+                saved_search = %saved_search.id,
+                "Saved search no longer parses: {}", e
+            );
+            (0, 0)
+        }
+    };
+
+    Ok(SavedSearchStats {
+        id: saved_search.id.clone(),
+        name: saved_search.name.clone(),
+        count,
+        unread,
+    })
+}
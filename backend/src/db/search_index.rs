@@ -0,0 +1,169 @@
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use std::collections::HashMap;
+
+use crate::db::DbConnection;
+use crate::db::search_sql::SqlFragment;
+use crate::web::error::DieselError;
+
+/// Emails indexed per statement, keeps the `IN` list below SQLite's bound parameter limit
+const INDEX_CHUNK_SIZE: usize = 500;
+/// Tokens of context around the matches in a snippet
+const SNIPPET_TOKENS: usize = 16;
+
+/// Builds the index rows of the emails listed in `email_search_documents`; keep in sync with
+/// the migration that created `email_search`.
+const INDEX_SELECT: &str = "
+    SELECT
+        email_search_documents.id,
+        COALESCE(emails.subject, ''),
+        emails.envelope_from || ' ' || COALESCE((
+            SELECT group_concat(headers.value, ' ') FROM headers
+            WHERE headers.email_id = emails.id AND LOWER(headers.name) = 'from'
+        ), ''),
+        COALESCE((
+            SELECT group_concat(envelope_recipients.email, ' ') FROM email_envelope_recipients
+            INNER JOIN envelope_recipients
+                ON envelope_recipients.id = email_envelope_recipients.envelope_recipient_id
+            WHERE email_envelope_recipients.email_id = emails.id
+        ), '') || ' ' || COALESCE((
+            SELECT group_concat(headers.value, ' ') FROM headers
+            WHERE headers.email_id = emails.id AND LOWER(headers.name) IN ('to', 'cc', 'bcc')
+        ), ''),
+        COALESCE(emails.body_text, ''),
+        COALESCE(emails.body_html, ''),
+        COALESCE((
+            SELECT group_concat(attachments.filename, ' ') FROM attachments
+            WHERE attachments.email_id = emails.id
+        ), '')
+    FROM email_search_documents
+    INNER JOIN emails ON emails.id = email_search_documents.email_id";
+
+/// Adds already stored emails, including their headers, attachments and recipients, to the
+/// full-text index. Rows are removed again by a trigger when the email is deleted.
+pub fn index_emails(conn: &mut DbConnection, email_ids: &[String]) -> Result<(), DieselError> {
+    for chunk in email_ids.chunks(INDEX_CHUNK_SIZE) {
+        let mut insert_documents =
+            SqlFragment::new("INSERT INTO email_search_documents (email_id) VALUES ");
+        for (i, email_id) in chunk.iter().enumerate() {
+            if i > 0 {
+                insert_documents.push_sql(", ");
+            }
+            insert_documents
+                .push_sql("(")
+                .push_bind(email_id.as_str())
+                .push_sql(")");
+        }
+        insert_documents.into_query().execute(conn)?;
+        insert_index_rows(conn, chunk)?;
+    }
+
+    Ok(())
+}
+
+/// Rebuilds the index rows of already indexed emails, e.g. after their recipients changed
+pub fn reindex_emails(conn: &mut DbConnection, email_ids: &[String]) -> Result<(), DieselError> {
+    for chunk in email_ids.chunks(INDEX_CHUNK_SIZE) {
+        let mut delete_rows = SqlFragment::new(
+            "DELETE FROM email_search WHERE rowid IN \
+             (SELECT id FROM email_search_documents WHERE email_id IN ",
+        );
+        delete_rows
+            .push_fragment(SqlFragment::bind_list(chunk))
+            .push_sql(")");
+        delete_rows.into_query().execute(conn)?;
+        insert_index_rows(conn, chunk)?;
+    }
+
+    Ok(())
+}
+
+fn insert_index_rows(conn: &mut DbConnection, email_ids: &[String]) -> Result<(), DieselError> {
+    let mut insert_rows = SqlFragment::new(
+        "INSERT INTO email_search \
+         (rowid, subject, sender, recipients, body_text, body_html, attachments)",
+    );
+    insert_rows
+        .push_sql(INDEX_SELECT)
+        .push_sql(" WHERE email_search_documents.email_id IN ")
+        .push_fragment(SqlFragment::bind_list(email_ids));
+    insert_rows.into_query().execute(conn)?;
+    Ok(())
+}
+
+/// Empties the index, faster than letting the delete trigger remove one row per email
+pub fn clear_index(conn: &mut DbConnection) -> Result<(), DieselError> {
+    conn.batch_execute("DELETE FROM email_search; DELETE FROM email_search_documents;")
+}
+
+#[derive(QueryableByName)]
+struct SnippetRow {
+    #[diesel(sql_type = Text)]
+    email_id: String,
+    #[diesel(sql_type = Text)]
+    snippet: String,
+}
+
+/// Loads a highlighted snippet for each of the given emails that matches `match_expression`
+pub fn load_snippets(
+    conn: &mut DbConnection,
+    match_expression: &str,
+    email_ids: &[String],
+) -> Result<HashMap<String, String>, DieselError> {
+    if email_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    // Private use characters mark the matches, so the text can be escaped before adding <mark>
+    let mut query = SqlFragment::new(&format!(
+        "SELECT email_search_documents.email_id AS email_id, \
+         snippet(email_search, -1, char(57344), char(57345), '…', {}) AS snippet \
+         FROM email_search \
+         INNER JOIN email_search_documents ON email_search_documents.id = email_search.rowid \
+         WHERE email_search MATCH ",
+        SNIPPET_TOKENS
+    ));
+    query
+        .push_bind(match_expression)
+        .push_sql(" AND email_search_documents.email_id IN ")
+        .push_fragment(SqlFragment::bind_list(email_ids));
+
+    Ok(query
+        .into_query()
+        .load::<SnippetRow>(conn)?
+        .into_iter()
+        .map(|row| (row.email_id, highlight_snippet(&row.snippet)))
+        .collect())
+}
+
+/// Escapes the snippet for HTML and wraps the marked matches in `<mark>`
+fn highlight_snippet(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            '\u{E000}' => html.push_str("<mark>"),
+            '\u{E001}' => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            _ => html.push(c),
+        }
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight_snippet_escapes_html() {
+        assert_eq!(
+            highlight_snippet("<b>\u{E000}Invoice\u{E001}</b> & more"),
+            "&lt;b&gt;<mark>Invoice</mark>&lt;/b&gt; &amp; more"
+        );
+    }
+}
@@ -1,4 +1,23 @@
-use regex::Regex;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
+use std::fmt;
+
+use crate::db::regexp;
+
+/// Operators that filter on email properties instead of searching text
+const FILTER_OPERATORS: &[&str] = &[
+    "after",
+    "before",
+    "size",
+    "is",
+    "has",
+    "sort",
+    "header",
+    "message-id",
+    "in-reply-to",
+    "subject~",
+    "text~",
+    "html~",
+];
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum SearchField {
@@ -9,6 +28,8 @@ pub enum SearchField {
     Text,
     Html,
     Attachment,
+    /// Matches the whole tag name, ignoring case
+    Tag,
 }
 
 impl SearchField {
@@ -21,211 +42,1248 @@ impl SearchField {
             "text" => Some(SearchField::Text),
             "html" => Some(SearchField::Html),
             "attachment" => Some(SearchField::Attachment),
+            "tag" => Some(SearchField::Tag),
             _ => None,
         }
     }
 }
 
+/// A search query as a tree, compiled to SQL by `db::search_sql`.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct SearchTerm {
-    pub field: SearchField,
-    pub value: String,
+pub enum SearchExpr {
+    /// Matches emails containing `value` in `field`, or in any default field when `None`
+    Term {
+        field: Option<SearchField>,
+        value: String,
+    },
+    /// Matches emails that match all children
+    And(Vec<SearchExpr>),
+    /// Matches emails that match at least one child
+    Or(Vec<SearchExpr>),
+    /// Matches emails that do not match the child
+    Not(Box<SearchExpr>),
+    /// Matches emails by a property other than their text
+    Filter(SearchFilter),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ParsedSearchQuery {
-    pub field_terms: Vec<SearchTerm>,
-    pub default_terms: Vec<String>,
+pub enum SearchFilter {
+    /// Received at or after this UTC time
+    After(NaiveDateTime),
+    /// Received before this UTC time
+    Before(NaiveDateTime),
+    /// Message size in bytes
+    Size(Comparison, u64),
+    Read(bool),
+    Starred(bool),
+    HasAttachment,
+    HasHtml,
+    HasText,
+    /// At least one parse warning, see `diagnostics`
+    HasWarnings,
+    /// A header named `name` (any case) whose value contains `value`, or any value if `None`
+    Header {
+        name: String,
+        value: Option<String>,
+    },
+    /// Exactly this Message-ID, without angle brackets
+    MessageId(String),
+    /// A reply to this Message-ID, without angle brackets
+    InReplyTo(String),
+    /// A regular expression matched against one field, or the subject and text body if `None`
+    Regex {
+        field: Option<SearchField>,
+        pattern: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Newest,
+    Oldest,
+    Largest,
+    Smallest,
+    /// Best full-text matches first, falls back to `Newest` without text terms
+    Relevance,
+}
+
+/// A parsed search: the filter expression and the requested `sort:` order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub expr: Option<SearchExpr>,
+    pub sort: Option<SortOrder>,
 }
 
-pub fn parse_search_query(query: &str) -> ParsedSearchQuery {
-    if query.trim().is_empty() {
-        return ParsedSearchQuery {
-            field_terms: Vec::new(),
-            default_terms: Vec::new(),
+impl SearchQuery {
+    /// Emails matching both queries, the `sort:` of `other` wins
+    pub fn and(self, other: SearchQuery) -> SearchQuery {
+        let expr = match (self.expr, other.expr) {
+            (Some(a), Some(b)) => {
+                let children = [a, b]
+                    .into_iter()
+                    .flat_map(|expr| match expr {
+                        SearchExpr::And(children) => children,
+                        expr => vec![expr],
+                    })
+                    .collect();
+                Some(SearchExpr::And(children))
+            }
+            (a, b) => a.or(b),
         };
+        SearchQuery {
+            expr,
+            sort: other.sort.or(self.sort),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchParseError {
+    pub message: String,
+    /// Character offset in the query, starting at 0
+    pub position: usize,
+}
+
+impl SearchParseError {
+    fn new(message: impl Into<String>, position: usize) -> Self {
+        Self {
+            message: message.into(),
+            position,
+        }
+    }
+}
+
+impl fmt::Display for SearchParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position + 1)
     }
+}
+
+impl std::error::Error for SearchParseError {}
 
-    let field_pattern = Regex::new(r"(\w+):(\S+)").unwrap();
-    let mut field_terms = Vec::new();
-    let mut matched_ranges = Vec::new();
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Term {
+        field: Option<SearchField>,
+        value: String,
+    },
+    /// `name:value` of one of the `FILTER_OPERATORS`, `name` in lowercase
+    Operator {
+        name: String,
+        value: String,
+    },
+    /// `/pattern/`
+    Regex(String),
+    And,
+    Or,
+    Not,
+    OpenParen,
+    CloseParen,
+}
 
-    for cap in field_pattern.captures_iter(query) {
-        let (field_name, value, full_match) = match (cap.get(1), cap.get(2), cap.get(0)) {
-            (Some(m1), Some(m2), Some(m0)) => (m1.as_str(), m2.as_str(), m0.range()),
-            _ => continue,
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    kind: TokenKind,
+    position: usize,
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Lexer {
+    fn tokenize(query: &str) -> Result<Vec<Token>, SearchParseError> {
+        let mut lexer = Lexer {
+            chars: query.chars().collect(),
+            position: 0,
         };
+        let mut tokens = Vec::new();
 
-        if let Some(field) = SearchField::from_str(field_name) {
-            let normalized_field = match field {
-                SearchField::To => SearchField::Recipient,
-                _ => field,
+        while let Some(c) = lexer.peek() {
+            let position = lexer.position;
+            let kind = match c {
+                c if c.is_whitespace() => {
+                    lexer.position += 1;
+                    continue;
+                }
+                '(' => {
+                    lexer.position += 1;
+                    TokenKind::OpenParen
+                }
+                ')' => {
+                    lexer.position += 1;
+                    TokenKind::CloseParen
+                }
+                '-' if lexer.starts_term(position + 1) => {
+                    lexer.position += 1;
+                    TokenKind::Not
+                }
+                '"' => TokenKind::Term {
+                    field: None,
+                    value: lexer.quoted()?,
+                },
+                '/' => match lexer.regex() {
+                    Some(pattern) => TokenKind::Regex(pattern),
+                    None => lexer.word()?,
+                },
+                _ => lexer.word()?,
             };
-            field_terms.push(SearchTerm {
-                field: normalized_field,
-                value: value.to_string(),
-            });
-            matched_ranges.push(full_match);
+            tokens.push(Token { kind, position });
         }
+
+        Ok(tokens)
     }
 
-    let mut default_terms = Vec::new();
-    let mut last_end = 0;
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    /// Whether a term starts at `position`, used to tell `-term` apart from a lone dash
+    fn starts_term(&self, position: usize) -> bool {
+        self.chars
+            .get(position)
+            .is_some_and(|c| !c.is_whitespace() && *c != ')')
+    }
 
-    for range in matched_ranges {
-        if range.start > last_end {
-            let text_between = &query[last_end..range.start];
-            default_terms.extend(
-                text_between
-                    .split_whitespace()
-                    .filter(|s| !s.is_empty())
-                    .map(|s| s.to_string()),
-            );
+    fn is_word_end(c: char) -> bool {
+        c.is_whitespace() || c == '(' || c == ')'
+    }
+
+    /// Reads a `"quoted phrase"`, `\"` and `\\` escape a quote and a backslash
+    fn quoted(&mut self) -> Result<String, SearchParseError> {
+        let start = self.position;
+        self.position += 1;
+        let mut value = String::new();
+
+        loop {
+            match self.peek() {
+                None => return Err(SearchParseError::new("Unclosed quote", start)),
+                Some('"') => {
+                    self.position += 1;
+                    break;
+                }
+                Some('\\') if matches!(self.chars.get(self.position + 1), Some('"' | '\\')) => {
+                    value.push(self.chars[self.position + 1]);
+                    self.position += 2;
+                }
+                Some(c) => {
+                    value.push(c);
+                    self.position += 1;
+                }
+            }
+        }
+
+        if value.trim().is_empty() {
+            return Err(SearchParseError::new("Empty phrase", start));
         }
-        last_end = range.end;
+        Ok(value)
     }
 
-    if last_end < query.len() {
-        let remaining = &query[last_end..];
-        default_terms.extend(
-            remaining
-                .split_whitespace()
-                .filter(|s| !s.is_empty())
-                .map(|s| s.to_string()),
-        );
+    /// Reads a `/pattern/` whose closing slash ends the word, `\/` is a slash inside the
+    /// pattern. `None` leaves words like `/path/to` to `word`.
+    fn regex(&mut self) -> Option<String> {
+        let mut end = self.position + 1;
+        let mut pattern = String::new();
+        loop {
+            match self.chars.get(end) {
+                None => return None,
+                Some('\\') if self.chars.get(end + 1) == Some(&'/') => {
+                    pattern.push('/');
+                    end += 2;
+                }
+                Some('/')
+                    if self
+                        .chars
+                        .get(end + 1)
+                        .is_none_or(|c| Self::is_word_end(*c)) =>
+                {
+                    break;
+                }
+                Some(c) => {
+                    pattern.push(*c);
+                    end += 1;
+                }
+            }
+        }
+
+        if pattern.is_empty() {
+            return None;
+        }
+        self.position = end + 1;
+        Some(pattern)
+    }
+
+    /// Reads an operator, a plain word or a `field:value` term
+    fn word(&mut self) -> Result<TokenKind, SearchParseError> {
+        let start = self.position;
+        while let Some(c) = self.peek() {
+            if Self::is_word_end(c) {
+                break;
+            }
+            if c == ':' {
+                let name: String = self.chars[start..self.position].iter().collect();
+                if let Some(field) = SearchField::from_str(&name) {
+                    let field = match field {
+                        SearchField::To => SearchField::Recipient,
+                        field => field,
+                    };
+                    self.position += 1;
+                    return Ok(TokenKind::Term {
+                        field: Some(field),
+                        value: self.field_value(&name, start)?,
+                    });
+                }
+                let name = name.to_lowercase();
+                if FILTER_OPERATORS.contains(&name.as_str()) {
+                    self.position += 1;
+                    let value = if name == "header" && self.peek() != Some('"') {
+                        self.header_value(start)?
+                    } else {
+                        self.field_value(&name, start)?
+                    };
+                    return Ok(TokenKind::Operator { name, value });
+                }
+            }
+            self.position += 1;
+        }
+
+        let word: String = self.chars[start..self.position].iter().collect();
+        Ok(match word.as_str() {
+            "AND" => TokenKind::And,
+            "OR" => TokenKind::Or,
+            "NOT" => TokenKind::Not,
+            // Unknown prefixes like `https:` stay part of the term
+            _ => TokenKind::Term {
+                field: None,
+                value: word,
+            },
+        })
+    }
+
+    /// Reads `Name:value` after `header:`, the value may be quoted
+    fn header_value(&mut self, start: usize) -> Result<String, SearchParseError> {
+        let name_start = self.position;
+        while self
+            .peek()
+            .is_some_and(|c| c != ':' && !Self::is_word_end(c))
+        {
+            self.position += 1;
+        }
+        let mut value: String = self.chars[name_start..self.position].iter().collect();
+        if value.is_empty() {
+            return Err(SearchParseError::new("Missing value for header:", start));
+        }
+
+        if self.peek() == Some(':') {
+            self.position += 1;
+            if self.peek().is_some_and(|c| !Self::is_word_end(c)) {
+                value.push(':');
+                value.push_str(&self.field_value("header", start)?);
+            }
+        }
+        Ok(value)
+    }
+
+    /// Reads the value after `name:`, plain or quoted
+    fn field_value(&mut self, name: &str, start: usize) -> Result<String, SearchParseError> {
+        match self.peek() {
+            Some('"') => self.quoted(),
+            Some(c) if !Self::is_word_end(c) => {
+                let value_start = self.position;
+                while self.peek().is_some_and(|c| !Self::is_word_end(c)) {
+                    self.position += 1;
+                }
+                Ok(self.chars[value_start..self.position].iter().collect())
+            }
+            _ => Err(SearchParseError::new(
+                format!("Missing value for {}:", name),
+                start,
+            )),
+        }
+    }
+}
+
+/// Recursive descent parser over the tokens. From lowest to highest precedence:
+///
+/// ```text
+/// query   = and
+/// and     = or { ["AND"] or }
+/// or      = unary { "OR" unary }
+/// unary   = ("NOT" | "-") unary | primary
+/// primary = "(" and ")" | term
+/// ```
+///
+/// `OR` binds tighter than `AND`, so `from:bob invoice OR receipt` finds invoices and receipts
+/// from bob.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    query_length: usize,
+    /// Relative dates like `after:10m` count back from here
+    now: NaiveDateTime,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.position).map(|token| &token.kind)
+    }
+
+    /// Position of the next token, or the end of the query
+    fn peek_position(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.query_length, |token| token.position)
     }
 
-    ParsedSearchQuery {
-        field_terms,
-        default_terms,
+    fn and(&mut self) -> Result<SearchExpr, SearchParseError> {
+        let mut children = vec![self.or()?];
+        loop {
+            match self.peek() {
+                None | Some(TokenKind::CloseParen) => break,
+                Some(TokenKind::And) => {
+                    self.position += 1;
+                    children.push(self.operand("AND")?);
+                }
+                Some(_) => children.push(self.or()?),
+            }
+        }
+        Ok(flatten(children, SearchExpr::And))
+    }
+
+    fn or(&mut self) -> Result<SearchExpr, SearchParseError> {
+        let mut children = vec![self.unary()?];
+        while self.peek() == Some(&TokenKind::Or) {
+            self.position += 1;
+            children.push(self.operand("OR")?);
+        }
+        Ok(flatten(children, SearchExpr::Or))
+    }
+
+    /// The right-hand side of a binary operator
+    fn operand(&mut self, operator: &str) -> Result<SearchExpr, SearchParseError> {
+        match self.peek() {
+            None | Some(TokenKind::CloseParen | TokenKind::And | TokenKind::Or) => {
+                Err(SearchParseError::new(
+                    format!("Expected a term after {}", operator),
+                    self.peek_position(),
+                ))
+            }
+            Some(_) => self.unary(),
+        }
+    }
+
+    fn unary(&mut self) -> Result<SearchExpr, SearchParseError> {
+        if self.peek() == Some(&TokenKind::Not) {
+            self.position += 1;
+            let operand = self.operand("NOT")?;
+            return Ok(SearchExpr::Not(Box::new(operand)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<SearchExpr, SearchParseError> {
+        let position = self.peek_position();
+        let Some(kind) = self.peek().cloned() else {
+            return Err(SearchParseError::new("Unexpected end of query", position));
+        };
+        self.position += 1;
+
+        match kind {
+            TokenKind::Term { field, value } => Ok(SearchExpr::Term { field, value }),
+            TokenKind::Operator { name, value } => {
+                parse_filter(&name, &value, self.now, position).map(SearchExpr::Filter)
+            }
+            TokenKind::Regex(pattern) => {
+                regex_filter(None, pattern, position).map(SearchExpr::Filter)
+            }
+            TokenKind::OpenParen => {
+                if self.peek() == Some(&TokenKind::CloseParen) {
+                    return Err(SearchParseError::new("Empty parentheses", position));
+                }
+                let expr = self.and()?;
+                if self.peek() != Some(&TokenKind::CloseParen) {
+                    return Err(SearchParseError::new("Unclosed parenthesis", position));
+                }
+                self.position += 1;
+                Ok(expr)
+            }
+            TokenKind::CloseParen => Err(SearchParseError::new("Unexpected )", position)),
+            TokenKind::And => Err(SearchParseError::new(
+                "Expected a term before AND",
+                position,
+            )),
+            TokenKind::Or => Err(SearchParseError::new("Expected a term before OR", position)),
+            TokenKind::Not => unreachable!("NOT is handled by unary"),
+        }
     }
 }
 
+fn flatten(
+    mut children: Vec<SearchExpr>,
+    combine: fn(Vec<SearchExpr>) -> SearchExpr,
+) -> SearchExpr {
+    if children.len() == 1 {
+        children.remove(0)
+    } else {
+        combine(children)
+    }
+}
+
+fn parse_filter(
+    name: &str,
+    value: &str,
+    now: NaiveDateTime,
+    position: usize,
+) -> Result<SearchFilter, SearchParseError> {
+    let invalid = |message: &str| SearchParseError::new(message, position);
+
+    match name {
+        "after" => parse_date(value, now)
+            .map(SearchFilter::After)
+            .ok_or_else(|| invalid("Invalid date for after:, use e.g. 2026-10-01 or 10m")),
+        "before" => parse_date(value, now)
+            .map(SearchFilter::Before)
+            .ok_or_else(|| invalid("Invalid date for before:, use e.g. 2026-10-01 or 2d")),
+        "size" => parse_size(value)
+            .map(|(comparison, bytes)| SearchFilter::Size(comparison, bytes))
+            .ok_or_else(|| invalid("Invalid size for size:, use e.g. >1mb or <500kb")),
+        "is" => match value.to_lowercase().as_str() {
+            "read" => Ok(SearchFilter::Read(true)),
+            "unread" => Ok(SearchFilter::Read(false)),
+            "starred" => Ok(SearchFilter::Starred(true)),
+            "unstarred" => Ok(SearchFilter::Starred(false)),
+            _ => Err(invalid(
+                "Unknown value for is:, use read, unread, starred or unstarred",
+            )),
+        },
+        "has" => match value.to_lowercase().as_str() {
+            "attachment" | "attachments" => Ok(SearchFilter::HasAttachment),
+            "html" => Ok(SearchFilter::HasHtml),
+            "text" => Ok(SearchFilter::HasText),
+            "warning" | "warnings" => Ok(SearchFilter::HasWarnings),
+            _ => Err(invalid(
+                "Unknown value for has:, use attachment, html, text or warnings",
+            )),
+        },
+        "header" => {
+            let (name, value) = match value.split_once(':') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (value, None),
+            };
+            if name.is_empty() || name.chars().any(|c| c.is_whitespace()) {
+                return Err(invalid(
+                    "Invalid header name for header:, use e.g. header:X-Tag:value",
+                ));
+            }
+            Ok(SearchFilter::Header {
+                name: name.to_string(),
+                value,
+            })
+        }
+        "message-id" => Ok(SearchFilter::MessageId(strip_angle_brackets(value))),
+        "subject~" => regex_filter(Some(SearchField::Subject), value.to_string(), position),
+        "text~" => regex_filter(Some(SearchField::Text), value.to_string(), position),
+        "html~" => regex_filter(Some(SearchField::Html), value.to_string(), position),
+        "in-reply-to" => Ok(SearchFilter::InReplyTo(strip_angle_brackets(value))),
+        "sort" => Err(invalid("sort: cannot be combined with other operators")),
+        // Only reached if an operator was added to FILTER_OPERATORS without an arm here
+        _ => Err(invalid(&format!("Unknown operator {}:", name))),
+    }
+}
+
+fn regex_filter(
+    field: Option<SearchField>,
+    pattern: String,
+    position: usize,
+) -> Result<SearchFilter, SearchParseError> {
+    match regexp::build_regex(&pattern) {
+        Ok(_) => Ok(SearchFilter::Regex { field, pattern }),
+        Err(regexp::RegexError::TooLong) => Err(SearchParseError::new(
+            format!(
+                "Pattern is longer than {} characters",
+                regexp::MAX_PATTERN_LENGTH
+            ),
+            position,
+        )),
+        Err(regexp::RegexError::Regex(regex::Error::CompiledTooBig(_))) => Err(
+            SearchParseError::new("Pattern is too complex, use fewer repetitions", position),
+        ),
+        Err(regexp::RegexError::Regex(regex::Error::Syntax(message)))
+            if message.contains("maximum number of nested") =>
+        {
+            Err(SearchParseError::new(
+                "Pattern is too deeply nested",
+                position,
+            ))
+        }
+        Err(_) => Err(SearchParseError::new(
+            "Invalid regular expression",
+            position,
+        )),
+    }
+}
+
+/// `<id@example.com>` and `id@example.com` are the same Message-ID
+pub fn strip_angle_brackets(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('<')
+        .and_then(|value| value.strip_suffix('>'))
+        .unwrap_or(value)
+        .to_string()
+}
+
+/// Absolute UTC dates (`2026-10-01`, `2026-10-01T12:30`, RFC 3339) or durations before `now`
+/// (`30s`, `10m`, `2h`, `7d`, `4w`)
+fn parse_date(value: &str, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return date.and_hms_opt(0, 0, 0);
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(datetime);
+        }
+    }
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Some(datetime.naive_utc());
+    }
+
+    let unit_start = value.find(|c: char| !c.is_ascii_digit())?;
+    let amount: i64 = value[..unit_start].parse().ok()?;
+    let duration = match &value[unit_start..] {
+        "s" => Duration::try_seconds(amount),
+        "m" | "min" => Duration::try_minutes(amount),
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        _ => None,
+    }?;
+    now.checked_sub_signed(duration)
+}
+
+/// `>1mb`, `<=500kb`, `2048` (at least); units are powers of 1024
+fn parse_size(value: &str) -> Option<(Comparison, u64)> {
+    let (comparison, rest) = [
+        (">=", Comparison::GreaterOrEqual),
+        ("<=", Comparison::LessOrEqual),
+        (">", Comparison::Greater),
+        ("<", Comparison::Less),
+    ]
+    .into_iter()
+    .find_map(|(prefix, comparison)| value.strip_prefix(prefix).map(|rest| (comparison, rest)))
+    .unwrap_or((Comparison::GreaterOrEqual, value));
+
+    let rest = rest.to_lowercase();
+    let unit_start = rest
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rest.len());
+    let amount: f64 = rest[..unit_start].parse().ok()?;
+    let multiplier: u64 = match &rest[unit_start..] {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    Some((comparison, (amount * multiplier as f64) as u64))
+}
+
+fn parse_sort(value: &str) -> Option<SortOrder> {
+    match value.to_lowercase().as_str() {
+        "newest" => Some(SortOrder::Newest),
+        "oldest" => Some(SortOrder::Oldest),
+        "largest" => Some(SortOrder::Largest),
+        "smallest" => Some(SortOrder::Smallest),
+        "relevance" => Some(SortOrder::Relevance),
+        _ => None,
+    }
+}
+
+/// Removes the `sort:` tokens, which apply to the whole query and not to single terms
+fn take_sort(tokens: &mut Vec<Token>) -> Result<Option<SortOrder>, SearchParseError> {
+    let mut sort = None;
+    let mut i = 0;
+    while i < tokens.len() {
+        let TokenKind::Operator { name, value } = &tokens[i].kind else {
+            i += 1;
+            continue;
+        };
+        if name != "sort" {
+            i += 1;
+            continue;
+        }
+
+        let position = tokens[i].position;
+        let is_operator = |token: Option<&Token>, kinds: &[TokenKind]| {
+            token.is_some_and(|token| kinds.contains(&token.kind))
+        };
+        if is_operator(
+            i.checked_sub(1).and_then(|i| tokens.get(i)),
+            &[TokenKind::Not, TokenKind::Or, TokenKind::And],
+        ) || is_operator(tokens.get(i + 1), &[TokenKind::Or])
+        {
+            return Err(SearchParseError::new(
+                "sort: cannot be combined with other operators",
+                position,
+            ));
+        }
+        if sort.is_some() {
+            return Err(SearchParseError::new("Only one sort: is allowed", position));
+        }
+        sort = Some(parse_sort(value).ok_or_else(|| {
+            SearchParseError::new(
+                "Unknown value for sort:, use newest, oldest, largest, smallest or relevance",
+                position,
+            )
+        })?);
+        tokens.remove(i);
+    }
+
+    Ok(sort)
+}
+
+/// Parses a search query, relative dates count back from the current time
+pub fn parse_search_query(query: &str) -> Result<SearchQuery, SearchParseError> {
+    parse_search_query_at(query, Utc::now().naive_utc())
+}
+
+pub fn parse_search_query_at(
+    query: &str,
+    now: NaiveDateTime,
+) -> Result<SearchQuery, SearchParseError> {
+    let mut tokens = Lexer::tokenize(query)?;
+    let sort = take_sort(&mut tokens)?;
+    if tokens.is_empty() {
+        return Ok(SearchQuery { expr: None, sort });
+    }
+
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        query_length: query.chars().count(),
+        now,
+    };
+    let expr = parser.and()?;
+    if parser.peek().is_some() {
+        return Err(SearchParseError::new(
+            "Unexpected )",
+            parser.peek_position(),
+        ));
+    }
+
+    Ok(SearchQuery {
+        expr: Some(expr),
+        sort,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn now() -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 10, 18)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    }
+
     #[test]
-    fn test_basic_field_parsing() {
-        let result = parse_search_query("subject:foo");
-        assert_eq!(result.field_terms.len(), 1);
-        assert_eq!(result.field_terms[0].field, SearchField::Subject);
-        assert_eq!(result.field_terms[0].value, "foo");
-        assert_eq!(result.default_terms.len(), 0);
+    fn test_every_operator_is_parsed() {
+        for name in FILTER_OPERATORS {
+            if let Err(err) = parse_filter(name, "x", now(), 0) {
+                assert!(!err.message.starts_with("Unknown operator"), "{}", name);
+            }
+        }
+        assert_eq!(
+            parse_filter("nope", "x", now(), 3).unwrap_err(),
+            SearchParseError::new("Unknown operator nope:", 3)
+        );
+    }
+
+    fn parse(query: &str) -> SearchExpr {
+        parse_search_query_at(query, now()).unwrap().expr.unwrap()
+    }
+
+    fn term(field: Option<SearchField>, value: &str) -> SearchExpr {
+        SearchExpr::Term {
+            field,
+            value: value.to_string(),
+        }
+    }
+
+    fn not(expr: SearchExpr) -> SearchExpr {
+        SearchExpr::Not(Box::new(expr))
+    }
+
+    fn error(query: &str) -> SearchParseError {
+        parse_search_query_at(query, now()).unwrap_err()
     }
 
     #[test]
-    fn test_multiple_fields() {
-        let result = parse_search_query("subject:paypal recipient:john");
-        assert_eq!(result.field_terms.len(), 2);
-        assert_eq!(result.field_terms[0].field, SearchField::Subject);
-        assert_eq!(result.field_terms[0].value, "paypal");
-        assert_eq!(result.field_terms[1].field, SearchField::Recipient);
-        assert_eq!(result.field_terms[1].value, "john");
+    fn test_basic_field_parsing() {
+        assert_eq!(
+            parse("subject:foo"),
+            term(Some(SearchField::Subject), "foo")
+        );
     }
 
     #[test]
-    fn test_default_search() {
-        let result = parse_search_query("simple search");
-        assert_eq!(result.field_terms.len(), 0);
-        assert_eq!(result.default_terms.len(), 2);
-        assert_eq!(result.default_terms[0], "simple");
-        assert_eq!(result.default_terms[1], "search");
+    fn test_multiple_fields() {
+        assert_eq!(
+            parse("subject:paypal recipient:john"),
+            SearchExpr::And(vec![
+                term(Some(SearchField::Subject), "paypal"),
+                term(Some(SearchField::Recipient), "john"),
+            ])
+        );
     }
 
     #[test]
-    fn test_mixed_search() {
-        let result = parse_search_query("subject:paypal test");
-        assert_eq!(result.field_terms.len(), 1);
-        assert_eq!(result.field_terms[0].field, SearchField::Subject);
-        assert_eq!(result.field_terms[0].value, "paypal");
-        assert_eq!(result.default_terms.len(), 1);
-        assert_eq!(result.default_terms[0], "test");
+    fn test_default_search() {
+        assert_eq!(
+            parse("simple search"),
+            SearchExpr::And(vec![term(None, "simple"), term(None, "search")])
+        );
     }
 
     #[test]
     fn test_mixed_search_with_from() {
-        let result = parse_search_query("from:paypal birthdayparty");
-        assert_eq!(result.field_terms.len(), 1);
-        assert_eq!(result.field_terms[0].field, SearchField::From);
-        assert_eq!(result.field_terms[0].value, "paypal");
-        assert_eq!(result.default_terms.len(), 1);
-        assert_eq!(result.default_terms[0], "birthdayparty");
+        assert_eq!(
+            parse("from:paypal birthdayparty"),
+            SearchExpr::And(vec![
+                term(Some(SearchField::From), "paypal"),
+                term(None, "birthdayparty"),
+            ])
+        );
     }
 
     #[test]
     fn test_field_aliases() {
-        let result1 = parse_search_query("to:john");
-        let result2 = parse_search_query("recipient:john");
-        assert_eq!(result1.field_terms[0].field, SearchField::Recipient);
-        assert_eq!(result2.field_terms[0].field, SearchField::Recipient);
+        assert_eq!(parse("to:john"), term(Some(SearchField::Recipient), "john"));
+        assert_eq!(
+            parse("recipient:john"),
+            term(Some(SearchField::Recipient), "john")
+        );
+        assert_eq!(
+            parse("Subject:john"),
+            term(Some(SearchField::Subject), "john")
+        );
     }
 
     #[test]
     fn test_empty_string() {
-        let result = parse_search_query("");
-        assert_eq!(result.field_terms.len(), 0);
-        assert_eq!(result.default_terms.len(), 0);
+        assert_eq!(parse_search_query(""), Ok(SearchQuery::default()));
+        assert_eq!(parse_search_query("   "), Ok(SearchQuery::default()));
     }
 
     #[test]
-    fn test_whitespace_only() {
-        let result = parse_search_query("   ");
-        assert_eq!(result.field_terms.len(), 0);
-        assert_eq!(result.default_terms.len(), 0);
+    fn test_unknown_field_is_a_term() {
+        assert_eq!(
+            parse("invalid:field https://example.com"),
+            SearchExpr::And(vec![
+                term(None, "invalid:field"),
+                term(None, "https://example.com"),
+            ])
+        );
     }
 
     #[test]
-    fn test_malformed_field() {
-        let result = parse_search_query("invalid:field test");
-        assert_eq!(result.field_terms.len(), 0);
-        assert_eq!(result.default_terms.len(), 2);
+    fn test_all_field_types() {
+        assert_eq!(
+            parse("subject:test from:sender recipient:recv text:body html:content attachment:file"),
+            SearchExpr::And(vec![
+                term(Some(SearchField::Subject), "test"),
+                term(Some(SearchField::From), "sender"),
+                term(Some(SearchField::Recipient), "recv"),
+                term(Some(SearchField::Text), "body"),
+                term(Some(SearchField::Html), "content"),
+                term(Some(SearchField::Attachment), "file"),
+            ])
+        );
     }
 
     #[test]
-    fn test_multiple_default_terms() {
-        let result = parse_search_query("test search query");
-        assert_eq!(result.default_terms.len(), 3);
-        assert_eq!(result.default_terms[0], "test");
-        assert_eq!(result.default_terms[1], "search");
-        assert_eq!(result.default_terms[2], "query");
+    fn test_value_with_underscore_and_dash() {
+        assert_eq!(
+            parse("subject:test_value subject:test-value"),
+            SearchExpr::And(vec![
+                term(Some(SearchField::Subject), "test_value"),
+                term(Some(SearchField::Subject), "test-value"),
+            ])
+        );
     }
 
     #[test]
-    fn test_all_field_types() {
-        let result = parse_search_query(
-            "subject:test from:sender recipient:recv text:body html:content attachment:file",
+    fn test_quoted_phrases() {
+        assert_eq!(
+            parse(r#""your invoice" subject:"Order #12 shipped" "say \"hi\"""#),
+            SearchExpr::And(vec![
+                term(None, "your invoice"),
+                term(Some(SearchField::Subject), "Order #12 shipped"),
+                term(None, r#"say "hi""#),
+            ])
         );
-        assert_eq!(result.field_terms.len(), 6);
-        assert_eq!(result.field_terms[0].field, SearchField::Subject);
-        assert_eq!(result.field_terms[1].field, SearchField::From);
-        assert_eq!(result.field_terms[2].field, SearchField::Recipient);
-        assert_eq!(result.field_terms[3].field, SearchField::Text);
-        assert_eq!(result.field_terms[4].field, SearchField::Html);
-        assert_eq!(result.field_terms[5].field, SearchField::Attachment);
     }
 
     #[test]
-    fn test_complex_mixed() {
-        let result = parse_search_query("from:paypal subject:invoice payment");
-        assert_eq!(result.field_terms.len(), 2);
-        assert_eq!(result.field_terms[0].field, SearchField::From);
-        assert_eq!(result.field_terms[1].field, SearchField::Subject);
-        assert_eq!(result.default_terms.len(), 1);
-        assert_eq!(result.default_terms[0], "payment");
+    fn test_or_binds_tighter_than_and() {
+        assert_eq!(
+            parse("from:bob invoice OR receipt"),
+            SearchExpr::And(vec![
+                term(Some(SearchField::From), "bob"),
+                SearchExpr::Or(vec![term(None, "invoice"), term(None, "receipt")]),
+            ])
+        );
+        assert_eq!(
+            parse("a OR b AND c"),
+            SearchExpr::And(vec![
+                SearchExpr::Or(vec![term(None, "a"), term(None, "b")]),
+                term(None, "c"),
+            ])
+        );
     }
 
     #[test]
-    fn test_value_with_underscore() {
-        let result = parse_search_query("subject:test_value");
-        assert_eq!(result.field_terms.len(), 1);
-        assert_eq!(result.field_terms[0].value, "test_value");
+    fn test_lowercase_operators_are_terms() {
+        assert_eq!(
+            parse("cats or dogs"),
+            SearchExpr::And(vec![
+                term(None, "cats"),
+                term(None, "or"),
+                term(None, "dogs")
+            ])
+        );
     }
 
     #[test]
-    fn test_value_with_dash() {
-        let result = parse_search_query("subject:test-value");
-        assert_eq!(result.field_terms.len(), 1);
-        assert_eq!(result.field_terms[0].value, "test-value");
+    fn test_negation() {
+        assert_eq!(
+            parse("invoice -paid NOT subject:draft"),
+            SearchExpr::And(vec![
+                term(None, "invoice"),
+                not(term(None, "paid")),
+                not(term(Some(SearchField::Subject), "draft")),
+            ])
+        );
+        assert_eq!(
+            parse("-\"out of office\""),
+            not(term(None, "out of office"))
+        );
+    }
+
+    #[test]
+    fn test_dash_inside_and_alone() {
+        assert_eq!(
+            parse("e-mail - x"),
+            SearchExpr::And(vec![term(None, "e-mail"), term(None, "-"), term(None, "x")])
+        );
+    }
+
+    #[test]
+    fn test_parentheses() {
+        assert_eq!(
+            parse("(from:alice OR from:bob) -(subject:re OR subject:fwd)"),
+            SearchExpr::And(vec![
+                SearchExpr::Or(vec![
+                    term(Some(SearchField::From), "alice"),
+                    term(Some(SearchField::From), "bob"),
+                ]),
+                not(SearchExpr::Or(vec![
+                    term(Some(SearchField::Subject), "re"),
+                    term(Some(SearchField::Subject), "fwd"),
+                ])),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            error("\"unclosed"),
+            SearchParseError::new("Unclosed quote", 0)
+        );
+        assert_eq!(error("a \"\""), SearchParseError::new("Empty phrase", 2));
+        assert_eq!(
+            error("subject: foo"),
+            SearchParseError::new("Missing value for subject:", 0)
+        );
+        assert_eq!(
+            error("(a OR b"),
+            SearchParseError::new("Unclosed parenthesis", 0)
+        );
+        assert_eq!(error("a)"), SearchParseError::new("Unexpected )", 1));
+        assert_eq!(error("()"), SearchParseError::new("Empty parentheses", 0));
+        assert_eq!(
+            error("a OR"),
+            SearchParseError::new("Expected a term after OR", 4)
+        );
+        assert_eq!(
+            error("OR a"),
+            SearchParseError::new("Expected a term before OR", 0)
+        );
+        assert_eq!(
+            error("a NOT"),
+            SearchParseError::new("Expected a term after NOT", 5)
+        );
+        assert_eq!(
+            error("a AND OR b"),
+            SearchParseError::new("Expected a term after AND", 6)
+        );
+    }
+
+    #[test]
+    fn test_error_display_is_one_based() {
+        assert_eq!(
+            error("a OR").to_string(),
+            "Expected a term after OR at position 5"
+        );
+    }
+
+    #[test]
+    fn test_date_filters() {
+        assert_eq!(
+            parse("after:10m before:2026-10-18"),
+            SearchExpr::And(vec![
+                SearchExpr::Filter(SearchFilter::After(now() - Duration::minutes(10))),
+                SearchExpr::Filter(SearchFilter::Before(
+                    NaiveDate::from_ymd_opt(2026, 10, 18)
+                        .unwrap()
+                        .and_hms_opt(0, 0, 0)
+                        .unwrap()
+                )),
+            ])
+        );
+        assert_eq!(
+            parse("after:2026-10-01T08:30"),
+            SearchExpr::Filter(SearchFilter::After(
+                NaiveDate::from_ymd_opt(2026, 10, 1)
+                    .unwrap()
+                    .and_hms_opt(8, 30, 0)
+                    .unwrap()
+            ))
+        );
+        assert_eq!(
+            parse("after:2026-10-01T08:30:00+02:00"),
+            SearchExpr::Filter(SearchFilter::After(
+                NaiveDate::from_ymd_opt(2026, 10, 1)
+                    .unwrap()
+                    .and_hms_opt(6, 30, 0)
+                    .unwrap()
+            ))
+        );
+        assert_eq!(
+            parse("before:2w"),
+            SearchExpr::Filter(SearchFilter::Before(now() - Duration::weeks(2)))
+        );
+    }
+
+    #[test]
+    fn test_size_filters() {
+        assert_eq!(
+            parse("size:>1mb"),
+            SearchExpr::Filter(SearchFilter::Size(Comparison::Greater, 1024 * 1024))
+        );
+        assert_eq!(
+            parse("size:<=1.5KB"),
+            SearchExpr::Filter(SearchFilter::Size(Comparison::LessOrEqual, 1536))
+        );
+        assert_eq!(
+            parse("size:2048"),
+            SearchExpr::Filter(SearchFilter::Size(Comparison::GreaterOrEqual, 2048))
+        );
+    }
+
+    #[test]
+    fn test_state_filters() {
+        assert_eq!(
+            parse("is:unread has:attachment -has:html IS:Read has:text is:starred has:warnings"),
+            SearchExpr::And(vec![
+                SearchExpr::Filter(SearchFilter::Read(false)),
+                SearchExpr::Filter(SearchFilter::HasAttachment),
+                not(SearchExpr::Filter(SearchFilter::HasHtml)),
+                SearchExpr::Filter(SearchFilter::Read(true)),
+                SearchExpr::Filter(SearchFilter::HasText),
+                SearchExpr::Filter(SearchFilter::Starred(true)),
+                SearchExpr::Filter(SearchFilter::HasWarnings),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_sort() {
+        assert_eq!(
+            parse_search_query_at("invoice sort:oldest", now()),
+            Ok(SearchQuery {
+                expr: Some(term(None, "invoice")),
+                sort: Some(SortOrder::Oldest),
+            })
+        );
+        assert_eq!(
+            parse_search_query_at("sort:largest", now()),
+            Ok(SearchQuery {
+                expr: None,
+                sort: Some(SortOrder::Largest),
+            })
+        );
+    }
+
+    #[test]
+    fn test_filter_errors() {
+        assert_eq!(
+            error("a after:yesterday"),
+            SearchParseError::new("Invalid date for after:, use e.g. 2026-10-01 or 10m", 2)
+        );
+        assert_eq!(
+            error("size:>1tb"),
+            SearchParseError::new("Invalid size for size:, use e.g. >1mb or <500kb", 0)
+        );
+        assert_eq!(
+            error("is:flagged"),
+            SearchParseError::new(
+                "Unknown value for is:, use read, unread, starred or unstarred",
+                0
+            )
+        );
+        assert_eq!(
+            error("has:pdf"),
+            SearchParseError::new(
+                "Unknown value for has:, use attachment, html, text or warnings",
+                0
+            )
+        );
+        assert_eq!(
+            error("sort:name"),
+            SearchParseError::new(
+                "Unknown value for sort:, use newest, oldest, largest, smallest or relevance",
+                0
+            )
+        );
+        assert_eq!(
+            error("a OR sort:oldest"),
+            SearchParseError::new("sort: cannot be combined with other operators", 5)
+        );
+        assert_eq!(
+            error("sort:oldest sort:newest"),
+            SearchParseError::new("Only one sort: is allowed", 12)
+        );
+    }
+
+    #[test]
+    fn test_header_filters() {
+        assert_eq!(
+            parse("header:X-Campaign-Id:abc header:x-tag:\"a b\" header:List-Id"),
+            SearchExpr::And(vec![
+                SearchExpr::Filter(SearchFilter::Header {
+                    name: "X-Campaign-Id".to_string(),
+                    value: Some("abc".to_string()),
+                }),
+                SearchExpr::Filter(SearchFilter::Header {
+                    name: "x-tag".to_string(),
+                    value: Some("a b".to_string()),
+                }),
+                SearchExpr::Filter(SearchFilter::Header {
+                    name: "List-Id".to_string(),
+                    value: None,
+                }),
+            ])
+        );
+        assert_eq!(
+            parse("header:\"X-Note:two words\""),
+            SearchExpr::Filter(SearchFilter::Header {
+                name: "X-Note".to_string(),
+                value: Some("two words".to_string()),
+            })
+        );
+        assert_eq!(
+            error("header:"),
+            SearchParseError::new("Missing value for header:", 0)
+        );
+    }
+
+    #[test]
+    fn test_message_id_filters() {
+        assert_eq!(
+            parse("message-id:<1234@example.com> in-reply-to:abc@example.com"),
+            SearchExpr::And(vec![
+                SearchExpr::Filter(SearchFilter::MessageId("1234@example.com".to_string())),
+                SearchExpr::Filter(SearchFilter::InReplyTo("abc@example.com".to_string())),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_regex_filters() {
+        assert_eq!(
+            parse(r#"/\b\d{6}\b/ text~:reset\?token= subject~:"^Re: \\d""#),
+            SearchExpr::And(vec![
+                SearchExpr::Filter(SearchFilter::Regex {
+                    field: None,
+                    pattern: r"\b\d{6}\b".to_string(),
+                }),
+                SearchExpr::Filter(SearchFilter::Regex {
+                    field: Some(SearchField::Text),
+                    pattern: r"reset\?token=".to_string(),
+                }),
+                SearchExpr::Filter(SearchFilter::Regex {
+                    field: Some(SearchField::Subject),
+                    pattern: r"^Re: \d".to_string(),
+                }),
+            ])
+        );
+        assert_eq!(
+            parse(r"/a b\/c/"),
+            SearchExpr::Filter(SearchFilter::Regex {
+                field: None,
+                pattern: "a b/c".to_string(),
+            })
+        );
+        // Paths are plain terms
+        assert_eq!(parse("/reset/abc"), term(None, "/reset/abc"));
+    }
+
+    #[test]
+    fn test_regex_errors() {
+        assert_eq!(
+            error("code /[0-9/"),
+            SearchParseError::new("Invalid regular expression", 5)
+        );
+        assert_eq!(
+            error(r"text~:\w{1000}{1000}"),
+            SearchParseError::new("Pattern is too complex, use fewer repetitions", 0)
+        );
+        assert_eq!(
+            error(&format!("/{}/", "a".repeat(257))),
+            SearchParseError::new("Pattern is longer than 256 characters", 0)
+        );
+        assert_eq!(
+            error(&format!("/{}a{}/", "(".repeat(20), ")".repeat(20))),
+            SearchParseError::new("Pattern is too deeply nested", 0)
+        );
+    }
+
+    #[test]
+    fn test_and_combines_queries() {
+        let base = parse_search_query_at("from:noreply sort:oldest", now()).unwrap();
+        let combined = base.and(parse_search_query_at("reset OR token", now()).unwrap());
+        assert_eq!(
+            combined,
+            SearchQuery {
+                expr: Some(SearchExpr::And(vec![
+                    term(Some(SearchField::From), "noreply"),
+                    SearchExpr::Or(vec![term(None, "reset"), term(None, "token")]),
+                ])),
+                sort: Some(SortOrder::Oldest),
+            }
+        );
     }
 }
@@ -0,0 +1,675 @@
+use chrono::NaiveDateTime;
+use diesel::query_builder::{BoxedSqlQuery, SqlQuery};
+use diesel::sql_types::{BigInt, Double, Text, Timestamp};
+use diesel::sqlite::Sqlite;
+
+use crate::db::search_query::{Comparison, SearchExpr, SearchField, SearchFilter};
+
+/// The trigram tokenizer cannot match shorter terms, those fall back to `LIKE`
+const MIN_MATCH_TERM_LENGTH: usize = 3;
+/// Columns of `email_search` a term without a field is matched against
+const DEFAULT_COLUMNS: &[&str] = &["subject", "sender", "recipients", "body_text"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Text(String),
+    BigInt(i64),
+    Timestamp(NaiveDateTime),
+    Double(f64),
+}
+
+impl From<String> for SqlValue {
+    fn from(value: String) -> Self {
+        SqlValue::Text(value)
+    }
+}
+
+impl From<&str> for SqlValue {
+    fn from(value: &str) -> Self {
+        SqlValue::Text(value.to_string())
+    }
+}
+
+impl From<i64> for SqlValue {
+    fn from(value: i64) -> Self {
+        SqlValue::BigInt(value)
+    }
+}
+
+impl From<NaiveDateTime> for SqlValue {
+    fn from(value: NaiveDateTime) -> Self {
+        SqlValue::Timestamp(value)
+    }
+}
+
+/// SQL text with `?` placeholders and the values bound to them, in order.
+///
+/// User input only ever ends up in `binds`, never in the SQL text itself.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SqlFragment {
+    pub sql: String,
+    pub binds: Vec<SqlValue>,
+}
+
+impl SqlFragment {
+    pub fn new(sql: &str) -> Self {
+        Self {
+            sql: sql.to_string(),
+            binds: Vec::new(),
+        }
+    }
+
+    pub fn push_sql(&mut self, sql: &str) -> &mut Self {
+        self.sql.push_str(sql);
+        self
+    }
+
+    /// Appends a `?` placeholder bound to `value`
+    pub fn push_bind(&mut self, value: impl Into<SqlValue>) -> &mut Self {
+        self.sql.push('?');
+        self.binds.push(value.into());
+        self
+    }
+
+    pub fn push_fragment(&mut self, fragment: SqlFragment) -> &mut Self {
+        self.sql.push_str(&fragment.sql);
+        self.binds.extend(fragment.binds);
+        self
+    }
+
+    /// Joins the fragments with `separator`, wrapped in parentheses
+    pub fn join(fragments: Vec<SqlFragment>, separator: &str) -> SqlFragment {
+        let mut joined = SqlFragment::new("(");
+        for (i, fragment) in fragments.into_iter().enumerate() {
+            if i > 0 {
+                joined.push_sql(separator);
+            }
+            joined.push_fragment(fragment);
+        }
+        joined.push_sql(")");
+        joined
+    }
+
+    /// `(?, ?, ...)` bound to `values`
+    pub fn bind_list(values: &[String]) -> SqlFragment {
+        let binds = values
+            .iter()
+            .map(|value| {
+                let mut bind = SqlFragment::default();
+                bind.push_bind(value.as_str());
+                bind
+            })
+            .collect();
+        SqlFragment::join(binds, ", ")
+    }
+
+    pub fn into_query(self) -> BoxedSqlQuery<'static, Sqlite, SqlQuery> {
+        let mut query = diesel::sql_query(self.sql).into_boxed::<Sqlite>();
+        for value in self.binds {
+            query = match value {
+                SqlValue::Text(value) => query.bind::<Text, _>(value),
+                SqlValue::BigInt(value) => query.bind::<BigInt, _>(value),
+                SqlValue::Timestamp(value) => query.bind::<Timestamp, _>(value),
+                SqlValue::Double(value) => query.bind::<Double, _>(value),
+            };
+        }
+        query
+    }
+}
+
+/// A search query compiled to conditions on `emails` and the `email_search` index
+#[derive(Debug, Default, PartialEq)]
+pub struct SearchSql {
+    /// FTS5 query for the part of the search the index can answer on its own. Bound to
+    /// `email_search MATCH ?`, it enables ranking and snippets.
+    pub match_expression: Option<String>,
+    /// Everything else, to be added to the `WHERE` clause
+    pub condition: Option<SqlFragment>,
+    /// Whether `condition` refers to the `email_search` table
+    pub condition_uses_index: bool,
+}
+
+impl SearchSql {
+    pub fn uses_index(&self) -> bool {
+        self.match_expression.is_some() || self.condition_uses_index
+    }
+}
+
+pub fn build_search_sql(expr: Option<&SearchExpr>) -> SearchSql {
+    let Some(expr) = expr else {
+        return SearchSql::default();
+    };
+
+    let children: Vec<&SearchExpr> = match expr {
+        SearchExpr::And(children) => children.iter().collect(),
+        expr => vec![expr],
+    };
+
+    let mut match_parts = Vec::new();
+    let mut conditions = Vec::new();
+    let mut condition_uses_index = false;
+    for child in children {
+        match match_expression(child) {
+            Some(part) => match_parts.push(part),
+            None => conditions.push(condition(child, &mut condition_uses_index)),
+        }
+    }
+
+    SearchSql {
+        match_expression: (!match_parts.is_empty()).then(|| match_parts.join(" AND ")),
+        condition: match conditions.len() {
+            0 => None,
+            1 => conditions.pop(),
+            _ => Some(SqlFragment::join(conditions, " AND ")),
+        },
+        condition_uses_index,
+    }
+}
+
+fn field_columns(field: Option<&SearchField>) -> &'static [&'static str] {
+    match field {
+        None => DEFAULT_COLUMNS,
+        Some(SearchField::Subject) => &["subject"],
+        Some(SearchField::From) => &["sender"],
+        Some(SearchField::Recipient | SearchField::To) => &["recipients"],
+        Some(SearchField::Text) => &["body_text"],
+        Some(SearchField::Html) => &["body_html"],
+        Some(SearchField::Attachment) => &["attachments"],
+        // Tags change after saving, they are not part of the index
+        Some(SearchField::Tag) => &[],
+    }
+}
+
+/// The FTS5 query for `expr`, `None` if the index cannot answer all of it
+fn match_expression(expr: &SearchExpr) -> Option<String> {
+    match expr {
+        SearchExpr::Term { field, value } => {
+            if field == &Some(SearchField::Tag) || value.chars().count() < MIN_MATCH_TERM_LENGTH {
+                return None;
+            }
+            // A quoted string is matched literally by FTS5, quotes are doubled inside
+            Some(format!(
+                "{{{}}} : \"{}\"",
+                field_columns(field.as_ref()).join(" "),
+                value.replace('"', "\"\"")
+            ))
+        }
+        SearchExpr::And(children) => join_match_expressions(children, " AND "),
+        SearchExpr::Or(children) => join_match_expressions(children, " OR "),
+        // FTS5 only knows the binary `a NOT b`, negations become SQL conditions instead
+        SearchExpr::Not(_) => None,
+        SearchExpr::Filter(_) => None,
+    }
+}
+
+fn join_match_expressions(children: &[SearchExpr], operator: &str) -> Option<String> {
+    let parts = children
+        .iter()
+        .map(match_expression)
+        .collect::<Option<Vec<_>>>()?;
+    Some(format!("({})", parts.join(operator)))
+}
+
+fn condition(expr: &SearchExpr, uses_index: &mut bool) -> SqlFragment {
+    match expr {
+        SearchExpr::Term {
+            field: Some(SearchField::Tag),
+            value,
+        } => {
+            let mut fragment = SqlFragment::new(
+                "EXISTS (SELECT 1 FROM email_tags \
+                 INNER JOIN tags ON tags.id = email_tags.tag_id \
+                 WHERE email_tags.email_id = emails.id AND tags.name = ",
+            );
+            fragment.push_bind(value.as_str()).push_sql(")");
+            fragment
+        }
+        SearchExpr::Term { field, value } => {
+            *uses_index = true;
+            let columns = field_columns(field.as_ref());
+            if let Some(expression) = match_expression(expr) {
+                let mut fragment = SqlFragment::new(
+                    "email_search.rowid IN (SELECT rowid FROM email_search WHERE email_search MATCH ",
+                );
+                fragment.push_bind(expression).push_sql(")");
+                return fragment;
+            }
+
+            let pattern = format!("%{}%", escape_like(value));
+            let likes = columns
+                .iter()
+                .map(|column| {
+                    let mut like = SqlFragment::new(&format!("email_search.{} LIKE ", column));
+                    like.push_bind(pattern.as_str()).push_sql(" ESCAPE '\\'");
+                    like
+                })
+                .collect();
+            SqlFragment::join(likes, " OR ")
+        }
+        SearchExpr::And(children) => SqlFragment::join(
+            children
+                .iter()
+                .map(|child| condition(child, uses_index))
+                .collect(),
+            " AND ",
+        ),
+        SearchExpr::Or(children) => SqlFragment::join(
+            children
+                .iter()
+                .map(|child| condition(child, uses_index))
+                .collect(),
+            " OR ",
+        ),
+        SearchExpr::Not(child) => {
+            let mut fragment = SqlFragment::new("NOT ");
+            fragment.push_fragment(SqlFragment::join(vec![condition(child, uses_index)], ""));
+            fragment
+        }
+        SearchExpr::Filter(filter) => filter_condition(filter),
+    }
+}
+
+/// Filters only look at columns of `emails`, never at the index
+fn filter_condition(filter: &SearchFilter) -> SqlFragment {
+    let mut fragment = SqlFragment::default();
+    match filter {
+        SearchFilter::After(time) => {
+            fragment.push_sql("emails.created_at >= ").push_bind(*time);
+        }
+        SearchFilter::Before(time) => {
+            fragment.push_sql("emails.created_at < ").push_bind(*time);
+        }
+        SearchFilter::Size(comparison, bytes) => {
+            let operator = match comparison {
+                Comparison::Less => "<",
+                Comparison::LessOrEqual => "<=",
+                Comparison::Greater => ">",
+                Comparison::GreaterOrEqual => ">=",
+            };
+            fragment
+                .push_sql(&format!("emails.size {} ", operator))
+                .push_bind(i64::try_from(*bytes).unwrap_or(i64::MAX));
+        }
+        SearchFilter::Read(true) => {
+            fragment.push_sql("emails.read = 1");
+        }
+        SearchFilter::Read(false) => {
+            fragment.push_sql("emails.read = 0");
+        }
+        SearchFilter::Starred(true) => {
+            fragment.push_sql("emails.starred = 1");
+        }
+        SearchFilter::Starred(false) => {
+            fragment.push_sql("emails.starred = 0");
+        }
+        SearchFilter::HasAttachment => {
+            fragment.push_sql("emails.has_attachments = 1");
+        }
+        SearchFilter::HasHtml => {
+            fragment.push_sql("(emails.body_html IS NOT NULL AND emails.body_html != '')");
+        }
+        SearchFilter::HasText => {
+            fragment.push_sql("(emails.body_text IS NOT NULL AND emails.body_text != '')");
+        }
+        SearchFilter::HasWarnings => {
+            fragment.push_sql(
+                "EXISTS (SELECT 1 FROM email_warnings WHERE email_warnings.email_id = emails.id)",
+            );
+        }
+        SearchFilter::Header { name, value } => {
+            fragment = header_condition(name, value.as_deref().map(escape_like));
+        }
+        SearchFilter::MessageId(message_id) => {
+            fragment
+                .push_sql("emails.message_id = ")
+                .push_bind(message_id.as_str());
+        }
+        SearchFilter::Regex { field, pattern } => {
+            let columns: &[&str] = match field {
+                Some(SearchField::Subject) => &["subject"],
+                Some(SearchField::Html) => &["body_html"],
+                Some(SearchField::Text) => &["body_text"],
+                _ => &["subject", "body_text"],
+            };
+            let regexes = columns
+                .iter()
+                .map(|column| {
+                    let mut regex = SqlFragment::new(&format!("emails.{} REGEXP ", column));
+                    regex.push_bind(pattern.as_str());
+                    regex
+                })
+                .collect();
+            fragment = SqlFragment::join(regexes, " OR ");
+        }
+        SearchFilter::InReplyTo(message_id) => {
+            // Message-IDs are case-sensitive, so the reply has to name exactly this one. mail-parser
+            // stores the header under this spelling, which lets the lookup use the name index.
+            fragment
+                .push_sql(
+                    "EXISTS (SELECT 1 FROM headers WHERE headers.email_id = emails.id \
+                     AND headers.name = 'In-Reply-To' \
+                     AND TRIM(headers.decoded, ' \t\r\n') = ",
+                )
+                .push_bind(format!("<{}>", message_id))
+                .push_sql(")");
+        }
+    }
+    fragment
+}
+
+/// Header values are stored as received, so `pattern` is matched anywhere in the value
+fn header_condition(name: &str, pattern: Option<String>) -> SqlFragment {
+    let mut fragment = SqlFragment::new(
+        "EXISTS (SELECT 1 FROM headers WHERE headers.email_id = emails.id \
+         AND LOWER(headers.name) = ",
+    );
+    fragment.push_bind(name.to_lowercase());
+    if let Some(pattern) = pattern {
+        fragment
+            .push_sql(" AND (headers.value LIKE ")
+            .push_bind(format!("%{}%", pattern))
+            .push_sql(" ESCAPE '\\' OR headers.decoded LIKE ")
+            .push_bind(format!("%{}%", pattern))
+            .push_sql(" ESCAPE '\\')");
+    }
+    fragment.push_sql(")");
+    fragment
+}
+
+/// Escapes the `LIKE` wildcards `%` and `_`, for use with `ESCAPE '\'`
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::search_query::parse_search_query;
+
+    fn build(query: &str) -> SearchSql {
+        build_search_sql(parse_search_query(query).unwrap().expr.as_ref())
+    }
+
+    #[test]
+    fn test_empty_query() {
+        let sql = build("");
+        assert_eq!(sql, SearchSql::default());
+        assert!(!sql.uses_index());
+    }
+
+    #[test]
+    fn test_terms_are_combined_into_one_match() {
+        let sql = build("hello subject:invoice to:bob@example.com");
+        assert_eq!(
+            sql.match_expression.as_deref(),
+            Some(
+                "{subject sender recipients body_text} : \"hello\" AND {subject} : \"invoice\" \
+                 AND {recipients} : \"bob@example.com\""
+            )
+        );
+        assert_eq!(sql.condition, None);
+    }
+
+    #[test]
+    fn test_quotes_stay_inside_the_match_string() {
+        let sql = build("\"say \\\"hi\\\"\" it's");
+        assert_eq!(
+            sql.match_expression.as_deref(),
+            Some(
+                "{subject sender recipients body_text} : \"say \"\"hi\"\"\" AND \
+                 {subject sender recipients body_text} : \"it's\""
+            )
+        );
+    }
+
+    #[test]
+    fn test_wildcards_are_matched_literally() {
+        let sql = build("100% user_name");
+        assert_eq!(
+            sql.match_expression.as_deref(),
+            Some(
+                "{subject sender recipients body_text} : \"100%\" AND \
+                 {subject sender recipients body_text} : \"user_name\""
+            )
+        );
+
+        let sql = build("attachment:%_");
+        let condition = sql.condition.unwrap();
+        assert_eq!(
+            condition.sql,
+            "(email_search.attachments LIKE ? ESCAPE '\\')"
+        );
+        assert_eq!(condition.binds, vec![SqlValue::from("%\\%\\_%")]);
+    }
+
+    #[test]
+    fn test_short_terms_are_bound_for_every_column() {
+        let sql = build("invoice 'a");
+        assert_eq!(
+            sql.match_expression.as_deref(),
+            Some("{subject sender recipients body_text} : \"invoice\"")
+        );
+        assert!(sql.uses_index());
+
+        let condition = sql.condition.unwrap();
+        assert_eq!(
+            condition.sql,
+            "(email_search.subject LIKE ? ESCAPE '\\' OR email_search.sender LIKE ? ESCAPE '\\' \
+             OR email_search.recipients LIKE ? ESCAPE '\\' OR email_search.body_text LIKE ? \
+             ESCAPE '\\')"
+        );
+        assert_eq!(condition.binds, vec![SqlValue::from("%'a%"); 4]);
+    }
+
+    #[test]
+    fn test_fragment_join() {
+        let mut a = SqlFragment::new("a = ");
+        a.push_bind("x");
+        let mut b = SqlFragment::new("b = ");
+        b.push_bind(1i64);
+
+        let joined = SqlFragment::join(vec![a, b], " OR ");
+        assert_eq!(joined.sql, "(a = ? OR b = ?)");
+        assert_eq!(
+            joined.binds,
+            vec![SqlValue::Text("x".to_string()), SqlValue::BigInt(1)]
+        );
+    }
+
+    #[test]
+    fn test_or_of_indexed_terms_is_one_match() {
+        let sql = build("from:bob (invoice OR subject:receipt)");
+        assert_eq!(
+            sql.match_expression.as_deref(),
+            Some(
+                "{sender} : \"bob\" AND \
+                 ({subject sender recipients body_text} : \"invoice\" OR {subject} : \"receipt\")"
+            )
+        );
+        assert_eq!(sql.condition, None);
+    }
+
+    #[test]
+    fn test_negation_becomes_a_condition() {
+        let sql = build("invoice -subject:paid");
+        assert_eq!(
+            sql.match_expression.as_deref(),
+            Some("{subject sender recipients body_text} : \"invoice\"")
+        );
+
+        let condition = sql.condition.unwrap();
+        assert_eq!(
+            condition.sql,
+            "NOT (email_search.rowid IN \
+             (SELECT rowid FROM email_search WHERE email_search MATCH ?))"
+        );
+        assert_eq!(
+            condition.binds,
+            vec![SqlValue::from("{subject} : \"paid\"")]
+        );
+    }
+
+    #[test]
+    fn test_or_with_a_short_term_becomes_a_condition() {
+        let sql = build("invoice OR subject:re");
+        assert_eq!(sql.match_expression, None);
+        assert!(sql.uses_index());
+
+        let condition = sql.condition.unwrap();
+        assert_eq!(
+            condition.sql,
+            "(email_search.rowid IN (SELECT rowid FROM email_search WHERE email_search MATCH ?) \
+             OR (email_search.subject LIKE ? ESCAPE '\\'))"
+        );
+        assert_eq!(
+            condition.binds,
+            vec![
+                SqlValue::from("{subject sender recipients body_text} : \"invoice\""),
+                SqlValue::from("%re%"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filters_are_conditions_on_emails() {
+        let sql = build("invoice is:unread -has:attachment size:>1mb");
+        assert_eq!(
+            sql.match_expression.as_deref(),
+            Some("{subject sender recipients body_text} : \"invoice\"")
+        );
+        assert!(!sql.condition_uses_index);
+
+        let condition = sql.condition.unwrap();
+        assert_eq!(
+            condition.sql,
+            "(emails.read = 0 AND NOT (emails.has_attachments = 1) AND emails.size > ?)"
+        );
+        assert_eq!(condition.binds, vec![SqlValue::BigInt(1024 * 1024)]);
+    }
+
+    #[test]
+    fn test_date_filters_bind_timestamps() {
+        let sql = build("after:2026-10-01 before:2026-10-02");
+        assert_eq!(sql.match_expression, None);
+        assert!(!sql.uses_index());
+
+        let condition = sql.condition.unwrap();
+        assert_eq!(
+            condition.sql,
+            "(emails.created_at >= ? AND emails.created_at < ?)"
+        );
+        let day = |day| {
+            chrono::NaiveDate::from_ymd_opt(2026, 10, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        assert_eq!(
+            condition.binds,
+            vec![SqlValue::Timestamp(day(1)), SqlValue::Timestamp(day(2))]
+        );
+    }
+
+    #[test]
+    fn test_header_filters_look_up_the_headers_table() {
+        let condition = build("header:X-Campaign-Id:50% in-reply-to:<a@b>")
+            .condition
+            .unwrap();
+        assert_eq!(
+            condition.sql,
+            "(EXISTS (SELECT 1 FROM headers WHERE headers.email_id = emails.id \
+             AND LOWER(headers.name) = ? \
+             AND (headers.value LIKE ? ESCAPE '\\' OR headers.decoded LIKE ? ESCAPE '\\')) \
+             AND EXISTS (SELECT 1 FROM headers WHERE headers.email_id = emails.id \
+             AND headers.name = 'In-Reply-To' \
+             AND TRIM(headers.decoded, ' \t\r\n') = ?))"
+        );
+        assert_eq!(
+            condition.binds,
+            vec![
+                SqlValue::from("x-campaign-id"),
+                SqlValue::from("%50\\%%"),
+                SqlValue::from("%50\\%%"),
+                SqlValue::from("<a@b>"),
+            ]
+        );
+
+        let condition = build("message-id:<a@b>").condition.unwrap();
+        assert_eq!(condition.sql, "emails.message_id = ?");
+        assert_eq!(condition.binds, vec![SqlValue::from("a@b")]);
+    }
+
+    #[test]
+    fn test_in_reply_to_matches_the_message_id_exactly() {
+        let pool = crate::db::testing::pool();
+        let mut conn = pool.get().unwrap();
+        let mut ids = Vec::new();
+        for in_reply_to in ["<Parent@Example.com>", "<Parent@Example.com.au>"] {
+            let raw = format!(
+                "In-Reply-To: {}\r\nSubject: Re\r\n\r\nBody\r\n",
+                in_reply_to
+            );
+            let parsed = mail_parser::MessageParser::default()
+                .parse(raw.as_bytes())
+                .unwrap();
+            let mut email = crate::db::testing::email("Re", &["a@x.com"]);
+            email.raw_headers = crate::header::extract_raw_headers(&parsed, raw.as_bytes());
+            crate::db::save_email::save_emails(&mut conn, &[&email]).unwrap();
+            ids.push(email.id.to_string());
+        }
+
+        let mut matching = |query: &str| {
+            let search = parse_search_query(query).unwrap();
+            crate::db::emails::matching_email_ids(&mut conn, search.expr.as_ref()).unwrap()
+        };
+        assert_eq!(
+            matching("in-reply-to:<Parent@Example.com>"),
+            vec![ids[0].clone()]
+        );
+        assert!(matching("in-reply-to:parent@example.com").is_empty());
+    }
+
+    #[test]
+    fn test_tags_are_not_looked_up_in_the_index() {
+        let sql = build("invoice -tag:verified");
+        assert_eq!(
+            sql.match_expression.as_deref(),
+            Some("{subject sender recipients body_text} : \"invoice\"")
+        );
+        assert!(!sql.condition_uses_index);
+
+        let condition = sql.condition.unwrap();
+        assert_eq!(
+            condition.sql,
+            "NOT (EXISTS (SELECT 1 FROM email_tags \
+             INNER JOIN tags ON tags.id = email_tags.tag_id \
+             WHERE email_tags.email_id = emails.id AND tags.name = ?))"
+        );
+        assert_eq!(condition.binds, vec![SqlValue::from("verified")]);
+    }
+
+    #[test]
+    fn test_regex_filters_use_regexp() {
+        let condition = build(r"/\d{6}/ html~:token=").condition.unwrap();
+        assert_eq!(
+            condition.sql,
+            "((emails.subject REGEXP ? OR emails.body_text REGEXP ?) \
+             AND (emails.body_html REGEXP ?))"
+        );
+        assert_eq!(
+            condition.binds,
+            vec![
+                SqlValue::from(r"\d{6}"),
+                SqlValue::from(r"\d{6}"),
+                SqlValue::from("token="),
+            ]
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::models::{EmailTag, Tag};
+use crate::{db::DbConnection, schema, web::error::DieselError};
+
+/// Longest tag name accepted
+pub const MAX_TAG_LENGTH: usize = 64;
+/// Emails per statement, stays below SQLite's limit of bound parameters
+const CHUNK_SIZE: usize = 500;
+
+#[derive(QueryableByName, serde::Serialize, Debug, PartialEq)]
+pub struct TagStats {
+    #[diesel(sql_type = Text)]
+    pub name: String,
+    #[diesel(sql_type = BigInt)]
+    pub count: i64,
+}
+
+/// The tags of one email after a change
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct EmailTags {
+    pub email_id: String,
+    pub tags: Vec<String>,
+}
+
+/// Trimmed tag name, `None` if it is empty, too long or contains control characters
+pub fn parse_tag_name(name: &str) -> Option<&str> {
+    let name = name.trim();
+    if name.is_empty()
+        || name.chars().count() > MAX_TAG_LENGTH
+        || name.chars().any(char::is_control)
+    {
+        return None;
+    }
+    Some(name)
+}
+
+/// Every tag with the number of emails it is on, including unused ones
+pub fn list_tags(conn: &mut DbConnection) -> Result<Vec<TagStats>, DieselError> {
+    diesel::sql_query(
+        "SELECT tags.name, COUNT(email_tags.email_id) AS count FROM tags \
+         LEFT JOIN email_tags ON email_tags.tag_id = tags.id \
+         GROUP BY tags.id ORDER BY tags.name COLLATE NOCASE",
+    )
+    .load(conn)
+}
+
+/// Adds the tags to the emails, creating tags that do not exist yet. Ids of missing emails are
+/// skipped. Returns the ids of the emails that exist.
+pub fn add_tags(
+    conn: &mut DbConnection,
+    email_ids: &[String],
+    names: &[&str],
+) -> Result<Vec<String>, DieselError> {
+    conn.transaction(|conn| {
+        let now = Utc::now().naive_utc();
+        let new_tags: Vec<Tag> = names
+            .iter()
+            .map(|name| Tag {
+                id: Uuid::new_v4().to_string(),
+                name: name.to_string(),
+                created_at: now,
+            })
+            .collect();
+        // An existing tag keeps its id and spelling
+        diesel::insert_or_ignore_into(schema::tags::table)
+            .values(&new_tags)
+            .execute(conn)?;
+        let tag_ids = tag_ids(conn, names)?;
+
+        let mut existing = Vec::new();
+        for chunk in email_ids.chunks(CHUNK_SIZE) {
+            let chunk_ids: Vec<String> = schema::emails::table
+                .filter(schema::emails::id.eq_any(chunk))
+                .select(schema::emails::id)
+                .load(conn)?;
+            let links: Vec<EmailTag> = chunk_ids
+                .iter()
+                .flat_map(|email_id| {
+                    tag_ids.iter().map(move |tag_id| EmailTag {
+                        email_id: email_id.clone(),
+                        tag_id: tag_id.clone(),
+                        created_at: now,
+                    })
+                })
+                .collect();
+            for links in links.chunks(CHUNK_SIZE) {
+                diesel::insert_or_ignore_into(schema::email_tags::table)
+                    .values(links)
+                    .execute(conn)?;
+            }
+            existing.extend(chunk_ids);
+        }
+        Ok(existing)
+    })
+}
+
+/// Removes the tags from the emails. Returns the ids of the emails that had any of them.
+pub fn remove_tags(
+    conn: &mut DbConnection,
+    email_ids: &[String],
+    names: &[&str],
+) -> Result<Vec<String>, DieselError> {
+    conn.transaction(|conn| {
+        let tag_ids = tag_ids(conn, names)?;
+        let mut changed = Vec::new();
+        for chunk in email_ids.chunks(CHUNK_SIZE) {
+            let links = schema::email_tags::table
+                .filter(schema::email_tags::email_id.eq_any(chunk))
+                .filter(schema::email_tags::tag_id.eq_any(&tag_ids));
+            let mut chunk_ids: Vec<String> = links
+                .clone()
+                .select(schema::email_tags::email_id)
+                .distinct()
+                .load(conn)?;
+            diesel::delete(links).execute(conn)?;
+            changed.append(&mut chunk_ids);
+        }
+        Ok(changed)
+    })
+}
+
+/// Adds and removes tags in one transaction. Returns the ids of the existing emails when
+/// adding, otherwise those that lost a tag.
+pub fn update_tags(
+    conn: &mut DbConnection,
+    email_ids: &[String],
+    add: &[&str],
+    remove: &[&str],
+) -> Result<Vec<String>, DieselError> {
+    conn.transaction(|conn| {
+        let mut changed = Vec::new();
+        if !add.is_empty() {
+            changed = add_tags(conn, email_ids, add)?;
+        }
+        if !remove.is_empty() {
+            let removed = remove_tags(conn, email_ids, remove)?;
+            if add.is_empty() {
+                changed = removed;
+            }
+        }
+        Ok(changed)
+    })
+}
+
+/// Deletes the tag from all emails. Returns the ids of the emails that had it.
+pub fn delete_tag(conn: &mut DbConnection, name: &str) -> Result<Vec<String>, DieselError> {
+    conn.transaction(|conn| {
+        let tag_id: String = schema::tags::table
+            .filter(schema::tags::name.eq(name))
+            .select(schema::tags::id)
+            .first(conn)?;
+        let email_ids = schema::email_tags::table
+            .filter(schema::email_tags::tag_id.eq(&tag_id))
+            .select(schema::email_tags::email_id)
+            .load(conn)?;
+        diesel::delete(schema::tags::table.find(&tag_id)).execute(conn)?;
+        Ok(email_ids)
+    })
+}
+
+/// Tag names per email, sorted by name. Emails without tags are missing from the map.
+pub fn load_tags_for_emails(
+    conn: &mut DbConnection,
+    email_ids: &[String],
+) -> Result<HashMap<String, Vec<String>>, DieselError> {
+    let mut tags_map: HashMap<String, Vec<String>> = HashMap::new();
+    for chunk in email_ids.chunks(CHUNK_SIZE) {
+        let tags = schema::email_tags::table
+            .inner_join(schema::tags::table)
+            .filter(schema::email_tags::email_id.eq_any(chunk))
+            .order(schema::tags::name.asc())
+            .select((schema::email_tags::email_id, schema::tags::name))
+            .load::<(String, String)>(conn)?;
+        for (email_id, name) in tags {
+            tags_map.entry(email_id).or_default().push(name);
+        }
+    }
+    Ok(tags_map)
+}
+
+/// The current tags of each email, for broadcasting a change
+pub fn email_tags(
+    conn: &mut DbConnection,
+    email_ids: &[String],
+) -> Result<Vec<EmailTags>, DieselError> {
+    let mut tags_map = load_tags_for_emails(conn, email_ids)?;
+    Ok(email_ids
+        .iter()
+        .map(|email_id| EmailTags {
+            email_id: email_id.clone(),
+            tags: tags_map.remove(email_id).unwrap_or_default(),
+        })
+        .collect())
+}
+
+fn tag_ids(conn: &mut DbConnection, names: &[&str]) -> Result<Vec<String>, DieselError> {
+    // The column collates with NOCASE, so this matches regardless of case
+    schema::tags::table
+        .filter(schema::tags::name.eq_any(names))
+        .select(schema::tags::id)
+        .load(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tag_name() {
+        assert_eq!(
+            parse_tag_name("  needs-copy-review "),
+            Some("needs-copy-review")
+        );
+        assert_eq!(parse_tag_name("needs review"), Some("needs review"));
+        assert_eq!(parse_tag_name("   "), None);
+        assert_eq!(parse_tag_name("a\nb"), None);
+        assert_eq!(parse_tag_name(&"a".repeat(MAX_TAG_LENGTH + 1)), None);
+    }
+}
@@ -0,0 +1,349 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Nullable, Text, Timestamp};
+use mail_parser::parsers::fields::thread::thread_name;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use crate::db::emails::{list_filter, thread_emails, where_clause};
+use crate::db::search_query::SearchExpr;
+use crate::db::search_sql::{SqlFragment, build_search_sql};
+use crate::db::{DbConnection, EmailListRecord};
+use crate::models::Email;
+use crate::{schema, web::error::DieselError};
+
+/// Most Message-IDs kept from `References`, the oldest ones are dropped first
+const MAX_REFERENCES: usize = 100;
+/// Emails per statement, stays below SQLite's limit of bound parameters
+const CHUNK_SIZE: usize = 500;
+/// How far back a reply without references looks for a thread with the same subject
+const SUBJECT_THREAD_DAYS: i64 = 7;
+
+/// A Message-ID in angle brackets, as `References` and `In-Reply-To` list them
+static MESSAGE_ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<([^<>\s]+)>").unwrap());
+
+#[derive(QueryableByName, serde::Serialize, Debug)]
+pub struct ThreadSummary {
+    #[diesel(sql_type = Text)]
+    pub id: String,
+    /// Subject of the first email in the thread
+    #[diesel(sql_type = Nullable<Text>)]
+    pub subject: Option<String>,
+    #[diesel(sql_type = BigInt)]
+    pub email_count: i64,
+    #[diesel(sql_type = BigInt)]
+    pub unread_count: i64,
+    #[diesel(sql_type = Timestamp)]
+    pub first_received_at: NaiveDateTime,
+    #[diesel(sql_type = Timestamp)]
+    pub last_received_at: NaiveDateTime,
+}
+
+/// One page of threads, the most recently active first
+pub struct ThreadPage {
+    pub threads: Vec<ThreadSummary>,
+    pub total_count: u64,
+    pub total_pages: u64,
+}
+
+#[derive(serde::Serialize)]
+pub struct ThreadRecord {
+    pub id: String,
+    pub subject: Option<String>,
+    /// Oldest first
+    pub emails: Vec<EmailListRecord>,
+}
+
+#[derive(QueryableByName)]
+struct CountResult {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+/// Message-IDs the email replies to, from `References` and `In-Reply-To`, the direct parent last
+pub fn message_references(headers: &Option<serde_json::Value>) -> Vec<String> {
+    let Some(headers) = headers.as_ref().and_then(|headers| headers.as_object()) else {
+        return Vec::new();
+    };
+    let values = |name: &'static str| {
+        headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .filter_map(|(_, values)| values.as_array())
+            .flatten()
+            .filter_map(|value| value.as_str())
+    };
+
+    let mut references: Vec<String> = Vec::new();
+    for value in values("References").chain(values("In-Reply-To")) {
+        for caps in MESSAGE_ID.captures_iter(value) {
+            let message_id = caps[1].to_string();
+            // In-Reply-To usually repeats the last reference, keep its later position
+            references.retain(|reference| *reference != message_id);
+            references.push(message_id);
+        }
+    }
+    if references.len() > MAX_REFERENCES {
+        references.drain(..references.len() - MAX_REFERENCES);
+    }
+    references
+}
+
+/// Lowercased subject without `Re:`/`Fwd:` prefixes, `None` if nothing is left
+pub fn thread_subject(subject: Option<&str>) -> Option<String> {
+    let name = thread_name(subject?).trim();
+    (!name.is_empty()).then(|| name.to_lowercase())
+}
+
+/// Moves newly inserted emails into their threads, in the given order.
+///
+/// An email joins the thread of the closest email it references. Replies that arrived before
+/// their parent are merged into the parent's thread. Without any stored reference, a subject
+/// like `Re: Order shipped` joins the latest thread with the same subject from the last week that
+/// shares a sender or recipient. Returns the thread id of every email.
+pub fn assign_threads(
+    conn: &mut DbConnection,
+    emails: &[Email],
+    references: &[Vec<String>],
+) -> Result<HashMap<String, String>, DieselError> {
+    for (email, references) in emails.iter().zip(references) {
+        assign_thread(conn, email, references)?;
+    }
+
+    let email_ids: Vec<&str> = emails.iter().map(|email| email.id.as_str()).collect();
+    let mut thread_ids = HashMap::new();
+    for chunk in email_ids.chunks(CHUNK_SIZE) {
+        thread_ids.extend(
+            schema::emails::table
+                .filter(schema::emails::id.eq_any(chunk))
+                .select((schema::emails::id, schema::emails::thread_id))
+                .load::<(String, String)>(conn)?,
+        );
+    }
+    Ok(thread_ids)
+}
+
+fn assign_thread(
+    conn: &mut DbConnection,
+    email: &Email,
+    references: &[String],
+) -> Result<(), DieselError> {
+    let mut threads: Vec<String> = Vec::new();
+
+    if !references.is_empty() {
+        let parents: Vec<(Option<String>, String)> = schema::emails::table
+            .filter(schema::emails::message_id.eq_any(references))
+            .filter(schema::emails::id.ne(&email.id))
+            .select((schema::emails::message_id, schema::emails::thread_id))
+            .load(conn)?;
+        for reference in references.iter().rev() {
+            for (message_id, thread_id) in &parents {
+                if message_id.as_ref() == Some(reference) && !threads.contains(thread_id) {
+                    threads.push(thread_id.clone());
+                }
+            }
+        }
+    }
+
+    if let Some(message_id) = &email.message_id {
+        let replies: Vec<String> = schema::email_references::table
+            .inner_join(schema::emails::table)
+            .filter(schema::email_references::message_id.eq(message_id))
+            .filter(schema::emails::id.ne(&email.id))
+            .select(schema::emails::thread_id)
+            .distinct()
+            .load(conn)?;
+        for thread_id in replies {
+            if !threads.contains(&thread_id) {
+                threads.push(thread_id);
+            }
+        }
+    }
+
+    // Unrelated notifications often share a subject, so the thread also has to be recent and
+    // have a sender or recipient in common with the reply
+    if threads.is_empty()
+        && let Some(thread_subject) = &email.thread_subject
+        && is_reply(email.subject.as_deref(), thread_subject)
+    {
+        let mut participants: Vec<String> = schema::email_envelope_recipients::table
+            .inner_join(schema::envelope_recipients::table)
+            .filter(schema::email_envelope_recipients::email_id.eq(&email.id))
+            .select(schema::envelope_recipients::email)
+            .load(conn)?;
+        participants.push(email.envelope_from.clone());
+        let shares_recipient = diesel::dsl::exists(
+            schema::email_envelope_recipients::table
+                .inner_join(schema::envelope_recipients::table)
+                .filter(schema::email_envelope_recipients::email_id.eq(schema::emails::id))
+                .filter(schema::envelope_recipients::email.eq_any(&participants)),
+        );
+
+        let latest: Option<String> = schema::emails::table
+            .filter(schema::emails::thread_subject.eq(thread_subject))
+            .filter(schema::emails::id.ne(&email.id))
+            .filter(
+                schema::emails::created_at
+                    .ge(email.created_at - chrono::Duration::days(SUBJECT_THREAD_DAYS)),
+            )
+            .filter(
+                schema::emails::envelope_from
+                    .eq_any(&participants)
+                    .or(shares_recipient),
+            )
+            .order((schema::emails::created_at.desc(), schema::emails::id.desc()))
+            .select(schema::emails::thread_id)
+            .first(conn)
+            .optional()?;
+        threads.extend(latest);
+    }
+
+    let Some(target) = threads.first().cloned() else {
+        return Ok(());
+    };
+    // An earlier email of the same batch may have merged this one already
+    let current: String = schema::emails::table
+        .find(&email.id)
+        .select(schema::emails::thread_id)
+        .first(conn)?;
+    threads.push(current);
+    threads.retain(|thread_id| *thread_id != target);
+    if !threads.is_empty() {
+        diesel::update(schema::emails::table.filter(schema::emails::thread_id.eq_any(&threads)))
+            .set(schema::emails::thread_id.eq(&target))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// True if the subject had a prefix like `Re:` that `thread_subject` stripped
+fn is_reply(subject: Option<&str>, thread_subject: &str) -> bool {
+    subject.is_some_and(|subject| subject.trim().to_lowercase() != thread_subject)
+}
+
+/// Threads with at least one email matching `search`, the most recently active first
+pub fn list_threads(
+    conn: &mut DbConnection,
+    search: Option<&SearchExpr>,
+    page: u64,
+    per_page: u64,
+) -> Result<ThreadPage, DieselError> {
+    let search = build_search_sql(search);
+    let (from, conditions) = list_filter(None, &search);
+    let mut matching = SqlFragment::new("SELECT emails.thread_id");
+    matching.push_fragment(where_clause(from, conditions));
+
+    let mut count_query = SqlFragment::new("SELECT COUNT(DISTINCT thread_id) AS count FROM (");
+    count_query.push_fragment(matching.clone()).push_sql(")");
+    let total_count = count_query
+        .into_query()
+        .get_result::<CountResult>(conn)?
+        .count as u64;
+
+    let mut query = SqlFragment::new(
+        "SELECT emails.thread_id AS id, \
+         (SELECT first.subject FROM emails AS first WHERE first.thread_id = emails.thread_id \
+          ORDER BY first.created_at, first.id LIMIT 1) AS subject, \
+         COUNT(*) AS email_count, COALESCE(SUM(emails.read = 0), 0) AS unread_count, \
+         MIN(emails.created_at) AS first_received_at, MAX(emails.created_at) AS last_received_at \
+         FROM emails WHERE emails.thread_id IN (",
+    );
+    query
+        .push_fragment(matching)
+        .push_sql(
+            ") GROUP BY emails.thread_id \
+             ORDER BY last_received_at DESC, emails.thread_id DESC LIMIT ",
+        )
+        .push_bind(per_page as i64)
+        .push_sql(" OFFSET ")
+        .push_bind(((page - 1) * per_page) as i64);
+    let threads = query.into_query().load::<ThreadSummary>(conn)?;
+
+    Ok(ThreadPage {
+        threads,
+        total_count,
+        total_pages: total_count.div_ceil(per_page),
+    })
+}
+
+pub fn get_thread(conn: &mut DbConnection, thread_id: &str) -> Result<ThreadRecord, DieselError> {
+    let emails = thread_emails(conn, thread_id)?;
+    let Some(first) = emails.first() else {
+        return Err(DieselError::NotFound);
+    };
+    Ok(ThreadRecord {
+        id: thread_id.to_string(),
+        subject: first.subject.clone(),
+        emails,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{save_email::save_emails, testing};
+    use serde_json::json;
+
+    /// Saves an email without references and returns its id and thread id
+    fn save(conn: &mut DbConnection, subject: &str, from: &str, to: &str) -> (String, String) {
+        let mut email = testing::email(subject, &[to]);
+        email.from = from.to_string();
+        let record = save_emails(conn, &[&email]).unwrap().remove(0);
+        (record.id, record.thread_id)
+    }
+
+    #[test]
+    fn test_subject_fallback_needs_a_recent_shared_participant() {
+        let pool = testing::pool();
+        let mut conn = pool.get().unwrap();
+        let (_, ours) = save(&mut conn, "Build failed", "ci@a.com", "dev@a.com");
+        let (other_id, other) = save(&mut conn, "Build failed", "ci@b.com", "ops@b.com");
+
+        // The newest thread with that subject belongs to someone else
+        assert_eq!(
+            save(&mut conn, "Re: Build failed", "dev@a.com", "ci@a.com").1,
+            ours
+        );
+        let (_, unrelated) = save(&mut conn, "Re: Build failed", "x@c.com", "y@c.com");
+        assert_ne!(unrelated, ours);
+        assert_ne!(unrelated, other);
+
+        diesel::update(schema::emails::table.find(&other_id))
+            .set(
+                schema::emails::created_at
+                    .eq(chrono::Utc::now().naive_utc() - chrono::Duration::days(8)),
+            )
+            .execute(&mut conn)
+            .unwrap();
+        assert_ne!(
+            save(&mut conn, "Re: Build failed", "ops@b.com", "ci@b.com").1,
+            other
+        );
+    }
+
+    #[test]
+    fn test_message_references() {
+        let headers = Some(json!({
+            "References": [" <a@x.com> <b@x.com>\r\n\t<c@x.com>\r\n"],
+            "in-reply-to": [" <b@x.com>\r\n"],
+            "Subject": [" <d@x.com>\r\n"],
+        }));
+        assert_eq!(
+            message_references(&headers),
+            vec!["a@x.com", "c@x.com", "b@x.com"]
+        );
+        assert!(message_references(&None).is_empty());
+    }
+
+    #[test]
+    fn test_thread_subject() {
+        assert_eq!(
+            thread_subject(Some("Re: Fwd: Order Shipped")),
+            Some("order shipped".to_string())
+        );
+        assert_eq!(thread_subject(Some("Re: ")), None);
+        assert!(is_reply(Some("RE: Order shipped"), "order shipped"));
+        assert!(!is_reply(Some("Order Shipped"), "order shipped"));
+    }
+}
@@ -0,0 +1,265 @@
+use mail_parser::decoders::charsets::map::charset_decoder;
+use mail_parser::{HeaderValue, Message, MimeHeaders, PartType};
+
+use crate::mime;
+
+/// Longest line RFC 5322 allows, without the CRLF
+pub const MAX_LINE_LENGTH: usize = 998;
+/// Malformed header lines are quoted up to this many characters
+const QUOTE_LENGTH: usize = 80;
+
+/// Something mail-parser had to skip or guess while reading an email
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ParseWarning {
+    /// One of `parse_failed`, `charset`, `malformed_header`, `missing_date`,
+    /// `missing_message_id`, `bare_lf` or `long_line`
+    pub code: String,
+    pub message: String,
+}
+
+impl ParseWarning {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// Lines the SMTP client ended with LF instead of CRLF
+    pub fn bare_lf(lines: usize) -> Self {
+        Self::new(
+            "bare_lf",
+            format!("{} lines end with a bare LF instead of CRLF", lines),
+        )
+    }
+}
+
+/// Parse diagnostics of a raw email, `message` is `None` if mail-parser could not parse it
+pub fn diagnose(raw: &str, message: Option<&Message<'_>>) -> Vec<ParseWarning> {
+    let mut warnings = Vec::new();
+    match message {
+        Some(message) => {
+            warnings.extend(header_line_warnings(raw));
+            warnings.extend(header_warnings(message));
+            warnings.extend(charset_warnings(message));
+        }
+        None => warnings.push(ParseWarning::new(
+            "parse_failed",
+            "The message could not be parsed, subject, headers and bodies are empty",
+        )),
+    }
+    warnings.extend(line_warnings(raw));
+    warnings
+}
+
+/// Lines in the header section that are neither a field nor the continuation of one
+fn header_line_warnings(raw: &str) -> Vec<ParseWarning> {
+    let mut warnings = Vec::new();
+    for (index, line) in raw.lines().enumerate() {
+        if line.is_empty() {
+            break;
+        }
+        let is_continuation = line.starts_with([' ', '\t']);
+        let is_field = line.split_once(':').is_some_and(|(name, _)| {
+            let name = name.trim_end_matches([' ', '\t']);
+            !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_graphic())
+        });
+        if (is_continuation && index == 0) || (!is_continuation && !is_field) {
+            let quoted: String = line.chars().take(QUOTE_LENGTH).collect();
+            warnings.push(ParseWarning::new(
+                "malformed_header",
+                format!(
+                    "Header line {} is not a header field: {}",
+                    index + 1,
+                    quoted
+                ),
+            ));
+        }
+    }
+    warnings
+}
+
+fn header_warnings(message: &Message<'_>) -> Vec<ParseWarning> {
+    let mut warnings = Vec::new();
+    match message.header("Date") {
+        None => warnings.push(ParseWarning::new(
+            "missing_date",
+            "The message has no Date header",
+        )),
+        Some(HeaderValue::DateTime(_)) => {}
+        Some(_) => warnings.push(ParseWarning::new(
+            "malformed_header",
+            "The Date header could not be parsed",
+        )),
+    }
+    match message.header("Message-ID") {
+        None => warnings.push(ParseWarning::new(
+            "missing_message_id",
+            "The message has no Message-ID header",
+        )),
+        Some(HeaderValue::Text(_) | HeaderValue::TextList(_)) => {}
+        Some(_) => warnings.push(ParseWarning::new(
+            "malformed_header",
+            "The Message-ID header could not be parsed",
+        )),
+    }
+    warnings
+}
+
+/// Text parts in an unknown charset or with bytes invalid in theirs, and parts whose transfer
+/// encoding could not be decoded
+fn charset_warnings(message: &Message<'_>) -> Vec<ParseWarning> {
+    let mut warnings = Vec::new();
+    mime::for_each_part(message, &mut |part, path| {
+        if part.is_encoding_problem {
+            warnings.push(ParseWarning::new(
+                "charset",
+                format!(
+                    "Part {} could not be decoded from {}",
+                    path,
+                    part.content_transfer_encoding()
+                        .unwrap_or("its transfer encoding")
+                ),
+            ));
+            return;
+        }
+        let (PartType::Text(text) | PartType::Html(text)) = &part.body else {
+            return;
+        };
+        let charset = part
+            .content_type()
+            .and_then(|content_type| content_type.attribute("charset"))
+            .unwrap_or("us-ascii");
+        // mail-parser reads these as UTF-8 without a decoder of their own
+        let is_utf8 = matches!(
+            charset.to_lowercase().as_str(),
+            "utf-8" | "utf8" | "us-ascii" | "ascii"
+        );
+        if !is_utf8 && charset_decoder(charset.as_bytes()).is_none() {
+            warnings.push(ParseWarning::new(
+                "charset",
+                format!("Part {} has the unknown charset {}", path, charset),
+            ));
+        } else if text.contains('\u{FFFD}') {
+            warnings.push(ParseWarning::new(
+                "charset",
+                format!("Part {} has bytes that are not valid {}", path, charset),
+            ));
+        }
+    });
+    warnings
+}
+
+fn line_warnings(raw: &str) -> Vec<ParseWarning> {
+    let mut warnings = Vec::new();
+    let mut bare_lf = 0;
+    let mut long_lines = 0;
+    let mut longest = 0;
+    for line in raw.split_inclusive('\n') {
+        let content = match line.strip_suffix("\r\n") {
+            Some(content) => content,
+            None => match line.strip_suffix('\n') {
+                Some(content) => {
+                    bare_lf += 1;
+                    content
+                }
+                None => line,
+            },
+        };
+        if content.len() > MAX_LINE_LENGTH {
+            long_lines += 1;
+            longest = longest.max(content.len());
+        }
+    }
+    if bare_lf > 0 {
+        warnings.push(ParseWarning::bare_lf(bare_lf));
+    }
+    if long_lines > 0 {
+        warnings.push(ParseWarning::new(
+            "long_line",
+            format!(
+                "{} lines are longer than {} characters, the longest has {}",
+                long_lines, MAX_LINE_LENGTH, longest
+            ),
+        ));
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mail_parser::MessageParser;
+
+    fn codes(raw: &str) -> Vec<String> {
+        let message = MessageParser::default().parse(raw.as_bytes());
+        diagnose(raw, message.as_ref())
+            .into_iter()
+            .map(|warning| warning.code)
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_message() {
+        let raw = "Date: Tue, 1 Jul 2025 10:00:00 +0000\r\n\
+Message-ID: <1@example.com>\r\n\
+Subject: Hi\r\n\
+\r\n\
+Body\r\n";
+        assert!(codes(raw).is_empty());
+    }
+
+    #[test]
+    fn test_header_warnings() {
+        let raw = "Date: yesterday\r\nSubject: Hi\r\nnot a header\r\n\r\nBody\r\n";
+        assert_eq!(
+            codes(raw),
+            vec!["malformed_header", "malformed_header", "missing_message_id"]
+        );
+    }
+
+    #[test]
+    fn test_charset_warnings() {
+        let raw = "Message-ID: <1@x>\r\n\
+Date: Tue, 1 Jul 2025 10:00:00 +0000\r\n\
+Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain; charset=x-unknown\r\n\
+\r\n\
+Hi\r\n\
+--b\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+/w==\r\n\
+--b--\r\n";
+        let message = MessageParser::default().parse(raw.as_bytes()).unwrap();
+        let messages: Vec<String> = diagnose(raw, Some(&message))
+            .into_iter()
+            .map(|warning| warning.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Part 1.1 has the unknown charset x-unknown",
+                "Part 1.2 has bytes that are not valid utf-8",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_line_warnings() {
+        let raw = format!(
+            "Message-ID: <1@x>\nDate: Tue, 1 Jul 2025 10:00:00 +0000\n\n{}\n",
+            "a".repeat(1000)
+        );
+        let warnings = diagnose(&raw, None);
+        assert_eq!(warnings[0].code, "parse_failed");
+        assert_eq!(warnings[1], ParseWarning::bare_lf(4));
+        assert_eq!(
+            warnings[2].message,
+            "1 lines are longer than 998 characters, the longest has 1000"
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use mail_parser::parsers::MessageStream;
+use mail_parser::{HeaderValue, Message};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// One mailbox of an address header, `name` is the decoded display name
+#[derive(serde::Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EmailAddress {
+    pub name: Option<String>,
+    pub address: Option<String>,
+}
+
+/// The address headers of an email, parsed into mailboxes. Groups are flattened.
+#[derive(serde::Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AddressHeaders {
+    pub from: Vec<EmailAddress>,
+    pub to: Vec<EmailAddress>,
+    pub cc: Vec<EmailAddress>,
+    pub reply_to: Vec<EmailAddress>,
+}
+
+/// One header field of the message, in the order it was received
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawHeader {
+    /// Name as mail-parser knows it, e.g. `Message-ID` for `message-id`
+    pub name: String,
+    /// Value after the colon, still folded and encoded
+    pub value: String,
+    /// Unfolded value with encoded-words decoded
+    pub decoded: String,
+    /// The whole field as received: name with its casing, colon, value and line break
+    pub raw: Vec<u8>,
+}
+
+/// Header names that `AddressHeaders` is built from
+pub const ADDRESS_HEADERS: [&str; 4] = ["From", "To", "Cc", "Reply-To"];
+
+impl AddressHeaders {
+    /// Parses the raw values of every address header, repeated headers are combined
+    pub fn from_headers(headers: &HashMap<String, Vec<String>>) -> Self {
+        let parse = |name: &str| -> Vec<EmailAddress> {
+            headers
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .flat_map(|(_, values)| values)
+                .flat_map(|value| parse_addresses(value))
+                .collect()
+        };
+        Self {
+            from: parse("From"),
+            to: parse("To"),
+            cc: parse("Cc"),
+            reply_to: parse("Reply-To"),
+        }
+    }
+}
+
+/// Header fields of `message` in the order they were received, `raw` is the whole message.
+///
+/// Offsets of embedded messages count from the start of the outer message as well.
+pub fn extract_raw_headers(message: &Message<'_>, raw: &[u8]) -> Vec<RawHeader> {
+    message
+        .headers()
+        .iter()
+        .filter_map(|header| {
+            let value = raw.get(header.offset_start as usize..header.offset_end as usize)?;
+            let field = raw.get(header.offset_field as usize..header.offset_end as usize)?;
+            let value = String::from_utf8_lossy(value).into_owned();
+            Some(RawHeader {
+                name: header.name.as_str().to_string(),
+                decoded: decode_value(&value),
+                value,
+                raw: field.to_vec(),
+            })
+        })
+        .collect()
+}
+
+/// Unfolds a raw header value and decodes RFC 2047 encoded-words like `=?UTF-8?B?...?=`
+pub fn decode_value(raw_value: &str) -> String {
+    let value = with_line_end(raw_value);
+    match MessageStream::new(value.as_bytes()).parse_unstructured() {
+        HeaderValue::Text(text) => text.into_owned(),
+        _ => String::new(),
+    }
+}
+
+/// Mailboxes of a raw address header value, e.g. `"Doe, Jane" <jane@example.com>, bob@example.com`
+pub fn parse_addresses(raw_value: &str) -> Vec<EmailAddress> {
+    let value = with_line_end(raw_value);
+    let HeaderValue::Address(address) = MessageStream::new(value.as_bytes()).parse_address() else {
+        return Vec::new();
+    };
+    address
+        .iter()
+        .map(|addr| EmailAddress {
+            name: addr.name().map(str::to_string),
+            address: addr.address().map(str::to_string),
+        })
+        .collect()
+}
+
+/// The header parsers stop at the line break ending the field
+fn with_line_end(raw_value: &str) -> Cow<'_, str> {
+    if raw_value.ends_with('\n') {
+        Cow::Borrowed(raw_value)
+    } else {
+        Cow::Owned(format!("{}\r\n", raw_value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_value() {
+        assert_eq!(
+            decode_value(" =?UTF-8?B?R3LDvMOfZQ==?= aus\r\n Berlin\r\n"),
+            "Grüße aus Berlin"
+        );
+        assert_eq!(decode_value(" plain"), "plain");
+        assert_eq!(decode_value(""), "");
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        assert_eq!(
+            parse_addresses(
+                " \"Doe, Jane\" <jane@example.com>, =?UTF-8?Q?J=C3=B6rg?= <j@x.com>,\r\n bob@example.com\r\n"
+            ),
+            vec![
+                EmailAddress {
+                    name: Some("Doe, Jane".to_string()),
+                    address: Some("jane@example.com".to_string()),
+                },
+                EmailAddress {
+                    name: Some("Jörg".to_string()),
+                    address: Some("j@x.com".to_string()),
+                },
+                EmailAddress {
+                    name: None,
+                    address: Some("bob@example.com".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_address_headers() {
+        let headers = HashMap::from([
+            ("From".to_string(), vec![" a@x.com\r\n".to_string()]),
+            (
+                "To".to_string(),
+                vec![" b@x.com\r\n".to_string(), " c@x.com\r\n".to_string()],
+            ),
+            ("reply-to".to_string(), vec![" d@x.com\r\n".to_string()]),
+        ]);
+        let addresses = AddressHeaders::from_headers(&headers);
+        assert_eq!(addresses.from.len(), 1);
+        assert_eq!(addresses.to.len(), 2);
+        assert!(addresses.cc.is_empty());
+        assert_eq!(addresses.reply_to[0].address.as_deref(), Some("d@x.com"));
+    }
+}
@@ -1,4 +1,5 @@
 use regex::{Captures, Regex};
+use std::collections::HashMap;
 
 pub fn normalize_html_document(html: &str) -> String {
     let lower = html.to_lowercase();
@@ -19,6 +20,54 @@ pub fn normalize_html_document(html: &str) -> String {
     }
 }
 
+/// Prepares an HTML body for display. `cid:` image sources are replaced by the URLs in `cid_map`,
+/// which is keyed by Content-ID, and links open in a new tab.
+pub fn render_body(body_html: &str, cid_map: &HashMap<String, String>) -> Option<String> {
+    if body_html.is_empty() {
+        return None;
+    }
+
+    let mut processed_html = if cid_map.is_empty() {
+        body_html.to_string()
+    } else {
+        let re = Regex::new(r#"(?i)src\s*=\s*(["']?)cid:([^"'\s>]+)"#).unwrap();
+        re.replace_all(body_html, |caps: &Captures| {
+            let quote = caps.get(1).map(|m| m.as_str()).unwrap_or("");
+            let cid = caps.get(2).map(|m| m.as_str()).unwrap_or("");
+            let cid_clean = cid.trim_start_matches('<').trim_end_matches('>');
+
+            if let Some(attachment_url) = cid_map.get(cid_clean) {
+                format!("src={}{}", quote, attachment_url)
+            } else if let Some(attachment_url) = cid_map.get(cid) {
+                format!("src={}{}", quote, attachment_url)
+            } else {
+                caps.get(0)
+                    .map(|m| m.as_str().to_string())
+                    .unwrap_or_default()
+            }
+        })
+        .to_string()
+    };
+
+    processed_html = normalize_html_document(&processed_html);
+    processed_html = add_base_tag(&processed_html);
+
+    Some(processed_html)
+}
+
+fn add_base_tag(html: &str) -> String {
+    let base_tag = "<base target=\"_blank\">";
+    let base_regex = Regex::new(r"(?i)<base[^>]*>").unwrap();
+
+    if base_regex.is_match(html) {
+        return base_regex
+            .replace(html, |_caps: &Captures| base_tag)
+            .to_string();
+    }
+
+    insert_into_head(html, base_tag)
+}
+
 pub fn insert_into_head(html: &str, content: &str) -> String {
     let head_regex = Regex::new(r"(?i)<head[^>]*>").expect("valid head regex");
     let html_regex = Regex::new(r"(?i)<html[^>]*>").expect("valid html regex");
@@ -114,6 +163,19 @@ mod tests {
         assert!(result.contains("<title>Test</title>"));
     }
 
+    #[test]
+    fn test_render_body_replaces_cid_sources() {
+        let cid_map = HashMap::from([("logo".to_string(), "/api/attachments/1".to_string())]);
+        let rendered = render_body("<img src=\"cid:logo\"><img src=cid:other>", &cid_map);
+        assert_eq!(
+            rendered.as_deref(),
+            Some(
+                "<html><head><base target=\"_blank\"></head><body><img src=\"/api/attachments/1\"><img src=cid:other></body></html>"
+            )
+        );
+        assert_eq!(render_body("", &cid_map), None);
+    }
+
     #[test]
     fn test_normalize_html_document_strips_wrappers() {
         let html = "<p><div>tracking</div><!doctype html><html><head><meta name=\"x\"></head><body>Hi</body></html></p>";
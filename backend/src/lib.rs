@@ -2,9 +2,15 @@ pub mod compression;
 pub mod config;
 pub mod csp;
 pub mod db;
+pub mod diagnostics;
+pub mod header;
 pub mod html;
+pub mod lint;
 pub mod logging;
+pub mod maintenance;
+pub mod mime;
 pub mod models;
 pub mod schema;
 pub mod smtp;
+pub mod storage;
 pub mod web;
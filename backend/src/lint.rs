@@ -0,0 +1,467 @@
+use chrono::DateTime;
+use mail_parser::{Message, MessagePart, MimeHeaders, PartType};
+use regex::Regex;
+use std::sync::LazyLock;
+
+use crate::diagnostics::MAX_LINE_LENGTH;
+use crate::header::decode_value;
+use crate::mime;
+
+/// Headers RFC 5322 Section 3.6 allows at most once
+const SINGLETON_HEADERS: [&str; 11] = [
+    "Date",
+    "From",
+    "Sender",
+    "Reply-To",
+    "To",
+    "Cc",
+    "Bcc",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+    "Subject",
+];
+const TRANSFER_ENCODINGS: [&str; 5] = ["7bit", "8bit", "binary", "quoted-printable", "base64"];
+/// Longest boundary RFC 2046 allows
+const MAX_BOUNDARY_LENGTH: usize = 70;
+
+/// RFC 5322 Section 3.3 without the obsolete forms of Section 4.3
+static CURRENT_DATE_SYNTAX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)^\s*(?:[a-z]{3}\s*,\s*)?\d{1,2}\s+[a-z]{3}\s+\d{4}\s+\d{2}:\d{2}(?::\d{2})?\s+[+-]\d{4}(?:\s*\(.*\))?\s*$",
+    )
+    .unwrap()
+});
+/// A comment, which MIME-Version may contain around `1.0`
+static COMMENT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\([^()]*\)").unwrap());
+/// The characters RFC 2046 allows in a boundary, which must not end with a space
+static BOUNDARY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[0-9A-Za-z'()+_,\-./:=? ]*[0-9A-Za-z'()+_,\-./:=?]$").unwrap());
+
+/// A way in which a received email breaks RFC 5322 or MIME
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct LintFinding {
+    /// `error` if strict receivers may reject the email, `warning` if it is discouraged
+    pub severity: String,
+    /// One of `missing_header`, `duplicate_header`, `line_too_long`, `non_ascii_header`,
+    /// `broken_boundary`, `missing_mime_version`, `bad_transfer_encoding` or `invalid_date`
+    pub code: String,
+    pub message: String,
+}
+
+impl LintFinding {
+    fn error(code: &str, message: impl Into<String>) -> Self {
+        Self {
+            severity: "error".to_string(),
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+
+    fn warning(code: &str, message: impl Into<String>) -> Self {
+        Self {
+            severity: "warning".to_string(),
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Checks a received email against RFC 5322 and the MIME RFCs. `smtputf8` is true if the client
+/// announced UTF-8 headers with the SMTPUTF8 parameter of MAIL FROM.
+pub fn lint(raw: &str, message: Option<&Message<'_>>, smtputf8: bool) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+    if let Some(message) = message {
+        findings.extend(required_headers(message));
+        findings.extend(duplicate_headers(message));
+        findings.extend(date_headers(message, raw));
+        findings.extend(mime_version(message, raw));
+        if !smtputf8 {
+            findings.extend(non_ascii_headers(message, raw));
+        }
+        findings.extend(multipart_boundaries(message, raw));
+        findings.extend(transfer_encodings(message, raw));
+    }
+    findings.extend(line_lengths(raw));
+    findings
+}
+
+fn required_headers(message: &Message<'_>) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+    for name in ["From", "Date"] {
+        if message.header(name).is_none() {
+            findings.push(LintFinding::error(
+                "missing_header",
+                format!("The required {} header is missing", name),
+            ));
+        }
+    }
+    if message.header("Message-ID").is_none() {
+        findings.push(LintFinding::warning(
+            "missing_header",
+            "The Message-ID header is missing, RFC 5322 says every email should have one",
+        ));
+    }
+    findings
+}
+
+fn duplicate_headers(message: &Message<'_>) -> Vec<LintFinding> {
+    SINGLETON_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let count = message
+                .headers()
+                .iter()
+                .filter(|header| header.name.as_str().eq_ignore_ascii_case(name))
+                .count();
+            (count > 1).then(|| {
+                LintFinding::error(
+                    "duplicate_header",
+                    format!(
+                        "The {} header appears {} times, only one is allowed",
+                        name, count
+                    ),
+                )
+            })
+        })
+        .collect()
+}
+
+fn date_headers(message: &Message<'_>, raw: &str) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+    for header in message.headers() {
+        let name = header.name.as_str();
+        if !name.eq_ignore_ascii_case("Date") && !name.eq_ignore_ascii_case("Resent-Date") {
+            continue;
+        }
+        let value = raw
+            .get(header.offset_start as usize..header.offset_end as usize)
+            .map(decode_value)
+            .unwrap_or_default();
+        if DateTime::parse_from_rfc2822(value.trim()).is_err() {
+            findings.push(LintFinding::error(
+                "invalid_date",
+                format!(
+                    "The {} header is not a valid RFC 5322 date: {}",
+                    name, value
+                ),
+            ));
+        } else if !CURRENT_DATE_SYNTAX.is_match(&value) {
+            findings.push(LintFinding::warning(
+                "invalid_date",
+                format!(
+                    "The {} header uses an obsolete format, e.g. a named time zone or a two digit year: {}",
+                    name, value
+                ),
+            ));
+        }
+    }
+    findings
+}
+
+fn mime_version(message: &Message<'_>, raw: &str) -> Vec<LintFinding> {
+    let Some(header) = message
+        .headers()
+        .iter()
+        .find(|header| header.name.as_str().eq_ignore_ascii_case("MIME-Version"))
+    else {
+        let uses_mime = message.header("Content-Type").is_some()
+            || message.header("Content-Transfer-Encoding").is_some()
+            || !raw
+                .get(message.root_part().offset_body as usize..)
+                .unwrap_or_default()
+                .is_ascii();
+        if uses_mime {
+            return vec![LintFinding::error(
+                "missing_mime_version",
+                "The email uses MIME but has no MIME-Version header",
+            )];
+        }
+        return Vec::new();
+    };
+
+    let value = raw
+        .get(header.offset_start as usize..header.offset_end as usize)
+        .unwrap_or_default();
+    let version: String = COMMENT
+        .replace_all(value, "")
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    if version == "1.0" {
+        return Vec::new();
+    }
+    vec![LintFinding::error(
+        "missing_mime_version",
+        format!("The MIME-Version header must be 1.0, not {}", value.trim()),
+    )]
+}
+
+/// Header fields with 8-bit bytes, which need SMTPUTF8 or RFC 2047 encoded-words
+fn non_ascii_headers(message: &Message<'_>, raw: &str) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+    mime::for_each_part(message, &mut |part, path| {
+        for header in part.headers.iter() {
+            let field = raw
+                .get(header.offset_field as usize..header.offset_end as usize)
+                .unwrap_or_default();
+            if !field.is_ascii() {
+                findings.push(LintFinding::error(
+                    "non_ascii_header",
+                    format!(
+                        "The {} header{} contains non-ASCII characters but the email was not sent with SMTPUTF8, encode them with RFC 2047",
+                        header.name.as_str(),
+                        part_suffix(path)
+                    ),
+                ));
+            }
+        }
+    });
+    findings
+}
+
+fn multipart_boundaries(message: &Message<'_>, raw: &str) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+    mime::for_each_part(message, &mut |part, path| {
+        if !matches!(part.body, PartType::Multipart(_)) {
+            return;
+        }
+        let Some(boundary) = part
+            .content_type()
+            .and_then(|content_type| content_type.attribute("boundary"))
+        else {
+            findings.push(LintFinding::error(
+                "broken_boundary",
+                format!("Multipart part {} has no boundary parameter", path),
+            ));
+            return;
+        };
+        if boundary.len() > MAX_BOUNDARY_LENGTH || !BOUNDARY.is_match(boundary) {
+            findings.push(LintFinding::error(
+                "broken_boundary",
+                format!(
+                    "The boundary of part {} is not valid, it must have 1 to 70 letters, digits or '()+_,-./:=? and must not end with a space",
+                    path
+                ),
+            ));
+        }
+
+        let body = raw
+            .get(part.offset_body as usize..part.offset_end as usize)
+            .unwrap_or_default();
+        let delimiter = format!("--{}", boundary);
+        let close_delimiter = format!("--{}--", boundary);
+        let mut delimiters = 0;
+        let mut closed = false;
+        for line in body.lines() {
+            let line = line.trim_end_matches([' ', '\t']);
+            if line == close_delimiter {
+                closed = true;
+                break;
+            }
+            if line == delimiter {
+                delimiters += 1;
+            }
+        }
+        if delimiters == 0 {
+            findings.push(LintFinding::error(
+                "broken_boundary",
+                format!("The boundary of part {} never starts a body part", path),
+            ));
+        } else if !closed {
+            findings.push(LintFinding::error(
+                "broken_boundary",
+                format!("Part {} has no closing boundary {}", path, close_delimiter),
+            ));
+        }
+    });
+    findings
+}
+
+fn transfer_encodings(message: &Message<'_>, raw: &str) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+    mime::for_each_part(message, &mut |part, path| {
+        findings.extend(transfer_encoding(part, path, raw));
+    });
+    findings
+}
+
+fn transfer_encoding(part: &MessagePart<'_>, path: &str, raw: &str) -> Option<LintFinding> {
+    let declared = part.content_transfer_encoding();
+    let encoding = declared.unwrap_or("7bit").trim().to_lowercase();
+    if !TRANSFER_ENCODINGS.contains(&encoding.as_str()) {
+        return Some(LintFinding::error(
+            "bad_transfer_encoding",
+            format!(
+                "Part {} has the unknown Content-Transfer-Encoding {}",
+                path, encoding
+            ),
+        ));
+    }
+
+    let is_container = matches!(part.body, PartType::Multipart(_) | PartType::Message(_));
+    if is_container && !matches!(encoding.as_str(), "7bit" | "8bit" | "binary") {
+        return Some(LintFinding::error(
+            "bad_transfer_encoding",
+            format!(
+                "Part {} is a multipart or message part and may only be 7bit, 8bit or binary, not {}",
+                path, encoding
+            ),
+        ));
+    }
+    if part.is_encoding_problem {
+        return Some(LintFinding::error(
+            "bad_transfer_encoding",
+            format!("The body of part {} is not valid {}", path, encoding),
+        ));
+    }
+    if is_container || encoding != "7bit" {
+        return None;
+    }
+
+    let body = raw
+        .get(part.offset_body as usize..part.offset_end as usize)
+        .unwrap_or_default();
+    if body.is_ascii() {
+        return None;
+    }
+    Some(match declared {
+        Some(_) => LintFinding::error(
+            "bad_transfer_encoding",
+            format!(
+                "Part {} is declared 7bit but contains 8-bit characters",
+                path
+            ),
+        ),
+        None => LintFinding::warning(
+            "bad_transfer_encoding",
+            format!(
+                "Part {} contains 8-bit characters but has no Content-Transfer-Encoding, which means 7bit",
+                path
+            ),
+        ),
+    })
+}
+
+fn line_lengths(raw: &str) -> Vec<LintFinding> {
+    let mut long_lines = raw
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.len() > MAX_LINE_LENGTH);
+    let Some((first, _)) = long_lines.next() else {
+        return Vec::new();
+    };
+    let count = long_lines.count() + 1;
+    vec![LintFinding::error(
+        "line_too_long",
+        format!(
+            "{} lines are longer than {} characters, the first is line {}",
+            count,
+            MAX_LINE_LENGTH,
+            first + 1
+        ),
+    )]
+}
+
+/// Names the part unless it is the email itself
+fn part_suffix(path: &str) -> String {
+    if path == "1" {
+        String::new()
+    } else {
+        format!(" of part {}", path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mail_parser::MessageParser;
+
+    fn codes(raw: &str, smtputf8: bool) -> Vec<(String, String)> {
+        let message = MessageParser::default().parse(raw.as_bytes());
+        lint(raw, message.as_ref(), smtputf8)
+            .into_iter()
+            .map(|finding| (finding.severity, finding.code))
+            .collect()
+    }
+
+    fn finding(severity: &str, code: &str) -> (String, String) {
+        (severity.to_string(), code.to_string())
+    }
+
+    #[test]
+    fn test_valid_email() {
+        let raw = "From: a@example.com\r\n\
+Date: Tue, 1 Jul 2025 10:00:00 +0000 (UTC)\r\n\
+Message-ID: <1@example.com>\r\n\
+MIME-Version: 1.0 (generated)\r\n\
+Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Transfer-Encoding: 8bit\r\n\
+\r\n\
+Grüße\r\n\
+--b--\r\n";
+        assert!(codes(raw, false).is_empty());
+    }
+
+    #[test]
+    fn test_headers() {
+        let raw = "Subject: Grüße\r\n\
+Subject: again\r\n\
+Date: 1 Jul 25 10:00 GMT\r\n\
+Resent-Date: yesterday\r\n\
+\r\n\
+Body\r\n";
+        assert_eq!(
+            codes(raw, false),
+            vec![
+                finding("error", "missing_header"),
+                finding("warning", "missing_header"),
+                finding("error", "duplicate_header"),
+                finding("warning", "invalid_date"),
+                finding("error", "invalid_date"),
+                finding("error", "non_ascii_header"),
+            ]
+        );
+        assert!(
+            !codes(raw, true)
+                .iter()
+                .any(|(_, code)| code == "non_ascii_header")
+        );
+    }
+
+    #[test]
+    fn test_mime() {
+        let raw = format!(
+            "From: a@example.com\r\n\
+Date: Tue, 1 Jul 2025 10:00:00 +0000\r\n\
+Message-ID: <1@example.com>\r\n\
+Content-Type: multipart/mixed; boundary=\"b \"\r\n\
+\r\n\
+--b \r\n\
+Content-Type: text/plain\r\n\
+Content-Transfer-Encoding: 7bit\r\n\
+\r\n\
+Grüße {}\r\n\
+--b \r\n\
+Content-Type: text/plain\r\n\
+Content-Transfer-Encoding: uuencode\r\n\
+\r\n\
+x\r\n",
+            "x".repeat(1000)
+        );
+        assert_eq!(
+            codes(&raw, false),
+            vec![
+                finding("error", "missing_mime_version"),
+                finding("error", "broken_boundary"),
+                finding("error", "broken_boundary"),
+                finding("error", "bad_transfer_encoding"),
+                finding("error", "bad_transfer_encoding"),
+                finding("error", "line_too_long"),
+            ]
+        );
+    }
+}
@@ -2,44 +2,18 @@ use clap::Parser;
 use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
-use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
-use mailfang::{config, db, logging, smtp, web};
+use diesel_migrations::MigrationHarness;
+use mailfang::{config, db, logging, maintenance, smtp, storage, web};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fs, io};
 use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 
-pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
-
-#[derive(Debug)]
-struct ConnectionOptions;
-
-impl diesel::r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error>
-    for ConnectionOptions
-{
-    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
-        let map_err = |e| diesel::r2d2::Error::QueryError(e);
-
-        let session_queries = [
-            "PRAGMA foreign_keys = ON;",
-            "PRAGMA busy_timeout = 5000;", // Increased to 5s for prod safety
-            "PRAGMA cache_size = -64000;", // ~64MB cache (negative number is in KiB)
-            "PRAGMA mmap_size = 268435456;", // 256MB Memory Mapping
-            "PRAGMA temp_store = MEMORY;", // Fast temp tables
-        ];
-
-        for query in session_queries {
-            conn.batch_execute(query).map_err(map_err)?;
-        }
-
-        Ok(())
-    }
-}
-
 fn sqlite_global_setup(url: &str) -> Result<(), io::Error> {
-    let mut conn =
-        SqliteConnection::establish(url).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let mut conn = SqliteConnection::establish(url).map_err(io::Error::other)?;
 
     let global_queries = [
         "PRAGMA journal_mode = WAL;",
@@ -49,8 +23,14 @@ fn sqlite_global_setup(url: &str) -> Result<(), io::Error> {
     ];
 
     for query in global_queries {
-        conn.batch_execute(query)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        conn.batch_execute(query).map_err(io::Error::other)?;
+    }
+
+    if db::enable_incremental_vacuum(&mut conn).map_err(io::Error::other)? {
+        info!(
+            component = "main",
+            "Database converted to incremental auto vacuum"
+        );
     }
 
     Ok(())
@@ -67,31 +47,64 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let smtp_addr = config.smtp_socket_addr()?;
     let web_addr = config.web_socket_addr()?;
 
-    let db_for_smtp = db.clone();
-    let broadcast_for_smtp = broadcast_tx.clone();
-    let smtp_on_receive = move |message: &smtp::Email| {
-        handle_new_email(
-            db_for_smtp.clone(),
-            broadcast_for_smtp.clone(),
-            message.clone(),
-        );
+    let storage_queue =
+        storage::StorageQueue::start(db.clone(), broadcast_tx.clone(), config.storage_queue_size);
+
+    let maintenance = maintenance::Maintenance::new(
+        db.clone(),
+        broadcast_tx.clone(),
+        config.retention_policy(),
+        config.vacuum_threshold.saturating_mul(1024 * 1024),
+        Duration::from_secs(config.vacuum_interval),
+    );
+    maintenance.spawn(Duration::from_secs(config.maintenance_interval.max(1)));
+
+    let queue_for_smtp = storage_queue.clone();
+    let smtp_on_receive = move |message: smtp::Email| {
+        let queue = queue_for_smtp.clone();
+        async move { queue.store(message).await.map_err(|e| e.to_string()) }
     };
 
     let smtp_server = smtp::SmtpServer::new(smtp_addr)
         .max_connections(config.smtp_max_connections)
         .auth(config.smtp_username.clone(), config.smtp_password.clone())
+        .command_timeout(Duration::from_secs(config.smtp_command_timeout))
+        .data_timeout(Duration::from_secs(config.smtp_data_timeout))
+        .session_timeout(Duration::from_secs(config.smtp_session_timeout))
+        .shutdown_timeout(Duration::from_secs(config.shutdown_timeout))
         .on_receive(smtp_on_receive);
 
-    tokio::select! {
-        smtp_result = smtp_server.run() => {
+    let shutdown = CancellationToken::new();
+    let smtp_run = smtp_server.run(shutdown.clone());
+    tokio::pin!(smtp_run);
+
+    let stop_requested = tokio::select! {
+        smtp_result = &mut smtp_run => {
             smtp_result?;
+            false
         }
-        web_result = web::run(web_addr, db, broadcast_tx) => {
+        web_result = web::run(web_addr, db, broadcast_tx, storage_queue.clone(), maintenance) => {
             web_result?;
+            false
         }
-        _ = shutdown_signal() => {
-            info!("Stopping mailfang...");
+        _ = shutdown_signal() => true,
+    };
+
+    if stop_requested {
+        info!(component = "main", "Stopping mailfang...");
+        shutdown.cancel();
+        smtp_run.await?;
+
+        // Sessions that gave up waiting may have left saves behind in the queue
+        let pending = storage_queue.stats().depth;
+        if pending > 0 {
+            info!(
+                component = "main",
+                "Waiting for {} pending email saves", pending
+            );
         }
+        storage_queue.flush().await;
+        info!(component = "main", "Mailfang stopped");
     }
 
     Ok(())
@@ -159,18 +172,16 @@ async fn setup_database(config: &config::Config) -> Result<db::DbPool, io::Error
     let manager = ConnectionManager::<SqliteConnection>::new(url);
     let pool = r2d2::Pool::builder()
         .max_size(5)
-        .connection_customizer(Box::new(ConnectionOptions))
+        .connection_customizer(Box::new(db::ConnectionOptions))
         .build(manager)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        .map_err(io::Error::other)?;
 
     info!(component = "main", "Database connected");
 
     // Run database migrations
-    let mut conn = pool
-        .get()
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-    conn.run_pending_migrations(MIGRATIONS)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let mut conn = pool.get().map_err(io::Error::other)?;
+    conn.run_pending_migrations(db::MIGRATIONS)
+        .map_err(io::Error::other)?;
     info!(component = "main", "Database migrations completed");
 
     Ok(Arc::new(pool))
@@ -200,10 +211,10 @@ fn create_sqlite_db_file(database_url: &str) -> io::Result<()> {
 
     let path = Path::new(path_str);
 
-    if let Some(parent) = path.parent() {
-        if !parent.as_os_str().is_empty() {
-            fs::create_dir_all(parent)?;
-        }
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)?;
     }
 
     // create the file if missing
@@ -216,62 +227,3 @@ fn create_sqlite_db_file(database_url: &str) -> io::Result<()> {
 
     Ok(())
 }
-
-fn handle_new_email(
-    db: db::DbPool,
-    broadcast: broadcast::Sender<web::ws::WebSocketMessage>,
-    message: smtp::Email,
-) {
-    tokio::spawn(async move {
-        let db_clone = db.clone();
-        let message_clone = message.clone();
-        let email_id_result = tokio::task::spawn_blocking(move || {
-            let mut conn = db_clone.get().map_err(|e| {
-                diesel::result::Error::DatabaseError(
-                    diesel::result::DatabaseErrorKind::UnableToSendCommand,
-                    Box::new(e.to_string()),
-                )
-            })?;
-            db::save_email::save_email(&mut conn, &message_clone)
-        })
-        .await;
-
-        match email_id_result {
-            Ok(Ok(email_id)) => {
-                let db_clone = db.clone();
-                let email_result = tokio::task::spawn_blocking(move || {
-                    let mut conn = db_clone.get().map_err(|e| {
-                        diesel::result::Error::DatabaseError(
-                            diesel::result::DatabaseErrorKind::UnableToSendCommand,
-                            Box::new(e.to_string()),
-                        )
-                    })?;
-                    db::email::get_email(&mut conn, &email_id)
-                })
-                .await;
-
-                if let Ok(Ok(email_record)) = email_result {
-                    let recipients = email_record.recipients.clone();
-                    let email_list_record: db::EmailListRecord = email_record.into();
-                    broadcast
-                        .send(web::ws::WebSocketMessage {
-                            event: web::ws::WebSocketEvent::NewMail,
-                            email: Some(email_list_record),
-                            email_id: None,
-                            recipients: Some(recipients),
-                        })
-                        .ok();
-                }
-            }
-            Ok(Err(e)) => {
-                error!(
-                    component = "smtp",
-                    "Failed to save email to database: {}", e
-                );
-            }
-            Err(e) => {
-                error!(component = "smtp", "Failed to spawn blocking task: {}", e);
-            }
-        }
-    });
-}
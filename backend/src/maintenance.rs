@@ -0,0 +1,218 @@
+use crate::db::{self, retention::RetentionPolicy};
+use crate::web::ws::{BroadcastSender, WebSocketMessage};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+/// Deleted email ids per WebSocket message
+const BROADCAST_CHUNK_SIZE: usize = 500;
+/// Pages returned to the file system per write transaction, so SMTP saves can run in between
+const VACUUM_STEP_PAGES: u64 = 1024;
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct MaintenanceReport {
+    pub deleted_emails: usize,
+    pub reclaimed_bytes: u64,
+}
+
+/// Background housekeeping: applies the retention policy and reclaims free database pages.
+///
+/// Free pages are returned to the file system once they exceed `vacuum_threshold` bytes, or
+/// when the last reclamation is older than `vacuum_interval`. Runs never overlap, a manual run
+/// waits for the scheduled one to finish.
+#[derive(Clone)]
+pub struct Maintenance {
+    pool: db::DbPool,
+    broadcast: BroadcastSender,
+    policy: RetentionPolicy,
+    vacuum_threshold: u64,
+    vacuum_interval: Duration,
+    last_vacuum: Arc<Mutex<Instant>>,
+}
+
+impl Maintenance {
+    pub fn new(
+        pool: db::DbPool,
+        broadcast: BroadcastSender,
+        policy: RetentionPolicy,
+        vacuum_threshold: u64,
+        vacuum_interval: Duration,
+    ) -> Self {
+        Self {
+            pool,
+            broadcast,
+            policy,
+            vacuum_threshold,
+            vacuum_interval,
+            last_vacuum: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Runs maintenance every `interval` until the process exits
+    pub fn spawn(&self, interval: Duration) {
+        let maintenance = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = maintenance.run(false).await {
+                    error!(component = "maintenance", "Maintenance failed: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Applies the retention policy and reclaims free pages, `force_vacuum` skips the threshold
+    pub async fn run(&self, force_vacuum: bool) -> Result<MaintenanceReport, String> {
+        let mut last_vacuum = self.last_vacuum.lock().await;
+        let vacuum_due = force_vacuum || last_vacuum.elapsed() >= self.vacuum_interval;
+
+        let pool = self.pool.clone();
+        let policy = self.policy;
+        let vacuum_threshold = self.vacuum_threshold;
+        let (deleted, reclaimed_bytes) = tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+
+            let deleted = if policy.is_enabled() {
+                db::retention::prune(&mut conn, &policy).map_err(|e| e.to_string())?
+            } else {
+                Vec::new()
+            };
+
+            let free = db::free_database_size(&mut conn).map_err(|e| e.to_string())?;
+            if free == 0 || (!vacuum_due && free < vacuum_threshold) {
+                return Ok::<_, String>((deleted, None));
+            }
+
+            let mut remaining = free;
+            while remaining > 0 {
+                db::incremental_vacuum(&mut conn, VACUUM_STEP_PAGES).map_err(|e| e.to_string())?;
+                let left = db::free_database_size(&mut conn).map_err(|e| e.to_string())?;
+                // Without incremental auto vacuum the pragma frees nothing, a concurrent writer
+                // can refill the freelist as fast as it is emptied
+                if left >= remaining {
+                    warn!(
+                        component = "maintenance",
+                        "Free database space stopped shrinking at {} bytes", left
+                    );
+                    break;
+                }
+                remaining = left;
+            }
+            Ok((deleted, Some(free - remaining)))
+        })
+        .await
+        .map_err(|e| format!("Failed to spawn blocking task: {}", e))??;
+
+        if let Some(bytes) = reclaimed_bytes {
+            *last_vacuum = Instant::now();
+            info!(
+                component = "maintenance",
+                "Reclaimed {} bytes of free database space", bytes
+            );
+        }
+
+        if !deleted.is_empty() {
+            info!(
+                component = "maintenance",
+                "Retention policy deleted {} emails",
+                deleted.len()
+            );
+            self.broadcast_deleted(&deleted);
+        }
+
+        Ok(MaintenanceReport {
+            deleted_emails: deleted.len(),
+            reclaimed_bytes: reclaimed_bytes.unwrap_or(0),
+        })
+    }
+
+    fn broadcast_deleted(&self, email_ids: &[String]) {
+        for chunk in email_ids.chunks(BROADCAST_CHUNK_SIZE) {
+            self.broadcast
+                .send(WebSocketMessage::emails_deleted(chunk.to_vec()))
+                .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{save_email::save_email, testing};
+    use diesel::connection::SimpleConnection;
+
+    fn save_emails(pool: &db::DbPool, count: usize) {
+        let mut conn = pool.get().unwrap();
+        for i in 0..count {
+            let mut email = testing::email(&format!("Email {}", i), &["a@x.com"]);
+            email.body_text = format!("{} ", i).repeat(2_000);
+            save_email(&mut conn, &email).unwrap();
+        }
+    }
+
+    async fn run(maintenance: &Maintenance) -> MaintenanceReport {
+        tokio::time::timeout(Duration::from_secs(5), maintenance.run(true))
+            .await
+            .expect("maintenance did not finish")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_run_prunes_and_reclaims_space() {
+        let pool = testing::pool();
+        save_emails(&pool, 20);
+        let (broadcast, mut events) = tokio::sync::broadcast::channel(16);
+        let policy = RetentionPolicy {
+            max_emails: Some(5),
+            ..RetentionPolicy::default()
+        };
+        let maintenance = Maintenance::new(
+            pool.clone(),
+            broadcast,
+            policy,
+            u64::MAX,
+            Duration::from_secs(3600),
+        );
+
+        let report = run(&maintenance).await;
+        assert_eq!(report.deleted_emails, 15);
+        assert!(report.reclaimed_bytes > 0);
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.email_ids.unwrap().len(), 15);
+
+        let mut conn = pool.get().unwrap();
+        assert_eq!(db::free_database_size(&mut conn).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_when_free_space_does_not_shrink() {
+        let pool = testing::pool();
+        save_emails(&pool, 20);
+        {
+            let mut conn = pool.get().unwrap();
+            conn.batch_execute("PRAGMA auto_vacuum = NONE; VACUUM;")
+                .unwrap();
+        }
+        let (broadcast, _events) = tokio::sync::broadcast::channel(16);
+        let policy = RetentionPolicy {
+            max_emails: Some(5),
+            ..RetentionPolicy::default()
+        };
+        let maintenance = Maintenance::new(
+            pool.clone(),
+            broadcast,
+            policy,
+            u64::MAX,
+            Duration::from_secs(3600),
+        );
+
+        let report = run(&maintenance).await;
+        assert_eq!(report.deleted_emails, 15);
+        assert_eq!(report.reclaimed_bytes, 0);
+        let mut conn = pool.get().unwrap();
+        assert!(db::free_database_size(&mut conn).unwrap() > 0);
+    }
+}
@@ -0,0 +1,493 @@
+use chrono::{DateTime, NaiveDateTime};
+use mail_parser::{Message, MessageParser, MessagePart, MimeHeaders, PartType};
+use std::collections::HashMap;
+
+use crate::db::HeaderRecord;
+use crate::header::{AddressHeaders, extract_raw_headers};
+
+/// One node of the MIME tree of an email.
+///
+/// Paths number the parts from the root: the message itself is `1`, the parts of a multipart
+/// `1.1`, `1.2` and so on. The message inside a `message/rfc822` part is its only child.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct MimePart {
+    pub path: String,
+    /// `None` if the part has no Content-Type header, which means `text/plain`
+    pub content_type: Option<String>,
+    pub charset: Option<String>,
+    pub transfer_encoding: Option<String>,
+    pub disposition: Option<String>,
+    pub filename: Option<String>,
+    pub content_id: Option<String>,
+    /// Bytes of the decoded body
+    pub size: usize,
+    /// Bytes of the part as received, headers included
+    pub raw_size: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<MimePart>,
+}
+
+/// Body of a single part, ready to be downloaded
+pub struct PartContent {
+    pub content_type: String,
+    pub filename: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// An email attached to another one as a `message/rfc822` part
+#[derive(serde::Serialize)]
+pub struct EmbeddedEmail {
+    /// Path of the `message/rfc822` part
+    pub path: String,
+    pub message_id: Option<String>,
+    pub subject: Option<String>,
+    pub date: Option<NaiveDateTime>,
+    pub headers: HashMap<String, Vec<String>>,
+    pub ordered_headers: Vec<HeaderRecord>,
+    #[serde(flatten)]
+    pub addresses: AddressHeaders,
+    pub body_text: String,
+    pub body_html: String,
+    /// Attachments and inline parts, downloadable by their path. Further embedded emails are
+    /// listed here too.
+    pub attachments: Vec<MimePart>,
+}
+
+/// Every text/plain body part joined in message order, the HTML body as text if there is none
+pub fn body_text(message: &Message<'_>) -> String {
+    let texts: Vec<&str> = message
+        .text_body
+        .iter()
+        .filter_map(
+            |part_id| match &message.parts.get(*part_id as usize)?.body {
+                PartType::Text(text) => Some(text.as_ref()),
+                _ => None,
+            },
+        )
+        .collect();
+    if texts.is_empty() {
+        return message
+            .body_text(0)
+            .map(|text| text.into_owned())
+            .unwrap_or_default();
+    }
+    texts.join("\n")
+}
+
+/// Every text/html body part joined in message order, e.g. the HTML alternative followed by the
+/// HTML parts of a mixed message. Empty if the message has no text/html part.
+pub fn body_html(message: &Message<'_>) -> String {
+    let htmls: Vec<&str> = message
+        .html_body
+        .iter()
+        .filter_map(
+            |part_id| match &message.parts.get(*part_id as usize)?.body {
+                PartType::Html(html) => Some(html.as_ref()),
+                _ => None,
+            },
+        )
+        .collect();
+    htmls.join("\n")
+}
+
+/// The MIME tree of a raw message, `None` if mail-parser cannot parse it
+pub fn structure(raw: &[u8]) -> Option<MimePart> {
+    let message = MessageParser::default().parse(raw)?;
+    Some(build_part(&message, 0, "1".to_string()))
+}
+
+/// Part numbers of a path like `1.2.1`, `None` if it is malformed
+pub fn parse_path(path: &str) -> Option<Vec<usize>> {
+    path.split('.')
+        .map(|number| number.parse::<usize>().ok().filter(|number| *number > 0))
+        .collect()
+}
+
+/// The part at `path`. Text parts are decoded to UTF-8 and binary parts from their transfer
+/// encoding, `raw` returns the part exactly as received instead.
+pub fn part_content(raw: &[u8], path: &[usize], raw_part: bool) -> Option<PartContent> {
+    let message = MessageParser::default().parse(raw)?;
+    let (message, part) = find_part(&message, path)?;
+
+    if raw_part {
+        let data = message
+            .raw_message
+            .get(part.offset_header as usize..part.offset_end as usize)?;
+        return Some(PartContent {
+            content_type: "text/plain; charset=utf-8".to_string(),
+            filename: None,
+            data: data.to_vec(),
+        });
+    }
+
+    let content_type = format_content_type(part);
+    let (content_type, data) = match &part.body {
+        PartType::Text(text) | PartType::Html(text) => (
+            format!(
+                "{}; charset=utf-8",
+                content_type.as_deref().unwrap_or("text/plain")
+            ),
+            text.as_bytes().to_vec(),
+        ),
+        PartType::Binary(data) | PartType::InlineBinary(data) => (
+            content_type.unwrap_or_else(|| "application/octet-stream".to_string()),
+            data.to_vec(),
+        ),
+        PartType::Message(nested) => ("message/rfc822".to_string(), nested.raw_message().to_vec()),
+        // A multipart has no body of its own, return what is between its boundaries
+        PartType::Multipart(_) => (
+            "text/plain; charset=utf-8".to_string(),
+            message
+                .raw_message
+                .get(part.offset_body as usize..part.offset_end as usize)?
+                .to_vec(),
+        ),
+    };
+    Some(PartContent {
+        content_type,
+        filename: part.attachment_name().map(str::to_string),
+        data,
+    })
+}
+
+/// Every email embedded in the raw message, nested ones after the email containing them
+pub fn embedded_emails(raw: &[u8]) -> Option<Vec<EmbeddedEmail>> {
+    let message = MessageParser::default().parse(raw)?;
+    let mut emails = Vec::new();
+    for_each_part(&message, &mut |part, path| {
+        if let PartType::Message(nested) = &part.body
+            && !nested.parts.is_empty()
+        {
+            emails.push(build_embedded(nested, path.to_string(), raw));
+        }
+    });
+    Some(emails)
+}
+
+/// The email embedded at `path`, `None` if that part is not a `message/rfc822` part
+pub fn embedded_email(raw: &[u8], path: &[usize]) -> Option<EmbeddedEmail> {
+    let message = MessageParser::default().parse(raw)?;
+    let (_, part) = find_part(&message, path)?;
+    let PartType::Message(nested) = &part.body else {
+        return None;
+    };
+    let path = path
+        .iter()
+        .map(|number| number.to_string())
+        .collect::<Vec<_>>()
+        .join(".");
+    Some(build_embedded(nested, path, raw))
+}
+
+/// Calls `visit` with every part of the message and its path, parents before their children and
+/// the parts of embedded emails included
+pub fn for_each_part<'x>(message: &Message<'x>, visit: &mut impl FnMut(&MessagePart<'x>, &str)) {
+    visit_part(message, 0, "1".to_string(), visit);
+}
+
+fn visit_part<'x>(
+    message: &Message<'x>,
+    part_id: usize,
+    path: String,
+    visit: &mut impl FnMut(&MessagePart<'x>, &str),
+) {
+    let Some(part) = message.parts.get(part_id) else {
+        return;
+    };
+    visit(part, &path);
+    match &part.body {
+        PartType::Multipart(children) => {
+            for (index, child) in children.iter().enumerate() {
+                visit_part(
+                    message,
+                    *child as usize,
+                    format!("{}.{}", path, index + 1),
+                    visit,
+                );
+            }
+        }
+        PartType::Message(nested) => visit_part(nested, 0, format!("{}.1", path), visit),
+        _ => {}
+    }
+}
+
+fn build_embedded(message: &Message<'_>, path: String, raw: &[u8]) -> EmbeddedEmail {
+    let raw_headers = extract_raw_headers(message, raw);
+    let mut headers: HashMap<String, Vec<String>> = HashMap::new();
+    for header in &raw_headers {
+        headers
+            .entry(header.name.clone())
+            .or_default()
+            .push(header.value.clone());
+    }
+
+    let mut paths = HashMap::new();
+    part_paths(message, 0, format!("{}.1", path), &mut paths);
+    let attachments = message
+        .attachments
+        .iter()
+        .filter_map(|part_id| {
+            let part = message.parts.get(*part_id as usize)?;
+            Some(describe_part(part, paths.get(part_id)?.clone()))
+        })
+        .collect();
+
+    EmbeddedEmail {
+        message_id: message.message_id().map(str::to_string),
+        subject: message.subject().map(str::to_string),
+        date: message
+            .date()
+            .and_then(|date| DateTime::from_timestamp(date.to_timestamp(), 0))
+            .map(|date| date.naive_utc()),
+        addresses: AddressHeaders::from_headers(&headers),
+        ordered_headers: raw_headers.iter().map(HeaderRecord::from).collect(),
+        headers,
+        body_text: body_text(message),
+        body_html: body_html(message),
+        attachments,
+        path,
+    }
+}
+
+/// Paths of the parts of `message`, by part id
+fn part_paths(
+    message: &Message<'_>,
+    part_id: usize,
+    path: String,
+    paths: &mut HashMap<u32, String>,
+) {
+    if let Some(PartType::Multipart(children)) = message.parts.get(part_id).map(|part| &part.body) {
+        for (index, child) in children.iter().enumerate() {
+            part_paths(
+                message,
+                *child as usize,
+                format!("{}.{}", path, index + 1),
+                paths,
+            );
+        }
+    }
+    paths.insert(part_id as u32, path);
+}
+
+fn build_part(message: &Message<'_>, part_id: usize, path: String) -> MimePart {
+    let part = &message.parts[part_id];
+    let parts = match &part.body {
+        PartType::Multipart(children) => children
+            .iter()
+            .enumerate()
+            .filter(|(_, child)| (**child as usize) < message.parts.len())
+            .map(|(index, child)| {
+                build_part(message, *child as usize, format!("{}.{}", path, index + 1))
+            })
+            .collect(),
+        PartType::Message(nested) if !nested.parts.is_empty() => {
+            vec![build_part(nested, 0, format!("{}.1", path))]
+        }
+        _ => Vec::new(),
+    };
+
+    MimePart {
+        parts,
+        ..describe_part(part, path)
+    }
+}
+
+fn describe_part(part: &MessagePart<'_>, path: String) -> MimePart {
+    MimePart {
+        content_type: format_content_type(part),
+        charset: part
+            .content_type()
+            .and_then(|content_type| content_type.attribute("charset"))
+            .map(str::to_string),
+        transfer_encoding: part.content_transfer_encoding().map(str::to_string),
+        disposition: part
+            .content_disposition()
+            .map(|disposition| disposition.ctype().to_string()),
+        filename: part.attachment_name().map(str::to_string),
+        content_id: part.content_id().map(str::to_string),
+        size: part.len(),
+        raw_size: (part.offset_end.saturating_sub(part.offset_header)) as usize,
+        path,
+        parts: Vec::new(),
+    }
+}
+
+fn find_part<'a, 'x>(
+    message: &'a Message<'x>,
+    path: &[usize],
+) -> Option<(&'a Message<'x>, &'a MessagePart<'x>)> {
+    let (first, rest) = path.split_first()?;
+    if *first != 1 {
+        return None;
+    }
+    let mut message = message;
+    let mut part = message.parts.first()?;
+    for number in rest {
+        match &part.body {
+            PartType::Multipart(children) => {
+                let child = *children.get(number - 1)? as usize;
+                part = message.parts.get(child)?;
+            }
+            PartType::Message(nested) if *number == 1 => {
+                message = nested;
+                part = message.parts.first()?;
+            }
+            _ => return None,
+        }
+    }
+    Some((message, part))
+}
+
+fn format_content_type(part: &MessagePart<'_>) -> Option<String> {
+    part.content_type()
+        .map(|content_type| match content_type.subtype() {
+            Some(subtype) => format!("{}/{}", content_type.ctype(), subtype),
+            None => content_type.ctype().to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW: &str = "\
+Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+\r\n\
+--outer\r\n\
+Content-Type: multipart/alternative; boundary=\"inner\"\r\n\
+\r\n\
+--inner\r\n\
+Content-Type: text/plain; charset=iso-8859-1\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+Gr=FC=DFe\r\n\
+--inner\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<p>Hi</p>\r\n\
+--inner--\r\n\
+--outer\r\n\
+Content-Type: application/octet-stream\r\n\
+Content-Disposition: attachment; filename=\"a.bin\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+AAEC\r\n\
+--outer--\r\n";
+
+    #[test]
+    fn test_structure() {
+        let root = structure(RAW.as_bytes()).unwrap();
+        assert_eq!(root.content_type.as_deref(), Some("multipart/mixed"));
+        assert_eq!(root.parts.len(), 2);
+
+        let alternative = &root.parts[0];
+        assert_eq!(alternative.path, "1.1");
+        let text = &alternative.parts[0];
+        assert_eq!(text.path, "1.1.1");
+        assert_eq!(text.charset.as_deref(), Some("iso-8859-1"));
+        assert_eq!(text.transfer_encoding.as_deref(), Some("quoted-printable"));
+
+        let attachment = &root.parts[1];
+        assert_eq!(attachment.path, "1.2");
+        assert_eq!(attachment.disposition.as_deref(), Some("attachment"));
+        assert_eq!(attachment.filename.as_deref(), Some("a.bin"));
+        assert_eq!(attachment.size, 3);
+    }
+
+    #[test]
+    fn test_part_content() {
+        let text = part_content(RAW.as_bytes(), &[1, 1, 1], false).unwrap();
+        assert_eq!(text.content_type, "text/plain; charset=utf-8");
+        assert_eq!(String::from_utf8(text.data).unwrap(), "Grüße");
+
+        let attachment = part_content(RAW.as_bytes(), &[1, 2], false).unwrap();
+        assert_eq!(attachment.data, vec![0, 1, 2]);
+        assert_eq!(attachment.filename.as_deref(), Some("a.bin"));
+
+        let raw = part_content(RAW.as_bytes(), &[1, 2], true).unwrap();
+        let raw = String::from_utf8(raw.data).unwrap();
+        assert!(
+            raw.starts_with("Content-Type: application/octet-stream"),
+            "{raw:?}"
+        );
+        assert!(raw.contains("AAEC"), "{raw:?}");
+
+        assert!(part_content(RAW.as_bytes(), &[1, 3], false).is_none());
+        assert!(part_content(RAW.as_bytes(), &[2], false).is_none());
+    }
+
+    const NESTED: &str = "\
+Content-Type: multipart/mixed; boundary=\"o\"\r\n\
+\r\n\
+--o\r\n\
+Content-Type: multipart/alternative; boundary=\"i\"\r\n\
+\r\n\
+--i\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Plain one\r\n\
+--i\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<p>Html one</p>\r\n\
+--i--\r\n\
+--o\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<p>Html two</p>\r\n\
+--o\r\n\
+Content-Type: message/rfc822\r\n\
+\r\n\
+Subject: Forwarded\r\n\
+FROM: Jane <jane@example.com>\r\n\
+Content-Type: multipart/related; boundary=\"n\"\r\n\
+\r\n\
+--n\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<img src=\"cid:logo\">\r\n\
+--n\r\n\
+Content-Type: image/png\r\n\
+Content-ID: <logo>\r\n\
+\r\n\
+png\r\n\
+--n--\r\n\
+--o--\r\n";
+
+    #[test]
+    fn test_bodies_join_all_parts() {
+        let message = MessageParser::default().parse(NESTED.as_bytes()).unwrap();
+        assert_eq!(body_text(&message), "Plain one");
+        assert_eq!(body_html(&message), "<p>Html one</p>\n<p>Html two</p>");
+    }
+
+    #[test]
+    fn test_embedded_emails() {
+        let emails = embedded_emails(NESTED.as_bytes()).unwrap();
+        assert_eq!(emails.len(), 1);
+        let email = &emails[0];
+        assert_eq!(email.path, "1.3");
+        assert_eq!(email.subject.as_deref(), Some("Forwarded"));
+        assert_eq!(email.ordered_headers[1].name, "FROM");
+        assert_eq!(
+            email.addresses.from[0].address.as_deref(),
+            Some("jane@example.com")
+        );
+        assert_eq!(email.body_html, "<img src=\"cid:logo\">");
+        assert_eq!(email.attachments[0].path, "1.3.1.2");
+        assert_eq!(email.attachments[0].content_id.as_deref(), Some("logo"));
+
+        let logo = part_content(NESTED.as_bytes(), &[1, 3, 1, 2], false).unwrap();
+        assert_eq!(logo.data, b"png");
+        assert!(embedded_email(NESTED.as_bytes(), &[1, 3]).is_some());
+        assert!(embedded_email(NESTED.as_bytes(), &[1, 2]).is_none());
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(parse_path("1.2.10"), Some(vec![1, 2, 10]));
+        assert_eq!(parse_path("1.0"), None);
+        assert_eq!(parse_path("1..2"), None);
+        assert_eq!(parse_path("a"), None);
+    }
+}
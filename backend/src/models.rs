@@ -20,6 +20,9 @@ pub struct Email {
     pub read: bool,
     pub has_attachments: bool,
     pub created_at: NaiveDateTime,
+    pub starred: bool,
+    pub thread_id: String,
+    pub thread_subject: Option<String>,
 }
 
 #[derive(
@@ -98,4 +101,65 @@ pub struct Header {
     pub name: String,
     pub value: String,
     pub created_at: NaiveDateTime,
+    pub position: i32,
+    pub raw: Option<Vec<u8>>,
+    pub decoded: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Insertable, Identifiable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = saved_searches)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct SavedSearch {
+    pub id: String,
+    pub name: String,
+    pub query: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Insertable, Identifiable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = tags)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Tag {
+    pub id: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = email_tags)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct EmailTag {
+    pub email_id: String,
+    pub tag_id: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = email_references)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct EmailReference {
+    pub email_id: String,
+    pub message_id: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = email_warnings)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct EmailWarning {
+    pub email_id: String,
+    pub position: i32,
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = email_lint_findings)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct EmailLintFinding {
+    pub email_id: String,
+    pub position: i32,
+    pub severity: String,
+    pub code: String,
+    pub message: String,
 }
@@ -21,6 +21,47 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    email_lint_findings (email_id, position) {
+        email_id -> Text,
+        position -> Integer,
+        severity -> Text,
+        code -> Text,
+        message -> Text,
+    }
+}
+
+diesel::table! {
+    email_references (email_id, message_id) {
+        email_id -> Text,
+        message_id -> Text,
+    }
+}
+
+diesel::table! {
+    email_search_documents (id) {
+        id -> Integer,
+        email_id -> Text,
+    }
+}
+
+diesel::table! {
+    email_tags (email_id, tag_id) {
+        email_id -> Text,
+        tag_id -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    email_warnings (email_id, position) {
+        email_id -> Text,
+        position -> Integer,
+        code -> Text,
+        message -> Text,
+    }
+}
+
 diesel::table! {
     emails (id) {
         id -> Text,
@@ -36,6 +77,9 @@ diesel::table! {
         read -> Bool,
         has_attachments -> Bool,
         created_at -> Timestamp,
+        starred -> Bool,
+        thread_id -> Text,
+        thread_subject -> Nullable<Text>,
     }
 }
 
@@ -54,18 +98,52 @@ diesel::table! {
         name -> Text,
         value -> Text,
         created_at -> Timestamp,
+        position -> Integer,
+        raw -> Nullable<Binary>,
+        decoded -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    saved_searches (id) {
+        id -> Text,
+        name -> Text,
+        query -> Text,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    tags (id) {
+        id -> Text,
+        name -> Text,
+        created_at -> Timestamp,
     }
 }
 
 diesel::joinable!(attachments -> emails (email_id));
 diesel::joinable!(email_envelope_recipients -> emails (email_id));
 diesel::joinable!(email_envelope_recipients -> envelope_recipients (envelope_recipient_id));
+diesel::joinable!(email_lint_findings -> emails (email_id));
+diesel::joinable!(email_references -> emails (email_id));
+diesel::joinable!(email_search_documents -> emails (email_id));
+diesel::joinable!(email_tags -> emails (email_id));
+diesel::joinable!(email_tags -> tags (tag_id));
+diesel::joinable!(email_warnings -> emails (email_id));
 diesel::joinable!(headers -> emails (email_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     attachments,
     email_envelope_recipients,
+    email_lint_findings,
+    email_references,
+    email_search_documents,
+    email_tags,
+    email_warnings,
     emails,
     envelope_recipients,
     headers,
+    saved_searches,
+    tags,
 );
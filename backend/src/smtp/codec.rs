@@ -0,0 +1,85 @@
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder, LinesCodec, LinesCodecError};
+
+/// `LinesCodec` that remembers how the last line ended.
+///
+/// Lines are split at LF with an optional CR before it, so a client sending bare LF line endings
+/// cannot be told apart from one sending CRLF after decoding.
+#[derive(Debug)]
+pub(super) struct LineCodec {
+    lines: LinesCodec,
+    bare_lf: bool,
+}
+
+impl LineCodec {
+    pub(super) fn new_with_max_length(max_length: usize) -> Self {
+        Self {
+            lines: LinesCodec::new_with_max_length(max_length),
+            bare_lf: false,
+        }
+    }
+
+    /// True if the last decoded line ended with LF instead of CRLF
+    pub(super) fn bare_lf(&self) -> bool {
+        self.bare_lf
+    }
+}
+
+/// Whether the first line in `buf` ends with a bare LF, `None` if it is not complete yet
+fn first_line_bare_lf(buf: &BytesMut) -> Option<bool> {
+    let newline = buf.iter().position(|byte| *byte == b'\n')?;
+    Some(newline == 0 || buf[newline - 1] != b'\r')
+}
+
+impl Decoder for LineCodec {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        let bare_lf = first_line_bare_lf(buf);
+        let line = self.lines.decode(buf)?;
+        if line.is_some() {
+            self.bare_lf = bare_lf.unwrap_or(false);
+        }
+        Ok(line)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        let bare_lf = first_line_bare_lf(buf);
+        let line = self.lines.decode_eof(buf)?;
+        if line.is_some() {
+            self.bare_lf = bare_lf.unwrap_or(false);
+        }
+        Ok(line)
+    }
+}
+
+impl<T: AsRef<str>> Encoder<T> for LineCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: T, buf: &mut BytesMut) -> Result<(), LinesCodecError> {
+        self.lines.encode(line, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_bare_lf() {
+        let mut codec = LineCodec::new_with_max_length(1024);
+        let mut buf = BytesMut::from("crlf\r\nbare\nincomplete");
+        assert_eq!(codec.decode(&mut buf).unwrap().as_deref(), Some("crlf"));
+        assert!(!codec.bare_lf());
+        assert_eq!(codec.decode(&mut buf).unwrap().as_deref(), Some("bare"));
+        assert!(codec.bare_lf());
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(codec.bare_lf());
+        assert_eq!(
+            codec.decode_eof(&mut buf).unwrap().as_deref(),
+            Some("incomplete")
+        );
+        assert!(!codec.bare_lf());
+    }
+}
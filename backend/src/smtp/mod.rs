@@ -1,5 +1,7 @@
+mod codec;
 mod parser;
 mod server;
 
+pub use crate::header::RawHeader;
 pub use parser::EmailAttachment;
-pub use server::{Email, Result, SmtpError, SmtpServer};
+pub use server::{Email, Result, SmtpError, SmtpServer, Timeouts};
@@ -3,6 +3,11 @@ use mail_parser::{MessageParser, MimeHeaders, PartType};
 use std::collections::HashMap;
 use tracing::warn;
 
+use crate::diagnostics::{self, ParseWarning};
+use crate::header::{RawHeader, extract_raw_headers};
+use crate::lint::{self, LintFinding};
+use crate::mime;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EmailAttachment {
     pub filename: Option<String>,
@@ -19,31 +24,26 @@ pub(super) struct ParsedEmailDetails {
     pub subject: Option<String>,
     pub date: Option<DateTime<Utc>>,
     pub headers: Option<serde_json::Value>,
+    pub raw_headers: Vec<RawHeader>,
     pub body_text: String,
     pub body_html: String,
+    pub warnings: Vec<ParseWarning>,
+    pub lint: Vec<LintFinding>,
 }
 
-pub(super) fn parse_email_details(raw: &str) -> ParsedEmailDetails {
+/// `smtputf8` is true if the client sent MAIL FROM with the SMTPUTF8 parameter
+pub(super) fn parse_email_details(raw: &str, smtputf8: bool) -> ParsedEmailDetails {
     let parser = MessageParser::default();
-    match parser.parse(raw.as_bytes()) {
+    let message = parser.parse(raw.as_bytes());
+    let warnings = diagnostics::diagnose(raw, message.as_ref());
+    let lint = lint::lint(raw, message.as_ref(), smtputf8);
+    match message {
         Some(message) => {
-            let headers = extract_headers_from_raw(message.headers_raw());
-
-            let body_text = message
-                .body_text(0)
-                .map(|s| s.to_string())
-                .unwrap_or_default();
-            let body_html = message
-                .html_part(0)
-                .filter(|part| {
-                    // Only use if it's actually text/html content type
-                    part.content_type()
-                        .map(|ct| ct.ctype() == "text" && ct.subtype() == Some("html"))
-                        .unwrap_or(false)
-                })
-                .and_then(|_| message.body_html(0))
-                .map(|s| s.to_string())
-                .unwrap_or_default();
+            let raw_headers = extract_raw_headers(&message, raw.as_bytes());
+            let headers = group_headers(&raw_headers);
+
+            let body_text = mime::body_text(&message);
+            let body_html = mime::body_html(&message);
 
             let attachments = collect_attachments(&message);
 
@@ -57,8 +57,11 @@ pub(super) fn parse_email_details(raw: &str) -> ParsedEmailDetails {
                 subject: message.subject().map(|s| s.to_string()),
                 date,
                 headers: Some(headers),
+                raw_headers,
                 body_text,
                 body_html,
+                warnings,
+                lint,
             }
         }
         None => {
@@ -69,23 +72,25 @@ pub(super) fn parse_email_details(raw: &str) -> ParsedEmailDetails {
                 subject: None,
                 date: None,
                 headers: None,
+                raw_headers: Vec::new(),
                 body_text: String::new(),
                 body_html: String::new(),
+                warnings,
+                lint,
             }
         }
     }
 }
 
-fn extract_headers_from_raw<'a>(
-    headers_raw: impl Iterator<Item = (&'a str, &'a str)>,
-) -> serde_json::Value {
+/// Values by header name, the shape `EmailRecord.headers` always had
+fn group_headers(raw_headers: &[RawHeader]) -> serde_json::Value {
     let mut header_map: HashMap<String, Vec<String>> = HashMap::new();
 
-    for (name, value) in headers_raw {
+    for header in raw_headers {
         header_map
-            .entry(name.to_string())
+            .entry(header.name.clone())
             .or_default()
-            .push(value.to_string());
+            .push(header.value.clone());
     }
 
     serde_json::to_value(header_map).unwrap_or(serde_json::Value::Object(serde_json::Map::new()))
@@ -156,7 +161,7 @@ Content-Disposition: attachment; filename=\"note.txt\"\r\n\
 \r\n\
 Attachment body\r\n\
 --BOUNDARY--\r\n";
-        let details = parse_email_details(raw);
+        let details = parse_email_details(raw, false);
         assert_eq!(details.attachments.len(), 1);
         let attachment = &details.attachments[0];
         assert_eq!(attachment.filename.as_deref(), Some("note.txt"));
@@ -172,9 +177,33 @@ Message-ID: <1234@example.com>\r\n\
 Content-Type: text/plain\r\n\
 \r\n\
 Body\r\n";
-        let details = parse_email_details(raw);
+        let details = parse_email_details(raw, false);
         // mail-parser returns message-id without angle brackets
         assert_eq!(details.message_id.as_deref(), Some("1234@example.com"));
         assert!(details.attachments.is_empty());
     }
+
+    #[test]
+    fn keeps_header_order_and_casing() {
+        let raw = "\
+Received: from b.example\r\n\
+SUBJECT: Hello\r\n\
+Received: from a.example\r\n\
+x-custom:  folded\r\n continued\r\n\
+\r\n\
+Body\r\n";
+        let details = parse_email_details(raw, false);
+        let names: Vec<&str> = details
+            .raw_headers
+            .iter()
+            .map(|header| header.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Received", "Subject", "Received", "x-custom"]);
+        assert_eq!(details.raw_headers[1].raw, b"SUBJECT: Hello\r\n");
+        assert_eq!(details.raw_headers[3].value, "  folded\r\n continued\r\n");
+        assert_eq!(
+            details.headers.unwrap()["Received"],
+            serde_json::json!([" from b.example\r\n", " from a.example\r\n"])
+        );
+    }
 }
@@ -1,15 +1,24 @@
+use super::codec::LineCodec;
 use super::parser::{EmailAttachment, parse_email_details};
+use crate::diagnostics::ParseWarning;
+use crate::header::RawHeader;
+use crate::lint::LintFinding;
 use chrono::Utc;
-use futures::{SinkExt, StreamExt};
+use futures::future::BoxFuture;
+use futures::{FutureExt, SinkExt, StreamExt};
 use rand::RngExt;
-use smtp_proto::Request;
+use smtp_proto::{MAIL_SMTPUTF8, Request};
 use std::fmt;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Semaphore;
-use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
-use tracing::{error, info};
+use tokio::time::{Instant, timeout, timeout_at};
+use tokio_util::codec::{Framed, LinesCodecError};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
 /// Decode base64 string, handling padding issues
@@ -24,8 +33,39 @@ fn base64_decode(input: &str) -> std::result::Result<String, String> {
 
 pub type Result<T> = std::result::Result<T, SmtpError>;
 
-/// Callback function type for handling received emails
-pub type OnReceiveCallback = Arc<dyn Fn(&Email) + Send + Sync>;
+/// RFC 3463: transient mail system error, the client is expected to retry later
+const DELIVERY_FAILED_REPLY: &str = "451 4.3.0 Failed to store message, try again later";
+
+/// Callback function type for handling received emails.
+///
+/// The client gets `250` once the returned future resolves with `Ok`, and a transient `451` error
+/// otherwise, so the callback should only resolve after the message has been stored.
+pub type OnReceiveCallback =
+    Arc<dyn Fn(Email) -> BoxFuture<'static, std::result::Result<(), String>> + Send + Sync>;
+
+/// Per-connection time limits (RFC 5321 Section 4.5.3.2)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Timeouts {
+    /// Maximum wait for the next command line
+    pub command: Duration,
+    /// Maximum wait for the next line while receiving DATA
+    pub data: Duration,
+    /// Maximum total duration of a connection
+    pub session: Duration,
+    /// Maximum wait for `on_receive` to store a message, independent of the session limit
+    pub delivery: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            command: Duration::from_secs(300),
+            data: Duration::from_secs(180),
+            session: Duration::from_secs(1800),
+            delivery: Duration::from_secs(60),
+        }
+    }
+}
 
 pub struct SmtpServer {
     addr: SocketAddr,
@@ -33,6 +73,17 @@ pub struct SmtpServer {
     max_connections: usize,
     auth_username: Option<String>,
     auth_password: Option<String>,
+    timeouts: Timeouts,
+    shutdown_timeout: Duration,
+}
+
+/// Shutdown signals handed to every connection
+#[derive(Clone)]
+struct Shutdown {
+    /// Set when the server stops; idle sessions close, DATA transfers may finish
+    stop: CancellationToken,
+    /// Set when the grace period is over; every session closes immediately
+    abort: CancellationToken,
 }
 
 impl SmtpServer {
@@ -43,14 +94,17 @@ impl SmtpServer {
             max_connections: 0,
             auth_username: None,
             auth_password: None,
+            timeouts: Timeouts::default(),
+            shutdown_timeout: Duration::from_secs(8),
         }
     }
 
-    pub fn on_receive<F>(mut self, callback: F) -> Self
+    pub fn on_receive<F, Fut>(mut self, callback: F) -> Self
     where
-        F: Fn(&Email) + Send + Sync + 'static,
+        F: Fn(Email) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<(), String>> + Send + 'static,
     {
-        self.on_receive = Some(Arc::new(callback));
+        self.on_receive = Some(Arc::new(move |message| callback(message).boxed()));
         self
     }
 
@@ -65,13 +119,44 @@ impl SmtpServer {
         self
     }
 
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.command = timeout;
+        self
+    }
+
+    pub fn data_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.data = timeout;
+        self
+    }
+
+    pub fn session_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.session = timeout;
+        self
+    }
+
+    /// Grace period for in-flight DATA transfers once shutdown was requested
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
     pub fn address(&self) -> SocketAddr {
         self.addr
     }
 
-    pub async fn run(&self) -> Result<()> {
+    /// Accept connections until `shutdown` is cancelled, then drain open sessions.
+    ///
+    /// Idle sessions are closed with `421` right away, sessions in the middle of DATA may finish
+    /// their message within `shutdown_timeout`. Returns once every session has ended, so all
+    /// `on_receive` callbacks have run by then.
+    pub async fn run(&self, shutdown: CancellationToken) -> Result<()> {
         let listener = TcpListener::bind(self.addr).await?;
         let on_receive = self.on_receive.clone();
+        let sessions = TaskTracker::new();
+        let signals = Shutdown {
+            stop: shutdown,
+            abort: CancellationToken::new(),
+        };
 
         // Only accept new TCP connections when we have a slot (enforces max_connections at accept time)
         let semaphore = Arc::new(Semaphore::new(self.max_connections));
@@ -83,28 +168,64 @@ impl SmtpServer {
 
         loop {
             // Wait for a free slot before accepting; this ensures we never accept more than max_connections
-            let permit = semaphore.clone().acquire_owned().await.map_err(|_| {
-                SmtpError::Io(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "semaphore closed",
-                ))
-            })?;
-
-            let (stream, peer) = listener.accept().await?;
+            let permit = tokio::select! {
+                _ = signals.stop.cancelled() => break,
+                permit = semaphore.clone().acquire_owned() => permit
+                    .map_err(|_| SmtpError::Io(std::io::Error::other("semaphore closed")))?,
+            };
+
+            let (stream, peer) = tokio::select! {
+                _ = signals.stop.cancelled() => break,
+                accepted = listener.accept() => accepted?,
+            };
             info!(component = "smtp", peer = %peer, "Connection accepted");
             let on_receive = on_receive.clone();
             let auth_username = self.auth_username.clone();
             let auth_password = self.auth_password.clone();
+            let timeouts = self.timeouts;
+            let signals = signals.clone();
 
-            tokio::spawn(async move {
+            sessions.spawn(async move {
                 let _permit = permit; // released when task ends
-                if let Err(err) =
-                    handle_connection(stream, on_receive, auth_username, auth_password, peer).await
+                if let Err(err) = handle_connection(
+                    stream,
+                    on_receive,
+                    auth_username,
+                    auth_password,
+                    timeouts,
+                    signals,
+                    peer,
+                )
+                .await
                 {
                     error!(component = "smtp", peer = %peer, "SMTP session failed: {}", err);
                 }
             });
         }
+
+        drop(listener);
+        sessions.close();
+        info!(
+            component = "smtp",
+            "SMTP server stopped accepting, draining {} open sessions",
+            sessions.len()
+        );
+
+        if timeout(self.shutdown_timeout, sessions.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                component = "smtp",
+                "Shutdown grace period exceeded, closing {} remaining sessions",
+                sessions.len()
+            );
+            signals.abort.cancel();
+            sessions.wait().await;
+        }
+
+        info!(component = "smtp", "SMTP server stopped");
+        Ok(())
     }
 }
 
@@ -116,6 +237,8 @@ impl Clone for SmtpServer {
             max_connections: self.max_connections,
             auth_username: self.auth_username.clone(),
             auth_password: self.auth_password.clone(),
+            timeouts: self.timeouts,
+            shutdown_timeout: self.shutdown_timeout,
         }
     }
 }
@@ -125,23 +248,79 @@ async fn handle_connection(
     on_receive: Option<OnReceiveCallback>,
     auth_username: Option<String>,
     auth_password: Option<String>,
+    timeouts: Timeouts,
+    shutdown: Shutdown,
     peer: SocketAddr,
 ) -> Result<()> {
-    let mut framed: Framed<TcpStream, LinesCodec> =
-        Framed::new(stream, LinesCodec::new_with_max_length(26_214_400));
-    framed.send("220 mailfang SMTP ready".to_string()).await?;
+    let session_deadline = Instant::now() + timeouts.session;
+    let mut framed: Framed<TcpStream, LineCodec> =
+        Framed::new(stream, LineCodec::new_with_max_length(26_214_400));
+    let deadline = session_deadline.min(Instant::now() + timeouts.command);
+    send_line(&mut framed, "220 mailfang SMTP ready".to_string(), deadline).await?;
 
     let mut session = Session::new(on_receive, auth_username, auth_password, peer);
 
-    while let Some(line_result) = framed.next().await {
+    loop {
+        let in_data = session.state == SessionState::Data;
+
+        // A message that is being transferred may still finish while the server drains
+        if shutdown.stop.is_cancelled() && !in_data {
+            close_connection(&mut framed, "4.3.2 Service shutting down", timeouts, peer).await;
+            break;
+        }
+
+        // RFC 5321 Section 4.5.3.2: the DATA block has its own, usually shorter, inactivity limit
+        let idle_timeout = if in_data {
+            timeouts.data
+        } else {
+            timeouts.command
+        };
+        let deadline = session_deadline.min(Instant::now() + idle_timeout);
+
+        let next_line = tokio::select! {
+            _ = shutdown.stop.cancelled(), if !in_data => continue,
+            _ = shutdown.abort.cancelled() => {
+                close_connection(&mut framed, "4.3.2 Service shutting down", timeouts, peer).await;
+                break;
+            }
+            next_line = timeout_at(deadline, framed.next()) => next_line,
+        };
+
+        let line_result = match next_line {
+            Ok(Some(line_result)) => line_result,
+            Ok(None) => break,
+            Err(_) => {
+                let reason = if deadline == session_deadline {
+                    "4.4.2 Session time limit exceeded"
+                } else {
+                    "4.4.2 Idle timeout exceeded"
+                };
+                close_connection(&mut framed, reason, timeouts, peer).await;
+                break;
+            }
+        };
+        let deadline = session_deadline.min(Instant::now() + timeouts.command);
+
         match line_result {
             Ok(line) => {
+                if session.state == SessionState::Data && line != "." && framed.codec().bare_lf() {
+                    session.bare_lf_lines += 1;
+                }
                 match session.process_line(&line) {
                     Ok(responses) => {
                         for response in responses {
-                            if let Err(e) = framed.send(response).await {
-                                return Err(e.into());
-                            }
+                            send_line(&mut framed, response, deadline).await?;
+                        }
+                        // The reply to the final "." waits until the message has been stored.
+                        // The session limit does not cut this short: the message would still be
+                        // committed after a 451 and the client's retry would store it twice.
+                        // A save that hangs is bounded by the separate delivery limit instead.
+                        if let Some(reply) = session
+                            .deliver_pending(timeouts.delivery, &shutdown.abort)
+                            .await
+                        {
+                            let deadline = Instant::now() + timeouts.command;
+                            send_line(&mut framed, reply, deadline).await?;
                         }
                         if session.should_close() {
                             break;
@@ -181,7 +360,7 @@ async fn handle_connection(
                             );
                         }
 
-                        let _ = framed.send(response).await;
+                        let _ = send_line(&mut framed, response, deadline).await;
                         // Don't break on error, continue processing
                     }
                 }
@@ -195,6 +374,30 @@ async fn handle_connection(
     Ok(())
 }
 
+/// RFC 5321 Section 3.8: announce that the server closes the transmission channel with 421
+async fn close_connection(
+    framed: &mut Framed<TcpStream, LineCodec>,
+    reason: &str,
+    timeouts: Timeouts,
+    peer: SocketAddr,
+) {
+    info!(component = "smtp", peer = %peer, "Closing connection: {}", reason);
+    let deadline = Instant::now() + timeouts.command;
+    let _ = send_line(framed, format!("421 {}", reason), deadline).await;
+}
+
+/// Write a single response line, giving up once `deadline` has passed
+async fn send_line(
+    framed: &mut Framed<TcpStream, LineCodec>,
+    line: String,
+    deadline: Instant,
+) -> Result<()> {
+    timeout_at(deadline, framed.send(line))
+        .await
+        .map_err(|_| SmtpError::Timeout)??;
+    Ok(())
+}
+
 struct Session {
     state: SessionState,
     greeted: bool,
@@ -202,7 +405,12 @@ struct Session {
     mail_from: Option<String>,
     rcpt_to: Vec<String>,
     buffer: Vec<String>,
+    /// Lines of the current DATA block that ended with LF instead of CRLF
+    bare_lf_lines: usize,
+    /// The client sent MAIL FROM with the SMTPUTF8 parameter
+    smtputf8: bool,
     messages: Vec<Email>,
+    pending_delivery: Option<Email>,
     quit: bool,
     on_receive: Option<OnReceiveCallback>,
     auth_state: AuthState,
@@ -227,7 +435,10 @@ impl Session {
             mail_from: None,
             rcpt_to: Vec::new(),
             buffer: Vec::new(),
+            bare_lf_lines: 0,
+            smtputf8: false,
             messages: Vec::new(),
+            pending_delivery: None,
             quit: false,
             on_receive,
             auth_state: AuthState::None,
@@ -262,10 +473,11 @@ impl Session {
                     self.reset_transaction();
                 }
                 self.greeted = true;
-                // Advertise AUTH PLAIN, LOGIN, and CRAM-MD5 capabilities
+                // Advertise AUTH PLAIN, LOGIN, and CRAM-MD5 capabilities and RFC 6531 SMTPUTF8
                 Ok(vec![
                     format!("250-Hello {}", host),
                     "250-AUTH PLAIN LOGIN CRAM-MD5".into(),
+                    "250-SMTPUTF8".into(),
                     "250 SIZE 26214400".into(),
                 ])
             }
@@ -284,6 +496,7 @@ impl Session {
                     from.address.to_string()
                 };
                 self.mail_from = Some(reverse_path);
+                self.smtputf8 = from.flags & MAIL_SMTPUTF8 != 0;
                 self.rcpt_to.clear();
                 self.buffer.clear();
                 Ok(vec!["250 OK".into()])
@@ -297,6 +510,7 @@ impl Session {
                 ensure(!self.rcpt_to.is_empty(), "503 Need RCPT TO first")?;
                 self.state = SessionState::Data;
                 self.buffer.clear();
+                self.bare_lf_lines = 0;
                 Ok(vec!["354 End data with <CR><LF>.<CR><LF>".into()])
             }
             Request::Rset => {
@@ -377,7 +591,11 @@ impl Session {
         // If line starts with "." and has other characters, remove the first "."
         if line == "." {
             let data = self.buffer.join("\r\n");
-            let parsed_details = parse_email_details(&data);
+            let parsed_details = parse_email_details(&data, self.smtputf8);
+            let mut warnings = parsed_details.warnings.clone();
+            if self.bare_lf_lines > 0 {
+                warnings.push(ParseWarning::bare_lf(self.bare_lf_lines));
+            }
 
             let message = Email {
                 id: Uuid::new_v4(),
@@ -385,13 +603,16 @@ impl Session {
                 subject: parsed_details.subject.clone(),
                 date: parsed_details.date,
                 headers: parsed_details.headers.clone(),
+                raw_headers: parsed_details.raw_headers.clone(),
                 from: self.mail_from.clone().unwrap_or_default(),
                 to: self.rcpt_to.clone(),
-                size: data.as_bytes().len() as u64,
+                size: data.len() as u64,
                 data: data.clone(),
                 body_text: parsed_details.body_text.clone(),
                 body_html: parsed_details.body_html.clone(),
                 attachments: parsed_details.attachments.clone(),
+                warnings,
+                lint: parsed_details.lint.clone(),
             };
             self.messages.push(message.clone());
 
@@ -406,14 +627,17 @@ impl Session {
                 "Email accepted"
             );
 
-            if let Some(ref callback) = self.on_receive {
-                callback(&message);
-            }
-
             // RFC 5321 Section 4.1.1.4: Clear buffers after successful DATA
             self.state = SessionState::Command;
             self.reset_transaction();
-            Ok(vec!["250 OK".into()])
+
+            if self.on_receive.is_some() {
+                // Answered by `deliver_pending` once the message has been stored
+                self.pending_delivery = Some(message);
+                Ok(vec![])
+            } else {
+                Ok(vec!["250 OK".into()])
+            }
         } else {
             // RFC 5321 Section 4.5.2: If first character is "." and there are other
             // characters, delete the first character
@@ -427,6 +651,50 @@ impl Session {
         }
     }
 
+    /// Hand the last accepted message to `on_receive` and build the final reply for it. Gives up
+    /// with `451` after `limit`, and with `421` and closes the session once `abort` is set.
+    async fn deliver_pending(
+        &mut self,
+        limit: Duration,
+        abort: &CancellationToken,
+    ) -> Option<String> {
+        let message = self.pending_delivery.take()?;
+        let callback = self.on_receive.clone()?;
+
+        let result = tokio::select! {
+            result = timeout(limit, callback(message)) => result,
+            _ = abort.cancelled() => {
+                warn!(
+                    component = "smtp",
+                    peer = %self.peer,
+                    "Shutting down before the email was stored"
+                );
+                self.quit = true;
+                return Some("421 4.3.2 Service shutting down".into());
+            }
+        };
+
+        match result {
+            Ok(Ok(())) => Some("250 OK".into()),
+            Err(_) => {
+                error!(
+                    component = "smtp",
+                    peer = %self.peer,
+                    "Storing the email took longer than {:?}", limit
+                );
+                Some(DELIVERY_FAILED_REPLY.into())
+            }
+            Ok(Err(e)) => {
+                error!(
+                    component = "smtp",
+                    peer = %self.peer,
+                    "Failed to store email: {}", e
+                );
+                Some(DELIVERY_FAILED_REPLY.into())
+            }
+        }
+    }
+
     fn should_close(&self) -> bool {
         self.quit
     }
@@ -482,7 +750,7 @@ impl Session {
                         return Ok(vec!["535 Authentication failed".into()]);
                     }
                 };
-                if self.validate_login_auth(&username, &password) {
+                if self.validate_login_auth(username, &password) {
                     self.state = SessionState::Command;
                     self.auth_state = AuthState::None;
                     self.authenticated = true;
@@ -499,7 +767,7 @@ impl Session {
                 }
             }
             AuthState::WaitingForCramMd5Response { challenge } => {
-                if self.validate_cram_md5_auth(line, &challenge) {
+                if self.validate_cram_md5_auth(line, challenge) {
                     self.state = SessionState::Command;
                     self.auth_state = AuthState::None;
                     self.authenticated = true;
@@ -638,6 +906,8 @@ impl Session {
         self.mail_from = None;
         self.rcpt_to.clear();
         self.buffer.clear();
+        self.bare_lf_lines = 0;
+        self.smtputf8 = false;
     }
 
     #[cfg(test)]
@@ -646,8 +916,9 @@ impl Session {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
 enum SessionState {
+    #[default]
     Command,
     Data,
     Auth,
@@ -662,12 +933,6 @@ enum AuthState {
     WaitingForCramMd5Response { challenge: String },
 }
 
-impl Default for SessionState {
-    fn default() -> Self {
-        SessionState::Command
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct Email {
     pub id: Uuid,
@@ -675,6 +940,8 @@ pub struct Email {
     pub subject: Option<String>,
     pub date: Option<chrono::DateTime<Utc>>,
     pub headers: Option<serde_json::Value>,
+    /// Header fields in message order
+    pub raw_headers: Vec<RawHeader>,
     pub from: String,    // SMTP envelope sender (MAIL FROM)
     pub to: Vec<String>, // SMTP envelope recipients (RCPT TO)
     pub size: u64,
@@ -682,6 +949,10 @@ pub struct Email {
     pub body_text: String,
     pub body_html: String,
     pub attachments: Vec<EmailAttachment>,
+    /// Problems found while parsing, see `diagnostics`
+    pub warnings: Vec<ParseWarning>,
+    /// RFC 5322 and MIME problems, see `lint`
+    pub lint: Vec<LintFinding>,
 }
 
 fn ensure(condition: bool, err: &'static str) -> Result<()> {
@@ -698,6 +969,7 @@ pub enum SmtpError {
     Codec(LinesCodecError),
     Protocol(&'static str),
     InvalidAddress,
+    Timeout,
 }
 
 impl fmt::Display for SmtpError {
@@ -707,6 +979,7 @@ impl fmt::Display for SmtpError {
             SmtpError::Codec(err) => write!(f, "codec error: {err}"),
             SmtpError::Protocol(msg) => write!(f, "protocol error: {msg}"),
             SmtpError::InvalidAddress => write!(f, "invalid address syntax"),
+            SmtpError::Timeout => write!(f, "timeout exceeded"),
         }
     }
 }
@@ -729,6 +1002,7 @@ impl From<LinesCodecError> for SmtpError {
 mod tests {
     use super::*;
     use base64::Engine;
+    use tokio_util::codec::LinesCodec;
 
     #[test]
     fn handles_basic_flow() {
@@ -739,6 +1013,7 @@ mod tests {
             vec![
                 "250-Hello localhost",
                 "250-AUTH PLAIN LOGIN CRAM-MD5",
+                "250-SMTPUTF8",
                 "250 SIZE 26214400"
             ]
         );
@@ -769,7 +1044,7 @@ mod tests {
         assert_eq!(stored.to, vec!["recipient@example.com"]);
         // Email should be stored exactly as sent (no Received header added)
         assert_eq!(stored.data, "Subject: Hi\r\n\r\nBody line");
-        assert_eq!(stored.size, stored.data.as_bytes().len() as u64);
+        assert_eq!(stored.size, stored.data.len() as u64);
         assert_eq!(stored.body_text, "Body line");
         assert_eq!(stored.body_html, "");
         // Date might be None if not in email, so we just check it's not in the future if present
@@ -790,6 +1065,7 @@ mod tests {
             vec![
                 "250-Hello localhost",
                 "250-AUTH PLAIN LOGIN CRAM-MD5",
+                "250-SMTPUTF8",
                 "250 SIZE 26214400"
             ]
         );
@@ -867,6 +1143,41 @@ mod tests {
         assert_eq!(stored.body_text, "Body text");
     }
 
+    #[test]
+    fn lints_non_ascii_headers_without_smtputf8() {
+        let peer: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let mut session = Session::new(None, None, None, peer);
+        session.process_line("EHLO localhost").unwrap();
+        for mail_from in [
+            "MAIL FROM:<sender@example.com>",
+            "MAIL FROM:<sender@example.com> SMTPUTF8",
+        ] {
+            session.process_line(mail_from).unwrap();
+            session
+                .process_line("RCPT TO:<recipient@example.com>")
+                .unwrap();
+            session.process_line("DATA").unwrap();
+            session.process_line("Subject: Grüße").unwrap();
+            session.process_line("").unwrap();
+            session.process_line("Body").unwrap();
+            session.process_line(".").unwrap();
+        }
+
+        let codes: Vec<Vec<&str>> = session
+            .messages
+            .iter()
+            .map(|message| {
+                message
+                    .lint
+                    .iter()
+                    .map(|finding| finding.code.as_str())
+                    .filter(|code| *code == "non_ascii_header")
+                    .collect()
+            })
+            .collect();
+        assert_eq!(codes, vec![vec!["non_ascii_header"], vec![]]);
+    }
+
     #[test]
     fn rejects_out_of_order_commands() {
         let peer: SocketAddr = "127.0.0.1:12345".parse().unwrap();
@@ -949,7 +1260,7 @@ mod tests {
         session.process_line("EHLO localhost").unwrap();
 
         // Create base64 encoded credentials: \0user\0pass
-        let credentials = format!("\0user\0pass");
+        let credentials = "\0user\0pass".to_string();
         let encoded = engine.encode(credentials.as_bytes());
 
         let response = session
@@ -984,7 +1295,7 @@ mod tests {
         assert_eq!(response, vec!["334 "]);
 
         // Send credentials
-        let credentials = format!("\0user\0pass");
+        let credentials = "\0user\0pass".to_string();
         let encoded = engine.encode(credentials.as_bytes());
         let response = session.process_line(&encoded).unwrap();
         assert_eq!(response, vec!["235 Authentication successful"]);
@@ -1005,7 +1316,7 @@ mod tests {
         session.process_line("EHLO localhost").unwrap();
 
         // Wrong password
-        let credentials = format!("\0user\0wrong");
+        let credentials = "\0user\0wrong".to_string();
         let encoded = engine.encode(credentials.as_bytes());
         let response = session
             .process_line(&format!("AUTH PLAIN {}", encoded))
@@ -1086,7 +1397,7 @@ mod tests {
         session.process_line("EHLO localhost").unwrap();
 
         // Any credentials should be accepted
-        let credentials = format!("\0anyuser\0anypass");
+        let credentials = "\0anyuser\0anypass".to_string();
         let encoded = engine.encode(credentials.as_bytes());
         let response = session
             .process_line(&format!("AUTH PLAIN {}", encoded))
@@ -1485,4 +1796,373 @@ mod tests {
         assert!(stored.data.contains("..three dots")); // ... becomes ..
         assert!(stored.data.contains("text.middle")); // Middle dot unchanged
     }
+
+    async fn spawn_connection(timeouts: Timeouts) -> Framed<TcpStream, LinesCodec> {
+        let shutdown = Shutdown {
+            stop: CancellationToken::new(),
+            abort: CancellationToken::new(),
+        };
+        spawn_connection_with_shutdown(timeouts, shutdown).await
+    }
+
+    async fn spawn_connection_with_shutdown(
+        timeouts: Timeouts,
+        shutdown: Shutdown,
+    ) -> Framed<TcpStream, LinesCodec> {
+        spawn_connection_with_callback(timeouts, shutdown, None).await
+    }
+
+    async fn spawn_connection_with_callback(
+        timeouts: Timeouts,
+        shutdown: Shutdown,
+        on_receive: Option<OnReceiveCallback>,
+    ) -> Framed<TcpStream, LinesCodec> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_connection(stream, on_receive, None, None, timeouts, shutdown, peer)
+                .await
+                .ok();
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        Framed::new(stream, LinesCodec::new())
+    }
+
+    async fn next_line(client: &mut Framed<TcpStream, LinesCodec>) -> Option<String> {
+        tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("server did not answer in time")
+            .map(|line| line.unwrap())
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connection_after_command_timeout() {
+        let mut client = spawn_connection(Timeouts {
+            command: Duration::from_millis(100),
+            ..Timeouts::default()
+        })
+        .await;
+
+        assert_eq!(
+            next_line(&mut client).await.unwrap(),
+            "220 mailfang SMTP ready"
+        );
+        assert_eq!(
+            next_line(&mut client).await.unwrap(),
+            "421 4.4.2 Idle timeout exceeded"
+        );
+        assert!(next_line(&mut client).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn closes_stalled_data_transfer_after_data_timeout() {
+        let mut client = spawn_connection(Timeouts {
+            data: Duration::from_millis(100),
+            ..Timeouts::default()
+        })
+        .await;
+
+        next_line(&mut client).await.unwrap();
+        client.send("HELO localhost".to_string()).await.unwrap();
+        next_line(&mut client).await.unwrap();
+        client
+            .send("MAIL FROM:<sender@example.com>".to_string())
+            .await
+            .unwrap();
+        next_line(&mut client).await.unwrap();
+        client
+            .send("RCPT TO:<recipient@example.com>".to_string())
+            .await
+            .unwrap();
+        next_line(&mut client).await.unwrap();
+        client.send("DATA".to_string()).await.unwrap();
+        assert!(next_line(&mut client).await.unwrap().starts_with("354"));
+        client.send("Subject: stalled".to_string()).await.unwrap();
+
+        assert_eq!(
+            next_line(&mut client).await.unwrap(),
+            "421 4.4.2 Idle timeout exceeded"
+        );
+        assert!(next_line(&mut client).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn closes_connection_after_session_timeout() {
+        let mut client = spawn_connection(Timeouts {
+            session: Duration::from_millis(300),
+            ..Timeouts::default()
+        })
+        .await;
+
+        next_line(&mut client).await.unwrap();
+        // Keep the connection busy so only the total session limit can expire
+        loop {
+            if client.send("NOOP".to_string()).await.is_err() {
+                break;
+            }
+            let line = next_line(&mut client).await;
+            match line.as_deref() {
+                Some("250 OK") => tokio::time::sleep(Duration::from_millis(50)).await,
+                Some(line) => {
+                    assert_eq!(line, "421 4.4.2 Session time limit exceeded");
+                    break;
+                }
+                None => panic!("connection closed without 421"),
+            }
+        }
+    }
+
+    async fn start_data(client: &mut Framed<TcpStream, LinesCodec>) {
+        next_line(client).await.unwrap();
+        for command in [
+            "HELO localhost",
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<recipient@example.com>",
+            "DATA",
+        ] {
+            client.send(command.to_string()).await.unwrap();
+            next_line(client).await.unwrap();
+        }
+    }
+
+    async fn deliver(session: &mut Session) -> Option<String> {
+        session
+            .deliver_pending(Timeouts::default().delivery, &CancellationToken::new())
+            .await
+    }
+
+    /// Callback that never finishes storing a message
+    fn hanging_callback() -> OnReceiveCallback {
+        Arc::new(|_message: Email| futures::future::pending().boxed())
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_storage_after_delivery_timeout() {
+        let shutdown = Shutdown {
+            stop: CancellationToken::new(),
+            abort: CancellationToken::new(),
+        };
+        let timeouts = Timeouts {
+            delivery: Duration::from_millis(100),
+            ..Timeouts::default()
+        };
+        let mut client =
+            spawn_connection_with_callback(timeouts, shutdown, Some(hanging_callback())).await;
+        start_data(&mut client).await;
+
+        client.send("Subject: Hi".to_string()).await.unwrap();
+        client.send(".".to_string()).await.unwrap();
+        assert_eq!(next_line(&mut client).await.unwrap(), DELIVERY_FAILED_REPLY);
+
+        // The session stays usable
+        client.send("NOOP".to_string()).await.unwrap();
+        assert_eq!(next_line(&mut client).await.unwrap(), "250 OK");
+    }
+
+    #[tokio::test]
+    async fn shutdown_abort_stops_waiting_for_storage() {
+        let shutdown = Shutdown {
+            stop: CancellationToken::new(),
+            abort: CancellationToken::new(),
+        };
+        let mut client = spawn_connection_with_callback(
+            Timeouts::default(),
+            shutdown.clone(),
+            Some(hanging_callback()),
+        )
+        .await;
+        start_data(&mut client).await;
+
+        client.send("Subject: Hi".to_string()).await.unwrap();
+        client.send(".".to_string()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.stop.cancel();
+        shutdown.abort.cancel();
+
+        assert_eq!(
+            next_line(&mut client).await.unwrap(),
+            "421 4.3.2 Service shutting down"
+        );
+        assert!(next_line(&mut client).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn waits_for_storage_beyond_session_timeout() {
+        let stored = Arc::new(std::sync::Mutex::new(0));
+        let callback: OnReceiveCallback = {
+            let stored = stored.clone();
+            Arc::new(move |_message: Email| {
+                let stored = stored.clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    *stored.lock().unwrap() += 1;
+                    Ok(())
+                }
+                .boxed()
+            })
+        };
+        let shutdown = Shutdown {
+            stop: CancellationToken::new(),
+            abort: CancellationToken::new(),
+        };
+        let timeouts = Timeouts {
+            session: Duration::from_millis(300),
+            ..Timeouts::default()
+        };
+        let mut client = spawn_connection_with_callback(timeouts, shutdown, Some(callback)).await;
+        start_data(&mut client).await;
+
+        client.send("Subject: Hi".to_string()).await.unwrap();
+        client.send(".".to_string()).await.unwrap();
+
+        // The message is committed after the session limit, so it must be confirmed
+        assert_eq!(next_line(&mut client).await.unwrap(), "250 OK");
+        assert_eq!(*stored.lock().unwrap(), 1);
+        assert_eq!(
+            next_line(&mut client).await.unwrap(),
+            "421 4.4.2 Session time limit exceeded"
+        );
+        assert!(next_line(&mut client).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_idle_session() {
+        let shutdown = Shutdown {
+            stop: CancellationToken::new(),
+            abort: CancellationToken::new(),
+        };
+        let mut client =
+            spawn_connection_with_shutdown(Timeouts::default(), shutdown.clone()).await;
+        assert_eq!(
+            next_line(&mut client).await.unwrap(),
+            "220 mailfang SMTP ready"
+        );
+
+        shutdown.stop.cancel();
+
+        assert_eq!(
+            next_line(&mut client).await.unwrap(),
+            "421 4.3.2 Service shutting down"
+        );
+        assert!(next_line(&mut client).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_data_transfer_finish() {
+        let shutdown = Shutdown {
+            stop: CancellationToken::new(),
+            abort: CancellationToken::new(),
+        };
+        let mut client =
+            spawn_connection_with_shutdown(Timeouts::default(), shutdown.clone()).await;
+        start_data(&mut client).await;
+
+        shutdown.stop.cancel();
+
+        client.send("Subject: Hi".to_string()).await.unwrap();
+        client.send("".to_string()).await.unwrap();
+        client.send("Body".to_string()).await.unwrap();
+        client.send(".".to_string()).await.unwrap();
+        assert_eq!(next_line(&mut client).await.unwrap(), "250 OK");
+        assert_eq!(
+            next_line(&mut client).await.unwrap(),
+            "421 4.3.2 Service shutting down"
+        );
+        assert!(next_line(&mut client).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_abort_closes_data_transfer() {
+        let shutdown = Shutdown {
+            stop: CancellationToken::new(),
+            abort: CancellationToken::new(),
+        };
+        let mut client =
+            spawn_connection_with_shutdown(Timeouts::default(), shutdown.clone()).await;
+        start_data(&mut client).await;
+
+        shutdown.stop.cancel();
+        shutdown.abort.cancel();
+
+        assert_eq!(
+            next_line(&mut client).await.unwrap(),
+            "421 4.3.2 Service shutting down"
+        );
+        assert!(next_line(&mut client).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn run_returns_after_shutdown() {
+        let addr = {
+            let probe = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            probe.local_addr().unwrap()
+        };
+        let server = SmtpServer::new(addr).max_connections(1);
+        let shutdown = CancellationToken::new();
+        let handle = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { server.run(shutdown).await }
+        });
+
+        shutdown.cancel();
+
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("server did not stop")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn replies_after_message_was_stored() {
+        let peer: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let stored = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let callback: OnReceiveCallback = {
+            let stored = stored.clone();
+            Arc::new(move |message: Email| {
+                stored.lock().unwrap().push(message);
+                async { Ok(()) }.boxed()
+            })
+        };
+        let mut session = Session::new(Some(callback), None, None, peer);
+        session.process_line("EHLO localhost").unwrap();
+        session
+            .process_line("MAIL FROM:<sender@example.com>")
+            .unwrap();
+        session
+            .process_line("RCPT TO:<recipient@example.com>")
+            .unwrap();
+        session.process_line("DATA").unwrap();
+        session.process_line("Subject: Hi").unwrap();
+
+        assert!(session.process_line(".").unwrap().is_empty());
+        assert!(stored.lock().unwrap().is_empty());
+
+        assert_eq!(deliver(&mut session).await.as_deref(), Some("250 OK"));
+        assert_eq!(stored.lock().unwrap().len(), 1);
+        assert!(deliver(&mut session).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn replies_with_transient_error_when_storing_fails() {
+        let peer: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let callback: OnReceiveCallback =
+            Arc::new(|_message: Email| async { Err("database is locked".to_string()) }.boxed());
+        let mut session = Session::new(Some(callback), None, None, peer);
+        session.process_line("EHLO localhost").unwrap();
+        session
+            .process_line("MAIL FROM:<sender@example.com>")
+            .unwrap();
+        session
+            .process_line("RCPT TO:<recipient@example.com>")
+            .unwrap();
+        session.process_line("DATA").unwrap();
+        session.process_line(".").unwrap();
+
+        assert_eq!(
+            deliver(&mut session).await.as_deref(),
+            Some(DELIVERY_FAILED_REPLY)
+        );
+    }
 }
@@ -0,0 +1,428 @@
+use crate::{db, smtp, web};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+/// Attempts per message before the save is reported as failed
+const MAX_ATTEMPTS: u32 = 4;
+/// Maximum number of queued emails written in one transaction
+const MAX_BATCH_SIZE: usize = 64;
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum StorageError {
+    Closed,
+    Save(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Closed => write!(f, "storage queue closed"),
+            StorageError::Save(e) => write!(f, "failed to save email: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// Why a save failed, and whether trying again could help
+#[derive(Debug)]
+struct SaveError {
+    message: String,
+    transient: bool,
+}
+
+impl SaveError {
+    fn permanent(message: impl ToString) -> Self {
+        Self {
+            message: message.to_string(),
+            transient: false,
+        }
+    }
+
+    fn transient(message: impl ToString) -> Self {
+        Self {
+            message: message.to_string(),
+            transient: true,
+        }
+    }
+}
+
+impl From<db::DbError> for SaveError {
+    fn from(err: db::DbError) -> Self {
+        if is_transient(&err) {
+            Self::transient(err)
+        } else {
+            Self::permanent(err)
+        }
+    }
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// SQLITE_BUSY and SQLITE_LOCKED clear up once the other writer is done, constraint violations
+/// and invalid data fail the same way on every attempt
+fn is_transient(err: &db::DbError) -> bool {
+    match err {
+        db::DbError::Diesel(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::Unknown,
+            info,
+        )) => {
+            let message = info.message();
+            message.starts_with("database is locked")
+                || message.starts_with("database table is locked")
+        }
+        _ => false,
+    }
+}
+
+type SaveReply = oneshot::Sender<Result<(), StorageError>>;
+
+enum Job {
+    Save {
+        message: Arc<smtp::Email>,
+        done: SaveReply,
+    },
+    Flush {
+        done: oneshot::Sender<()>,
+    },
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub capacity: usize,
+}
+
+/// Bounded queue between SMTP acceptance and the database.
+///
+/// A single worker saves messages in arrival order, writing everything that queued up while the
+/// previous transaction ran in one batch, and retries failed saves. `store` resolves only
+/// once the message is committed, so the SMTP session can answer `250` after a durable write.
+/// When the queue is full, `store` waits for a free slot, which slows down the sending clients.
+#[derive(Clone)]
+pub struct StorageQueue {
+    sender: mpsc::Sender<Job>,
+    depth: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+impl StorageQueue {
+    pub fn start(pool: db::DbPool, broadcast: web::ws::BroadcastSender, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        let (sender, receiver) = mpsc::channel(capacity);
+        let depth = Arc::new(AtomicUsize::new(0));
+
+        tokio::spawn(run_worker(receiver, pool, broadcast, depth.clone()));
+
+        Self {
+            sender,
+            depth,
+            capacity,
+        }
+    }
+
+    /// Queue a message and wait until it has been committed to the database
+    pub async fn store(&self, message: smtp::Email) -> Result<(), StorageError> {
+        let (done, committed) = oneshot::channel();
+
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        if self
+            .sender
+            .send(Job::Save {
+                message: Arc::new(message),
+                done,
+            })
+            .await
+            .is_err()
+        {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+            return Err(StorageError::Closed);
+        }
+
+        committed.await.unwrap_or(Err(StorageError::Closed))
+    }
+
+    /// Wait until every message queued before this call has been processed
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.sender.send(Job::Flush { done }).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.depth.load(Ordering::Relaxed),
+            capacity: self.capacity,
+        }
+    }
+}
+
+async fn run_worker(
+    mut receiver: mpsc::Receiver<Job>,
+    pool: db::DbPool,
+    broadcast: web::ws::BroadcastSender,
+    depth: Arc<AtomicUsize>,
+) {
+    while let Some(job) = receiver.recv().await {
+        let mut saves = Vec::new();
+        let mut flushes = Vec::new();
+        let mut next_job = Some(job);
+
+        // Everything that is already waiting goes into the same transaction
+        while let Some(job) = next_job {
+            match job {
+                Job::Save { message, done } => saves.push((message, done)),
+                Job::Flush { done } => flushes.push(done),
+            }
+            next_job = if saves.len() < MAX_BATCH_SIZE {
+                receiver.try_recv().ok()
+            } else {
+                None
+            };
+        }
+
+        let saved = saves.len();
+        save_batch(&pool, &broadcast, saves).await;
+        depth.fetch_sub(saved, Ordering::Relaxed);
+
+        for done in flushes {
+            let _ = done.send(());
+        }
+    }
+}
+
+async fn save_batch(
+    pool: &db::DbPool,
+    broadcast: &web::ws::BroadcastSender,
+    batch: Vec<(Arc<smtp::Email>, SaveReply)>,
+) {
+    if batch.len() > 1 {
+        let messages = batch.iter().map(|(message, _)| message.clone()).collect();
+        match save(pool, messages).await {
+            Ok(records) => {
+                let email_ids = records.iter().map(|record| record.id.clone()).collect();
+                for (record, (_, done)) in records.into_iter().zip(batch) {
+                    broadcast_new_email(broadcast, record);
+                    // The session may have given up waiting; the email is stored either way
+                    let _ = done.send(Ok(()));
+                }
+                broadcast_saved_search_counts(pool, broadcast, email_ids).await;
+                return;
+            }
+            Err(e) => {
+                warn!(
+                    component = "storage",
+                    "Failed to save batch of {} emails, saving them one by one: {}",
+                    batch.len(),
+                    e
+                );
+            }
+        }
+    }
+
+    // A single broken email must not take the rest of the batch down with it
+    let mut email_ids = Vec::new();
+    for (message, done) in batch {
+        let result = save_with_retry(pool, broadcast, message).await;
+        if let Ok(ids) = &result {
+            email_ids.extend(ids.iter().cloned());
+        }
+        let _ = done.send(result.map(|_| ()));
+    }
+    broadcast_saved_search_counts(pool, broadcast, email_ids).await;
+}
+
+async fn save_with_retry(
+    pool: &db::DbPool,
+    broadcast: &web::ws::BroadcastSender,
+    message: Arc<smtp::Email>,
+) -> Result<Vec<String>, StorageError> {
+    let mut attempt = 1;
+    loop {
+        match save(pool, vec![message.clone()]).await {
+            Ok(records) => {
+                let email_ids = records.iter().map(|record| record.id.clone()).collect();
+                for record in records {
+                    broadcast_new_email(broadcast, record);
+                }
+                return Ok(email_ids);
+            }
+            Err(e) if e.transient && attempt < MAX_ATTEMPTS => {
+                warn!(
+                    component = "storage",
+                    attempt, "Failed to save email, retrying: {}", e
+                );
+                tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+                attempt += 1;
+            }
+            // Logged by the SMTP session that answers the client with 451
+            Err(e) => return Err(StorageError::Save(e.to_string())),
+        }
+    }
+}
+
+async fn save(
+    pool: &db::DbPool,
+    messages: Vec<Arc<smtp::Email>>,
+) -> Result<Vec<db::EmailListRecord>, SaveError> {
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        // The pool times out while all connections are busy
+        let mut conn = pool.get().map_err(SaveError::transient)?;
+        let messages: Vec<&smtp::Email> = messages.iter().map(|m| m.as_ref()).collect();
+        Ok(db::save_email::save_emails(&mut conn, &messages)?)
+    })
+    .await
+    .map_err(|e| SaveError::permanent(format!("Failed to spawn blocking task: {}", e)))?
+}
+
+fn broadcast_new_email(broadcast: &web::ws::BroadcastSender, record: db::EmailListRecord) {
+    broadcast
+        .send(web::ws::WebSocketMessage::new_mail(record))
+        .ok();
+}
+
+/// Sends the new counts of the saved searches the stored emails show up in. The emails are
+/// already saved, a failure only costs the live update.
+async fn broadcast_saved_search_counts(
+    pool: &db::DbPool,
+    broadcast: &web::ws::BroadcastSender,
+    email_ids: Vec<String>,
+) {
+    if email_ids.is_empty() {
+        return;
+    }
+
+    let pool = pool.clone();
+    let stats = tokio::task::spawn_blocking(move || {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        db::saved_search::saved_search_stats_matching(&mut conn, &email_ids)
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| format!("Failed to spawn blocking task: {}", e))
+    .and_then(|result| result);
+
+    match stats {
+        Ok(stats) if !stats.is_empty() => {
+            broadcast
+                .send(web::ws::WebSocketMessage::saved_search_counts(stats))
+                .ok();
+        }
+        Ok(_) => {}
+        Err(e) => {
+            warn!(
+                component = "storage",
+                "Failed to count saved searches: {}", e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+    use diesel::prelude::*;
+
+    #[tokio::test]
+    async fn saves_one_by_one_when_the_batch_fails() {
+        let pool = testing::pool();
+        let (broadcast, mut events) = tokio::sync::broadcast::channel(16);
+
+        let first = testing::email("First", &["a@x.com"]);
+        // Same id as the first email, so the batch transaction and this email's own save fail
+        let mut duplicate = testing::email("Duplicate", &["a@x.com"]);
+        duplicate.id = first.id;
+        let third = testing::email("Third", &["a@x.com", "b@x.com"]);
+
+        let mut batch = Vec::new();
+        let mut replies = Vec::new();
+        for message in [first, duplicate, third] {
+            let (done, reply) = oneshot::channel();
+            batch.push((Arc::new(message), done));
+            replies.push(reply);
+        }
+        save_batch(&pool, &broadcast, batch).await;
+
+        let results: Vec<bool> = futures::future::join_all(replies)
+            .await
+            .into_iter()
+            .map(|reply| reply.unwrap().is_ok())
+            .collect();
+        assert_eq!(results, vec![true, false, true]);
+
+        let mut subjects = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            subjects.push(event.email.and_then(|email| email.subject));
+        }
+        assert_eq!(
+            subjects,
+            vec![Some("First".to_string()), Some("Third".to_string())]
+        );
+
+        let mut conn = pool.get().unwrap();
+        let counts: Vec<(String, i32)> = crate::schema::envelope_recipients::table
+            .select((
+                crate::schema::envelope_recipients::email,
+                crate::schema::envelope_recipients::email_count,
+            ))
+            .order(crate::schema::envelope_recipients::email.asc())
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(
+            counts,
+            vec![("a@x.com".to_string(), 2), ("b@x.com".to_string(), 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_errors() {
+        let pool = testing::pool();
+        let (broadcast, _events) = tokio::sync::broadcast::channel(16);
+        let message = Arc::new(testing::email("Hi", &["a@x.com"]));
+        save_with_retry(&pool, &broadcast, message.clone())
+            .await
+            .unwrap();
+
+        // Saving the same id again violates the primary key, waiting would not change that
+        let started = std::time::Instant::now();
+        assert!(save_with_retry(&pool, &broadcast, message).await.is_err());
+        assert!(started.elapsed() < RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn only_busy_and_locked_databases_are_transient() {
+        let error = |kind, message: &str| {
+            db::DbError::Diesel(diesel::result::Error::DatabaseError(
+                kind,
+                Box::new(message.to_string()),
+            ))
+        };
+        assert!(is_transient(&error(
+            diesel::result::DatabaseErrorKind::Unknown,
+            "database is locked"
+        )));
+        assert!(is_transient(&error(
+            diesel::result::DatabaseErrorKind::Unknown,
+            "database table is locked: emails"
+        )));
+        assert!(!is_transient(&error(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            "UNIQUE constraint failed: emails.id"
+        )));
+        assert!(!is_transient(&db::DbError::Io(std::io::Error::other(
+            "invalid data"
+        ))));
+    }
+}
@@ -10,6 +10,7 @@ pub type DieselError = diesel::result::Error;
 pub enum WebError {
     Database(String),
     NotFound,
+    BadRequest(String),
     Io(std::io::Error),
 }
 
@@ -21,6 +22,7 @@ impl IntoResponse for WebError {
                 "An internal error occurred".to_string(),
             ),
             WebError::NotFound => (StatusCode::NOT_FOUND, "Not found".to_string()),
+            WebError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
             WebError::Io(_) => (StatusCode::INTERNAL_SERVER_ERROR, "IO error".to_string()),
         };
         (status, message).into_response()
@@ -40,6 +42,18 @@ impl From<std::io::Error> for WebError {
     }
 }
 
+impl From<crate::db::ListParamsError> for WebError {
+    fn from(err: crate::db::ListParamsError) -> Self {
+        WebError::BadRequest(err.to_string())
+    }
+}
+
+impl From<crate::db::search_query::SearchParseError> for WebError {
+    fn from(err: crate::db::search_query::SearchParseError) -> Self {
+        WebError::BadRequest(err.to_string())
+    }
+}
+
 impl From<r2d2::Error> for WebError {
     fn from(err: r2d2::Error) -> Self {
         WebError::Database(err.to_string())
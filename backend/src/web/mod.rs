@@ -3,8 +3,14 @@ mod frontend;
 pub mod routes;
 pub mod ws;
 
-use crate::db::{DbPool, ListQuery};
-use axum::{Router, http::StatusCode, routing::get};
+use crate::db::{DbPool, ListQuery, emails::EmailPage};
+use crate::maintenance::Maintenance;
+use crate::storage::{QueueStats, StorageQueue};
+use axum::{
+    Router,
+    http::StatusCode,
+    routing::{delete, get, post},
+};
 use serde::Deserialize;
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
@@ -17,6 +23,8 @@ use ws::BroadcastSender;
 pub struct AppState {
     pool: DbPool,
     broadcast: BroadcastSender,
+    storage_queue: StorageQueue,
+    maintenance: Maintenance,
 }
 
 #[derive(Deserialize)]
@@ -24,19 +32,75 @@ pub struct RenderedQueryParams {
     allow_remote_content: Option<bool>,
 }
 
+#[derive(Deserialize)]
+pub struct PartQueryParams {
+    /// Set to true to download the part as received, headers and transfer encoding included
+    raw: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct GetEmailParams {
+    /// Set to false to fetch the email without marking it as read
+    mark_read: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ThreadListParams {
+    search: Option<String>,
+    page: Option<u64>,
+    per_page: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct SavedSearchRequest {
+    name: String,
+    query: String,
+}
+
+#[derive(Deserialize)]
+pub struct TagsRequest {
+    tags: Vec<String>,
+}
+
+/// Selects emails by `ids` or by `search`, exactly one of them
+#[derive(Deserialize)]
+pub struct BulkRequest {
+    ids: Option<Vec<String>>,
+    search: Option<String>,
+    #[serde(flatten)]
+    action: crate::db::bulk::BulkAction,
+}
+
+#[derive(serde::Serialize)]
+pub struct BulkResponse {
+    affected: usize,
+}
+
+#[derive(serde::Serialize)]
+pub struct ClearedInboxResponse {
+    deleted: usize,
+    unlinked: usize,
+}
+
 #[derive(serde::Serialize)]
 struct PaginationInfo {
     page: u64,
     per_page: u64,
-    total_pages: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_pages: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_count: Option<u64>,
+    next_cursor: Option<String>,
 }
 
 impl PaginationInfo {
-    fn from_query(query: &ListQuery, total_pages: u64) -> Self {
+    fn from_page(query: &ListQuery, page: &EmailPage) -> Self {
         Self {
             page: query.page,
             per_page: query.per_page,
-            total_pages,
+            total_pages: page.total_pages,
+            total_count: page.total_count,
+            next_cursor: page.next_cursor.clone(),
         }
     }
 }
@@ -48,30 +112,98 @@ pub struct EmailListResponse {
     pagination: PaginationInfo,
 }
 
+#[derive(serde::Serialize)]
+pub struct ThreadListResponse {
+    threads: Vec<crate::db::thread::ThreadSummary>,
+    pagination: PaginationInfo,
+}
+
+#[derive(serde::Serialize)]
+pub struct HealthResponse {
+    status: &'static str,
+    storage_queue: QueueStats,
+}
+
 pub async fn run(
     addr: SocketAddr,
     pool: DbPool,
     broadcast: BroadcastSender,
+    storage_queue: StorageQueue,
+    maintenance: Maintenance,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let app_state = AppState { pool, broadcast };
+    let app_state = AppState {
+        pool,
+        broadcast,
+        storage_queue,
+        maintenance,
+    };
 
     let app = Router::new()
-        .route("/health", get(|| async { StatusCode::OK }))
+        .route("/health", get(routes::get_health))
         .route("/api/counts", get(routes::get_counts))
+        .route("/api/maintenance", post(routes::run_maintenance))
         .route(
             "/api/emails",
             get(routes::get_emails).delete(routes::delete_emails),
         )
+        .route("/api/emails/bulk", post(routes::bulk_action))
         .route(
             "/api/emails/inbox/{recipient}",
-            get(routes::get_emails_by_recipient),
+            get(routes::get_emails_by_recipient).delete(routes::clear_recipient_inbox),
+        )
+        .route(
+            "/api/emails/by-message-id/{message_id}",
+            get(routes::get_email_by_message_id),
         )
         .route(
             "/api/emails/{id}",
-            get(routes::get_email).delete(routes::delete_email),
+            get(routes::get_email)
+                .patch(routes::update_email)
+                .delete(routes::delete_email),
+        )
+        .route("/api/emails/{id}/tags", post(routes::add_email_tags))
+        .route(
+            "/api/emails/{id}/tags/{tag}",
+            delete(routes::remove_email_tag),
         )
         .route("/api/emails/{id}/raw", get(routes::get_raw_email))
         .route("/api/emails/{id}/rendered", get(routes::get_rendered_email))
+        .route(
+            "/api/emails/{id}/structure",
+            get(routes::get_email_structure),
+        )
+        .route("/api/emails/{id}/parts/{path}", get(routes::get_email_part))
+        .route("/api/emails/{id}/lint", get(routes::get_email_lint))
+        .route(
+            "/api/emails/{id}/messages",
+            get(routes::get_embedded_emails),
+        )
+        .route(
+            "/api/emails/{id}/messages/{path}",
+            get(routes::get_embedded_email),
+        )
+        .route(
+            "/api/emails/{id}/messages/{path}/rendered",
+            get(routes::get_rendered_embedded_email),
+        )
+        .route(
+            "/api/saved-searches",
+            get(routes::get_saved_searches).post(routes::create_saved_search),
+        )
+        .route(
+            "/api/saved-searches/{id}",
+            get(routes::get_saved_search)
+                .put(routes::update_saved_search)
+                .delete(routes::delete_saved_search),
+        )
+        .route(
+            "/api/saved-searches/{id}/emails",
+            get(routes::get_saved_search_emails),
+        )
+        .route("/api/threads", get(routes::get_threads))
+        .route("/api/threads/{id}", get(routes::get_thread))
+        .route("/api/tags", get(routes::get_tags))
+        .route("/api/tags/{name}", delete(routes::delete_tag))
         .route("/api/attachments/{id}", get(routes::get_attachment))
         .route("/ws", get(ws::websocket_handler))
         .layer(TimeoutLayer::with_status_code(
@@ -3,10 +3,17 @@ use std::env;
 
 use crate::csp::inject_csp_meta_tag;
 use crate::db::{ListParams, ListQuery};
-use crate::html::normalize_html_document;
+use crate::html::{self, normalize_html_document};
+use crate::lint::LintFinding;
+use crate::maintenance::MaintenanceReport;
+use crate::mime::{self, EmbeddedEmail, MimePart};
 use crate::web::error::WebError;
-use crate::web::ws::{WebSocketEvent, WebSocketMessage};
-use crate::web::{EmailListResponse, RenderedQueryParams};
+use crate::web::ws::WebSocketMessage;
+use crate::web::{
+    BulkRequest, BulkResponse, ClearedInboxResponse, EmailListResponse, GetEmailParams,
+    HealthResponse, PartQueryParams, RenderedQueryParams, SavedSearchRequest, TagsRequest,
+    ThreadListParams, ThreadListResponse,
+};
 use axum::{
     extract::{Path, Query},
     http::{HeaderMap, HeaderValue, StatusCode},
@@ -16,6 +23,13 @@ use axum::{
 use crate::db;
 use crate::web::AppState;
 
+pub async fn get_health(State(state): State<AppState>) -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "ok",
+        storage_queue: state.storage_queue.stats(),
+    })
+}
+
 pub async fn get_counts(
     State(state): State<AppState>,
 ) -> Result<Json<db::counts::EmailStats>, WebError> {
@@ -28,17 +42,17 @@ pub async fn get_emails(
     State(state): State<AppState>,
     Query(params): Query<ListParams>,
 ) -> Result<Json<EmailListResponse>, WebError> {
-    let query: ListQuery = params.into();
+    let query = ListQuery::try_from(params)?;
 
     let mut conn = state.pool.get()?;
-    let (emails, total_pages) = db::emails::get_emails(&mut conn, &query)?;
+    let page = db::emails::get_emails(&mut conn, &query)?;
 
     let counts = db::counts::get_email_counts(&mut conn)?;
 
     Ok(Json(EmailListResponse {
-        emails,
+        pagination: super::PaginationInfo::from_page(&query, &page),
+        emails: page.emails,
         counts,
-        pagination: super::PaginationInfo::from_query(&query, total_pages),
     }))
 }
 
@@ -47,46 +61,362 @@ pub async fn get_emails_by_recipient(
     Path(recipient): Path<String>,
     Query(params): Query<ListParams>,
 ) -> Result<Json<EmailListResponse>, WebError> {
-    let query: ListQuery = params.into();
+    let query = ListQuery::try_from(params)?;
 
     let mut conn = state.pool.get()?;
     if !db::emails::envelope_recipient_exists(&mut conn, &recipient)? {
         return Err(WebError::NotFound);
     }
-    let (emails, total_pages) = db::emails::get_emails_by_recipient(&mut conn, &recipient, &query)?;
+    let page = db::emails::get_emails_by_recipient(&mut conn, &recipient, &query)?;
 
     let counts = db::counts::get_email_counts(&mut conn)?;
 
     Ok(Json(EmailListResponse {
-        emails,
+        pagination: super::PaginationInfo::from_page(&query, &page),
+        emails: page.emails,
         counts,
-        pagination: super::PaginationInfo::from_query(&query, total_pages),
     }))
 }
 
+/// Empties one recipient's inbox without touching emails that other recipients still have
+pub async fn clear_recipient_inbox(
+    State(state): State<AppState>,
+    Path(recipient): Path<String>,
+) -> Result<Json<ClearedInboxResponse>, WebError> {
+    if demo_mode() {
+        return Ok(Json(ClearedInboxResponse {
+            deleted: 0,
+            unlinked: 0,
+        }));
+    }
+
+    let mut conn = state.pool.get()?;
+    let cleared = db::emails::clear_recipient_inbox(&mut conn, &recipient).map_err(not_found)?;
+    let response = ClearedInboxResponse {
+        deleted: cleared.deleted.len(),
+        unlinked: cleared.unlinked.len(),
+    };
+
+    if !cleared.deleted.is_empty() {
+        state
+            .broadcast
+            .send(WebSocketMessage::emails_deleted(cleared.deleted))
+            .ok();
+    }
+    if !cleared.unlinked.is_empty() {
+        state
+            .broadcast
+            .send(WebSocketMessage::inbox_cleared(
+                cleared.unlinked,
+                vec![recipient],
+            ))
+            .ok();
+    }
+
+    Ok(Json(response))
+}
+
+pub async fn get_saved_searches(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<crate::models::SavedSearch>>, WebError> {
+    let mut conn = state.pool.get()?;
+    let saved_searches = db::saved_search::list_saved_searches(&mut conn)?;
+    Ok(Json(saved_searches))
+}
+
+pub async fn get_saved_search(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<crate::models::SavedSearch>, WebError> {
+    let mut conn = state.pool.get()?;
+    let saved_search = db::saved_search::get_saved_search(&mut conn, &id).map_err(not_found)?;
+    Ok(Json(saved_search))
+}
+
+pub async fn create_saved_search(
+    State(state): State<AppState>,
+    Json(request): Json<SavedSearchRequest>,
+) -> Result<(StatusCode, Json<crate::models::SavedSearch>), WebError> {
+    let (name, query) = validate_saved_search(&request)?;
+
+    let mut conn = state.pool.get()?;
+    let saved_search = db::saved_search::create_saved_search(&mut conn, name, query)
+        .map_err(|e| duplicate_name(e, name))?;
+    Ok((StatusCode::CREATED, Json(saved_search)))
+}
+
+pub async fn update_saved_search(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(request): Json<SavedSearchRequest>,
+) -> Result<Json<crate::models::SavedSearch>, WebError> {
+    let (name, query) = validate_saved_search(&request)?;
+
+    let mut conn = state.pool.get()?;
+    let saved_search = db::saved_search::update_saved_search(&mut conn, &id, name, query)
+        .map_err(|e| duplicate_name(e, name))?;
+    Ok(Json(saved_search))
+}
+
+pub async fn delete_saved_search(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, WebError> {
+    let mut conn = state.pool.get()?;
+    if db::saved_search::delete_saved_search(&mut conn, &id)? > 0 {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(WebError::NotFound)
+    }
+}
+
+/// Lists the emails matching the saved query, `search` narrows them down further
+pub async fn get_saved_search_emails(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<EmailListResponse>, WebError> {
+    let mut conn = state.pool.get()?;
+    let saved_search = db::saved_search::get_saved_search(&mut conn, &id).map_err(not_found)?;
+    let base = db::search_query::parse_search_query(&saved_search.query)?;
+    let query = ListQuery::within(base, params)?;
+
+    let page = db::emails::get_emails(&mut conn, &query)?;
+
+    let counts = db::counts::get_email_counts(&mut conn)?;
+
+    Ok(Json(EmailListResponse {
+        pagination: super::PaginationInfo::from_page(&query, &page),
+        emails: page.emails,
+        counts,
+    }))
+}
+
+/// Trimmed name and query, the query has to parse so the view can always be listed
+fn validate_saved_search(request: &SavedSearchRequest) -> Result<(&str, &str), WebError> {
+    let name = request.name.trim();
+    let query = request.query.trim();
+    if name.is_empty() {
+        return Err(WebError::BadRequest(
+            "A saved search needs a name".to_string(),
+        ));
+    }
+    if query.is_empty() {
+        return Err(WebError::BadRequest(
+            "A saved search needs a query".to_string(),
+        ));
+    }
+    db::search_query::parse_search_query(query)?;
+    Ok((name, query))
+}
+
+fn duplicate_name(err: diesel::result::Error, name: &str) -> WebError {
+    match err {
+        diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        ) => WebError::BadRequest(format!("A saved search named {} already exists", name)),
+        diesel::result::Error::NotFound => WebError::NotFound,
+        e => WebError::from(e),
+    }
+}
+
+/// Threads with an email matching `search`, the most recently active first
+pub async fn get_threads(
+    State(state): State<AppState>,
+    Query(params): Query<ThreadListParams>,
+) -> Result<Json<ThreadListResponse>, WebError> {
+    let search = match params.search.as_deref() {
+        Some(search) => db::search_query::parse_search_query(search)?,
+        None => db::search_query::SearchQuery::default(),
+    };
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params.per_page.unwrap_or(20).clamp(1, 100);
+
+    let mut conn = state.pool.get()?;
+    let threads = db::thread::list_threads(&mut conn, search.expr.as_ref(), page, per_page)?;
+
+    Ok(Json(ThreadListResponse {
+        threads: threads.threads,
+        pagination: super::PaginationInfo {
+            page,
+            per_page,
+            total_pages: Some(threads.total_pages),
+            total_count: Some(threads.total_count),
+            next_cursor: None,
+        },
+    }))
+}
+
+pub async fn get_thread(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<db::thread::ThreadRecord>, WebError> {
+    let mut conn = state.pool.get()?;
+    let thread = db::thread::get_thread(&mut conn, &id).map_err(not_found)?;
+    Ok(Json(thread))
+}
+
+pub async fn get_tags(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<db::tag::TagStats>>, WebError> {
+    let mut conn = state.pool.get()?;
+    let tags = db::tag::list_tags(&mut conn)?;
+    Ok(Json(tags))
+}
+
+/// Removes the tag from all emails and forgets it
+pub async fn delete_tag(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, WebError> {
+    if demo_mode() {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    let mut conn = state.pool.get()?;
+    let email_ids = db::tag::delete_tag(&mut conn, &name).map_err(not_found)?;
+    broadcast_tags(&state, &mut conn, &email_ids)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn add_email_tags(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(request): Json<TagsRequest>,
+) -> Result<Json<db::tag::EmailTags>, WebError> {
+    let tags = tag_names(&request.tags)?;
+    if tags.is_empty() {
+        return Err(WebError::BadRequest("No tags given".to_string()));
+    }
+
+    let mut conn = state.pool.get()?;
+    let email_ids = db::tag::add_tags(&mut conn, std::slice::from_ref(&id), &tags)?;
+    broadcast_tags(&state, &mut conn, &email_ids)?
+        .pop()
+        .map(Json)
+        .ok_or(WebError::NotFound)
+}
+
+pub async fn remove_email_tag(
+    State(state): State<AppState>,
+    Path((id, tag)): Path<(String, String)>,
+) -> Result<StatusCode, WebError> {
+    let mut conn = state.pool.get()?;
+    let email_ids = db::tag::remove_tags(&mut conn, std::slice::from_ref(&id), &[tag.as_str()])?;
+    if email_ids.is_empty() {
+        return Err(WebError::NotFound);
+    }
+    broadcast_tags(&state, &mut conn, &email_ids)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn tag_names(names: &[String]) -> Result<Vec<&str>, WebError> {
+    names
+        .iter()
+        .map(|name| {
+            db::tag::parse_tag_name(name).ok_or_else(|| {
+                WebError::BadRequest(format!(
+                    "Invalid tag {:?}, tags need 1 to {} characters",
+                    name,
+                    db::tag::MAX_TAG_LENGTH
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Sends the current tags of the emails to all clients and returns them
+fn broadcast_tags(
+    state: &AppState,
+    conn: &mut db::DbConnection,
+    email_ids: &[String],
+) -> Result<Vec<db::tag::EmailTags>, WebError> {
+    let email_tags = db::tag::email_tags(conn, email_ids)?;
+    if !email_tags.is_empty() {
+        state
+            .broadcast
+            .send(WebSocketMessage::tags_changed(email_tags.clone()))
+            .ok();
+    }
+    Ok(email_tags)
+}
+
+fn not_found(err: diesel::result::Error) -> WebError {
+    match err {
+        diesel::result::Error::NotFound => WebError::NotFound,
+        e => WebError::from(e),
+    }
+}
+
 pub async fn get_email(
     State(state): State<AppState>,
     Path(id): Path<String>,
+    Query(params): Query<GetEmailParams>,
 ) -> Result<Json<crate::db::EmailRecord>, WebError> {
     let mut conn = state.pool.get()?;
-    let mut email = db::email::get_email(&mut conn, &id).map_err(|e| match e {
+    open_email(&state, &mut conn, &id, params.mark_read.unwrap_or(true))
+}
+
+/// Sets `read` and `starred` explicitly, without opening the email
+pub async fn update_email(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(flags): Json<db::email::EmailFlags>,
+) -> Result<Json<crate::db::EmailRecord>, WebError> {
+    if flags.is_empty() {
+        return Err(WebError::BadRequest(
+            "Nothing to update, set read or starred".to_string(),
+        ));
+    }
+
+    let mut conn = state.pool.get()?;
+    if db::email::update_email_flags(&mut conn, &id, &flags)? == 0 {
+        return Err(WebError::NotFound);
+    }
+    let email = db::email::get_email(&mut conn, &id)?;
+
+    state
+        .broadcast
+        .send(WebSocketMessage::email_updated(email.clone().into()))
+        .ok();
+    Ok(Json(email))
+}
+
+pub async fn get_email_by_message_id(
+    State(state): State<AppState>,
+    Path(message_id): Path<String>,
+    Query(params): Query<GetEmailParams>,
+) -> Result<Json<crate::db::EmailRecord>, WebError> {
+    let mut conn = state.pool.get()?;
+    let id =
+        db::email::find_email_id_by_message_id(&mut conn, &message_id).map_err(|e| match e {
+            db::DbError::Diesel(diesel::result::Error::NotFound) => WebError::NotFound,
+            _ => WebError::from(e),
+        })?;
+    open_email(&state, &mut conn, &id, params.mark_read.unwrap_or(true))
+}
+
+/// Loads the email and, unless `mark_read` is false, marks it as read like opening it in the UI
+fn open_email(
+    state: &AppState,
+    conn: &mut db::DbConnection,
+    id: &str,
+    mark_read: bool,
+) -> Result<Json<crate::db::EmailRecord>, WebError> {
+    let mut email = db::email::get_email(conn, id).map_err(|e| match e {
         db::DbError::Diesel(diesel::result::Error::NotFound) => WebError::NotFound,
         _ => WebError::from(e),
     })?;
 
-    if !email.read {
-        db::email::mark_email_read(&mut conn, &id, true)?;
+    if mark_read && !email.read {
+        db::email::mark_email_read(conn, id, true)?;
         email.read = true;
 
         let email_list_record: crate::db::EmailListRecord = email.clone().into();
         state
             .broadcast
-            .send(WebSocketMessage {
-                event: WebSocketEvent::EmailRead,
-                email: Some(email_list_record),
-                email_id: None,
-                recipients: None,
-            })
+            .send(WebSocketMessage::email_read(email_list_record))
             .ok();
     }
     Ok(Json(email))
@@ -99,12 +429,7 @@ pub async fn delete_email(
     if demo_mode() {
         state
             .broadcast
-            .send(WebSocketMessage {
-                event: WebSocketEvent::EmailDeleted,
-                email: None,
-                email_id: Some(id),
-                recipients: None,
-            })
+            .send(WebSocketMessage::email_deleted(id))
             .ok();
         return Ok(StatusCode::NO_CONTENT);
     }
@@ -115,12 +440,7 @@ pub async fn delete_email(
     if rows_affected > 0 {
         state
             .broadcast
-            .send(WebSocketMessage {
-                event: WebSocketEvent::EmailDeleted,
-                email: None,
-                email_id: Some(id),
-                recipients: None,
-            })
+            .send(WebSocketMessage::email_deleted(id))
             .ok();
         Ok(StatusCode::NO_CONTENT)
     } else {
@@ -138,6 +458,78 @@ pub async fn delete_emails(State(state): State<AppState>) -> Result<StatusCode,
     Ok(StatusCode::NO_CONTENT)
 }
 
+/// Marks, deletes or tags the emails selected by id or search, all at once
+pub async fn bulk_action(
+    State(state): State<AppState>,
+    Json(request): Json<BulkRequest>,
+) -> Result<Json<BulkResponse>, WebError> {
+    let selection = match (request.ids, request.search.as_deref()) {
+        (Some(ids), None) => db::bulk::BulkSelection::Ids(ids),
+        (None, Some(search)) => {
+            db::bulk::BulkSelection::Search(db::search_query::parse_search_query(search)?)
+        }
+        _ => {
+            return Err(WebError::BadRequest(
+                "Select emails with either ids or search".to_string(),
+            ));
+        }
+    };
+    let action = match request.action {
+        db::bulk::BulkAction::AddTag { tag } => db::bulk::BulkAction::AddTag {
+            tag: tag_names(std::slice::from_ref(&tag))?[0].to_string(),
+        },
+        db::bulk::BulkAction::RemoveTag { tag } => db::bulk::BulkAction::RemoveTag {
+            tag: tag_names(std::slice::from_ref(&tag))?[0].to_string(),
+        },
+        action => action,
+    };
+    // The demo only lets visitors change the read state
+    if demo_mode()
+        && !matches!(
+            action,
+            db::bulk::BulkAction::MarkRead | db::bulk::BulkAction::MarkUnread
+        )
+    {
+        return Ok(Json(BulkResponse { affected: 0 }));
+    }
+
+    let mut conn = state.pool.get()?;
+    let email_ids = db::bulk::run_bulk_action(&mut conn, &selection, &action)?;
+    let affected = email_ids.len();
+    if email_ids.is_empty() {
+        return Ok(Json(BulkResponse { affected }));
+    }
+
+    let message = match action {
+        db::bulk::BulkAction::MarkRead | db::bulk::BulkAction::MarkUnread => {
+            WebSocketMessage::emails_updated(email_ids, action == db::bulk::BulkAction::MarkRead)
+        }
+        db::bulk::BulkAction::Delete => WebSocketMessage::emails_deleted(email_ids),
+        db::bulk::BulkAction::AddTag { .. } | db::bulk::BulkAction::RemoveTag { .. } => {
+            WebSocketMessage::tags_changed(db::tag::email_tags(&mut conn, &email_ids)?)
+        }
+    };
+    state.broadcast.send(message).ok();
+
+    Ok(Json(BulkResponse { affected }))
+}
+
+/// Runs retention and reclaims all free database space right away
+pub async fn run_maintenance(
+    State(state): State<AppState>,
+) -> Result<Json<MaintenanceReport>, WebError> {
+    if demo_mode() {
+        return Ok(Json(MaintenanceReport::default()));
+    }
+
+    let report = state
+        .maintenance
+        .run(true)
+        .await
+        .map_err(WebError::Database)?;
+    Ok(Json(report))
+}
+
 fn demo_mode() -> bool {
     if let Ok(val) = env::var("DEMO_MODE") {
         val == "true"
@@ -201,6 +593,130 @@ pub async fn get_rendered_email(
     Ok((headers, html).into_response())
 }
 
+pub async fn get_email_structure(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<MimePart>, WebError> {
+    let mut conn = state.pool.get()?;
+    let raw_data = db::email::get_raw_data(&mut conn, &id).map_err(|e| match e {
+        db::DbError::Diesel(diesel::result::Error::NotFound) => WebError::NotFound,
+        _ => WebError::from(e),
+    })?;
+
+    let structure = mime::structure(raw_data.as_bytes()).ok_or(WebError::NotFound)?;
+    Ok(Json(structure))
+}
+
+pub async fn get_email_lint(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<LintFinding>>, WebError> {
+    let mut conn = state.pool.get()?;
+    let findings = db::email::get_lint_findings(&mut conn, &id).map_err(|e| match e {
+        db::DbError::Diesel(diesel::result::Error::NotFound) => WebError::NotFound,
+        _ => WebError::from(e),
+    })?;
+    Ok(Json(findings))
+}
+
+pub async fn get_email_part(
+    State(state): State<AppState>,
+    Path((id, path)): Path<(String, String)>,
+    Query(params): Query<PartQueryParams>,
+) -> Result<Response, WebError> {
+    let part_path = mime::parse_path(&path)
+        .ok_or_else(|| WebError::BadRequest(format!("Invalid part path {}", path)))?;
+
+    let mut conn = state.pool.get()?;
+    let raw_data = db::email::get_raw_data(&mut conn, &id).map_err(|e| match e {
+        db::DbError::Diesel(diesel::result::Error::NotFound) => WebError::NotFound,
+        _ => WebError::from(e),
+    })?;
+
+    let part = mime::part_content(raw_data.as_bytes(), &part_path, params.raw.unwrap_or(false))
+        .ok_or(WebError::NotFound)?;
+
+    let headers = untrusted_content_headers(&part.content_type, part.filename.as_deref());
+    Ok((headers, part.data).into_response())
+}
+
+pub async fn get_embedded_emails(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<EmbeddedEmail>>, WebError> {
+    let mut conn = state.pool.get()?;
+    let raw_data = db::email::get_raw_data(&mut conn, &id).map_err(|e| match e {
+        db::DbError::Diesel(diesel::result::Error::NotFound) => WebError::NotFound,
+        _ => WebError::from(e),
+    })?;
+
+    let emails = mime::embedded_emails(raw_data.as_bytes()).unwrap_or_default();
+    Ok(Json(emails))
+}
+
+pub async fn get_embedded_email(
+    State(state): State<AppState>,
+    Path((id, path)): Path<(String, String)>,
+) -> Result<Json<EmbeddedEmail>, WebError> {
+    let part_path = mime::parse_path(&path)
+        .ok_or_else(|| WebError::BadRequest(format!("Invalid part path {}", path)))?;
+
+    let mut conn = state.pool.get()?;
+    let raw_data = db::email::get_raw_data(&mut conn, &id).map_err(|e| match e {
+        db::DbError::Diesel(diesel::result::Error::NotFound) => WebError::NotFound,
+        _ => WebError::from(e),
+    })?;
+
+    let email = mime::embedded_email(raw_data.as_bytes(), &part_path).ok_or(WebError::NotFound)?;
+    Ok(Json(email))
+}
+
+pub async fn get_rendered_embedded_email(
+    State(state): State<AppState>,
+    Path((id, path)): Path<(String, String)>,
+    Query(params): Query<RenderedQueryParams>,
+) -> Result<Response, WebError> {
+    let part_path = mime::parse_path(&path)
+        .ok_or_else(|| WebError::BadRequest(format!("Invalid part path {}", path)))?;
+
+    let mut conn = state.pool.get()?;
+    let raw_data = db::email::get_raw_data(&mut conn, &id).map_err(|e| match e {
+        db::DbError::Diesel(diesel::result::Error::NotFound) => WebError::NotFound,
+        _ => WebError::from(e),
+    })?;
+
+    let email = mime::embedded_email(raw_data.as_bytes(), &part_path).ok_or(WebError::NotFound)?;
+    // Inline images of the embedded email are served as parts of the outer one
+    let cid_map = email
+        .attachments
+        .iter()
+        .filter_map(|part| {
+            let content_id = part.content_id.as_deref()?;
+            let content_id = content_id.trim_start_matches('<').trim_end_matches('>');
+            Some((
+                content_id.to_string(),
+                format!("/api/emails/{}/parts/{}", id, part.path),
+            ))
+        })
+        .collect();
+    let rendered_html = html::render_body(&email.body_html, &cid_map).ok_or(WebError::NotFound)?;
+
+    let allow_remote_content = params.allow_remote_content.unwrap_or(false);
+    let html = if allow_remote_content {
+        rendered_html
+    } else {
+        inject_csp_meta_tag(rendered_html)
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html"),
+    );
+
+    Ok((headers, html).into_response())
+}
+
 pub async fn get_attachment(
     State(state): State<AppState>,
     Path(id): Path<String>,
@@ -233,3 +749,98 @@ pub async fn get_attachment(
 
     Ok((headers, attachment.data).into_response())
 }
+
+/// Headers for serving a MIME part inline on the app's origin. The content security policy
+/// keeps HTML and SVG from running scripts, `nosniff` stops browsers from guessing another type.
+fn untrusted_content_headers(content_type: &str, filename: Option<&str>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        HeaderValue::from_str(content_type)
+            .unwrap_or_else(|_| HeaderValue::from_static("application/octet-stream")),
+    );
+    headers.insert(
+        axum::http::header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(crate::csp::CSP),
+    );
+    headers.insert(
+        axum::http::header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+
+    if let Some(filename) = filename {
+        headers.insert(
+            axum::http::header::CONTENT_DISPOSITION,
+            HeaderValue::from_str(&inline_content_disposition(filename))
+                .unwrap_or_else(|_| HeaderValue::from_static("inline")),
+        );
+    }
+    headers
+}
+
+/// `inline` with the filename as RFC 6266 describes it: an ASCII fallback in `filename` and the
+/// exact name percent-encoded in `filename*`
+fn inline_content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect();
+    format!(
+        "inline; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_untrusted_content_headers_block_scripts() {
+        let headers = untrusted_content_headers("text/html", None);
+        assert_eq!(headers["content-type"], "text/html");
+        assert_eq!(headers["content-security-policy"], crate::csp::CSP);
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+        assert!(!headers.contains_key("content-disposition"));
+    }
+
+    #[test]
+    fn test_inline_content_disposition_escapes_filename() {
+        assert_eq!(
+            inline_content_disposition("report.pdf"),
+            "inline; filename=\"report.pdf\"; filename*=UTF-8''report.pdf"
+        );
+        assert_eq!(
+            inline_content_disposition("a\"; b.html"),
+            "inline; filename=\"a_; b.html\"; filename*=UTF-8''a%22%3B%20b.html"
+        );
+        assert_eq!(
+            inline_content_disposition("Grüße\r\n.txt"),
+            "inline; filename=\"Gr__e__.txt\"; filename*=UTF-8''Gr%C3%BC%C3%9Fe%0D%0A.txt"
+        );
+    }
+}
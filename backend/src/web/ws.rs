@@ -12,7 +12,15 @@ use crate::web::AppState;
 pub enum WebSocketEvent {
     NewMail,
     EmailRead,
+    /// `email` changed its read or starred state
+    EmailUpdated,
     EmailDeleted,
+    EmailsDeleted,
+    SavedSearchCounts,
+    TagsChanged,
+    EmailsUpdated,
+    /// `email_ids` left the inbox of `recipients`, but stay in the other inboxes
+    InboxCleared,
 }
 
 #[derive(serde::Serialize, Clone)]
@@ -23,7 +31,98 @@ pub struct WebSocketMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub email_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_ids: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub recipients: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saved_searches: Option<Vec<crate::db::counts::SavedSearchStats>>,
+    /// The new tags of every changed email
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_tags: Option<Vec<crate::db::tag::EmailTags>>,
+    /// New read state of `email_ids`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read: Option<bool>,
+}
+
+impl WebSocketMessage {
+    fn new(event: WebSocketEvent) -> Self {
+        Self {
+            event,
+            email: None,
+            email_id: None,
+            email_ids: None,
+            recipients: None,
+            saved_searches: None,
+            email_tags: None,
+            read: None,
+        }
+    }
+
+    pub fn new_mail(email: crate::db::EmailListRecord) -> Self {
+        Self {
+            recipients: Some(email.recipients.clone()),
+            email: Some(email),
+            ..Self::new(WebSocketEvent::NewMail)
+        }
+    }
+
+    pub fn email_read(email: crate::db::EmailListRecord) -> Self {
+        Self {
+            email: Some(email),
+            ..Self::new(WebSocketEvent::EmailRead)
+        }
+    }
+
+    pub fn email_updated(email: crate::db::EmailListRecord) -> Self {
+        Self {
+            email: Some(email),
+            ..Self::new(WebSocketEvent::EmailUpdated)
+        }
+    }
+
+    pub fn email_deleted(email_id: String) -> Self {
+        Self {
+            email_id: Some(email_id),
+            ..Self::new(WebSocketEvent::EmailDeleted)
+        }
+    }
+
+    pub fn emails_deleted(email_ids: Vec<String>) -> Self {
+        Self {
+            email_ids: Some(email_ids),
+            ..Self::new(WebSocketEvent::EmailsDeleted)
+        }
+    }
+
+    pub fn emails_updated(email_ids: Vec<String>, read: bool) -> Self {
+        Self {
+            email_ids: Some(email_ids),
+            read: Some(read),
+            ..Self::new(WebSocketEvent::EmailsUpdated)
+        }
+    }
+
+    pub fn inbox_cleared(email_ids: Vec<String>, recipients: Vec<String>) -> Self {
+        Self {
+            email_ids: Some(email_ids),
+            recipients: Some(recipients),
+            ..Self::new(WebSocketEvent::InboxCleared)
+        }
+    }
+
+    pub fn saved_search_counts(saved_searches: Vec<crate::db::counts::SavedSearchStats>) -> Self {
+        Self {
+            saved_searches: Some(saved_searches),
+            ..Self::new(WebSocketEvent::SavedSearchCounts)
+        }
+    }
+
+    pub fn tags_changed(email_tags: Vec<crate::db::tag::EmailTags>) -> Self {
+        Self {
+            email_tags: Some(email_tags),
+            ..Self::new(WebSocketEvent::TagsChanged)
+        }
+    }
 }
 
 pub type BroadcastSender = broadcast::Sender<WebSocketMessage>;
@@ -60,7 +159,8 @@ async fn handle_socket(socket: axum::extract::ws::WebSocket, state: AppState) {
                     Some(Ok(msg)) => {
                         match msg {
                             axum::extract::ws::Message::Ping(payload) => {
-                                if sender.send(axum::extract::ws::Message::Pong(payload)).await.is_err() {
+                                let pong = axum::extract::ws::Message::Pong(payload);
+                                if sender.send(pong).await.is_err() {
                                     break;
                                 }
                             }
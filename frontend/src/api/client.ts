@@ -1,4 +1,15 @@
-import type { EmailRecord, EmailListResponse, EmailCounts } from '@/types/email'
+import type {
+  EmailRecord,
+  EmbeddedEmail,
+  LintFinding,
+  EmailListResponse,
+  EmailCounts,
+  SavedSearch,
+  TagCount,
+  EmailTags,
+  BulkAction,
+  BulkSelection,
+} from '@/types/email'
 
 export class ApiError extends Error {
   readonly status: number
@@ -10,6 +21,14 @@ export class ApiError extends Error {
   }
 }
 
+// The list is scrolled by cursor, so it never needs the total count
+const listParams = (cursor?: string, search?: string): URLSearchParams => {
+  const params = new URLSearchParams({ count: 'false' })
+  if (cursor) params.set('cursor', cursor)
+  if (search) params.set('search', search)
+  return params
+}
+
 export class ApiClient {
   private baseUrl: string
   private defaultHeaders: HeadersInit
@@ -37,9 +56,12 @@ export class ApiClient {
     const response = await fetch(url, config)
 
     if (!response.ok) {
+      // Bad requests explain what was wrong, e.g. a search query that cannot be parsed
+      const message = response.status === 400 ? await response.text() : ''
       throw new ApiError(
         response.status,
-        `API request failed: ${response.status} ${response.statusText}`
+        message ||
+          `API request failed: ${response.status} ${response.statusText}`
       )
     }
 
@@ -53,27 +75,57 @@ export class ApiClient {
     return response.json()
   }
 
-  async inbox(page: number = 1, search?: string): Promise<EmailListResponse> {
-    const params = new URLSearchParams()
-    params.set('page', page.toString())
-    if (search) params.set('search', search)
+  async inbox(cursor?: string, search?: string): Promise<EmailListResponse> {
+    const params = listParams(cursor, search)
     return this.request<EmailListResponse>(`/api/emails?${params.toString()}`)
   }
 
   async inboxByRecipient(
     recipient: string,
-    page: number = 1,
+    cursor?: string,
     search?: string
   ): Promise<EmailListResponse> {
-    const params = new URLSearchParams()
-    params.set('page', page.toString())
-    if (search) params.set('search', search)
+    const params = listParams(cursor, search)
     const encodedRecipient = encodeURIComponent(recipient)
     return this.request<EmailListResponse>(
       `/api/emails/inbox/${encodedRecipient}?${params.toString()}`
     )
   }
 
+  async savedSearchEmails(
+    id: string,
+    cursor?: string,
+    search?: string
+  ): Promise<EmailListResponse> {
+    const params = listParams(cursor, search)
+    return this.request<EmailListResponse>(
+      `/api/saved-searches/${encodeURIComponent(id)}/emails?${params.toString()}`
+    )
+  }
+
+  async createSavedSearch(name: string, query: string): Promise<SavedSearch> {
+    return this.request<SavedSearch>('/api/saved-searches', {
+      method: 'POST',
+      body: JSON.stringify({ name, query }),
+    })
+  }
+
+  async deleteSavedSearch(id: string): Promise<void> {
+    return this.request<void>(
+      `/api/saved-searches/${encodeURIComponent(id)}`,
+      { method: 'DELETE' }
+    )
+  }
+
+  async clearInbox(
+    recipient: string
+  ): Promise<{ deleted: number; unlinked: number }> {
+    return this.request<{ deleted: number; unlinked: number }>(
+      `/api/emails/inbox/${encodeURIComponent(recipient)}`,
+      { method: 'DELETE' }
+    )
+  }
+
   async getCounts(): Promise<EmailCounts> {
     return this.request<EmailCounts>('/api/counts')
   }
@@ -82,6 +134,16 @@ export class ApiClient {
     return this.request<EmailRecord>(`/api/emails/${id}`)
   }
 
+  async updateEmail(
+    id: string,
+    flags: { read?: boolean; starred?: boolean }
+  ): Promise<EmailRecord> {
+    return this.request<EmailRecord>(`/api/emails/${id}`, {
+      method: 'PATCH',
+      body: JSON.stringify(flags),
+    })
+  }
+
   async deleteEmail(id: string): Promise<void> {
     return this.request<void>(`/api/emails/${id}`, {
       method: 'DELETE',
@@ -94,6 +156,50 @@ export class ApiClient {
     })
   }
 
+  async bulk(
+    selection: BulkSelection,
+    action: BulkAction
+  ): Promise<{ affected: number }> {
+    return this.request<{ affected: number }>('/api/emails/bulk', {
+      method: 'POST',
+      body: JSON.stringify({ ...selection, ...action }),
+    })
+  }
+
+  async getTags(): Promise<TagCount[]> {
+    return this.request<TagCount[]>('/api/tags')
+  }
+
+  async addTags(id: string, tags: string[]): Promise<EmailTags> {
+    return this.request<EmailTags>(`/api/emails/${id}/tags`, {
+      method: 'POST',
+      body: JSON.stringify({ tags }),
+    })
+  }
+
+  async removeTag(id: string, tag: string): Promise<void> {
+    return this.request<void>(
+      `/api/emails/${id}/tags/${encodeURIComponent(tag)}`,
+      { method: 'DELETE' }
+    )
+  }
+
+  async getEmbeddedEmails(id: string): Promise<EmbeddedEmail[]> {
+    return this.request<EmbeddedEmail[]>(`/api/emails/${id}/messages`)
+  }
+
+  async getLint(id: string): Promise<LintFinding[]> {
+    return this.request<LintFinding[]>(`/api/emails/${id}/lint`)
+  }
+
+  getEmbeddedEmailUrl(id: string, path: string): string {
+    return `${this.baseUrl}/api/emails/${id}/messages/${path}/rendered`
+  }
+
+  getPartUrl(id: string, path: string): string {
+    return `${this.baseUrl}/api/emails/${id}/parts/${path}`
+  }
+
   getAttachmentUrl(id: string): string {
     return `${this.baseUrl}/api/attachments/${id}`
   }
@@ -103,9 +209,12 @@ export class ApiClient {
     const response = await fetch(url)
 
     if (!response.ok) {
+      // Bad requests explain what was wrong, e.g. a search query that cannot be parsed
+      const message = response.status === 400 ? await response.text() : ''
       throw new ApiError(
         response.status,
-        `API request failed: ${response.status} ${response.statusText}`
+        message ||
+          `API request failed: ${response.status} ${response.statusText}`
       )
     }
 
@@ -0,0 +1,10 @@
+import type { RouteLocationNormalizedLoaded } from 'vue-router'
+
+// Path of the list the current route belongs to, e.g. to open an email within it
+export const listPath = (route: RouteLocationNormalizedLoaded) => {
+  const recipient = route.params.recipient as string | undefined
+  const savedSearch = route.params.savedSearch as string | undefined
+  if (savedSearch) return `/emails/saved/${encodeURIComponent(savedSearch)}`
+  if (recipient) return `/emails/inbox/${encodeURIComponent(recipient)}`
+  return '/emails/inbox'
+}
@@ -21,6 +21,11 @@ const routes = [
     component: EmailLayout,
     children: [{ path: 'email/:id', component: EmailView }],
   },
+  {
+    path: '/emails/saved/:savedSearch',
+    component: EmailLayout,
+    children: [{ path: 'email/:id', component: EmailView }],
+  },
   {
     path: '/redirect_first_email',
     component: EmailLayout,
@@ -29,7 +34,7 @@ const routes = [
       _from: RouteLocationNormalized,
       next: NavigationGuardNext
     ) => {
-      const response = await apiClient.inbox(1)
+      const response = await apiClient.inbox()
       const first = response.emails[0]
       if (first) {
         next({ path: `/emails/inbox/email/${first.id}`, query: to.query })
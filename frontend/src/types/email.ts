@@ -8,12 +8,72 @@ export interface Attachment {
   created_at: string
 }
 
+export interface EmailAddress {
+  name: string | null // decoded display name
+  address: string | null
+}
+
+export interface HeaderRecord {
+  name: string // casing as in the message
+  value: string
+  decoded: string // unfolded, RFC 2047 encoded-words decoded
+  raw: string // the whole field, including folding and line break
+}
+
+// Something the parser had to skip or guess, e.g. code bare_lf
+export interface ParseWarning {
+  code: string
+  message: string
+}
+
+// An RFC 5322 or MIME problem, errors may get the email rejected by strict providers
+export interface LintFinding {
+  severity: 'error' | 'warning'
+  code: string
+  message: string
+}
+
+export interface MimePart {
+  path: string // e.g. 1.2.1, the email itself is 1
+  content_type: string | null
+  charset: string | null
+  transfer_encoding: string | null
+  disposition: string | null
+  filename: string | null
+  content_id: string | null
+  size: number // decoded body
+  raw_size: number // as received, headers included
+  parts?: MimePart[]
+}
+
+// An email attached as a message/rfc822 part
+export interface EmbeddedEmail {
+  path: string
+  message_id: string | null
+  subject: string | null
+  date: string | null
+  headers: Record<string, string[]>
+  ordered_headers: HeaderRecord[]
+  from: EmailAddress[]
+  to: EmailAddress[]
+  cc: EmailAddress[]
+  reply_to: EmailAddress[]
+  body_text: string
+  body_html: string
+  attachments: MimePart[]
+}
+
 export interface EmailRecord {
   id: string
   message_id: string | null
   subject: string | null
   date: string | null
   headers: Record<string, string[]>
+  ordered_headers: HeaderRecord[]
+  from: EmailAddress[]
+  to: EmailAddress[]
+  cc: EmailAddress[]
+  reply_to: EmailAddress[]
   created_at: string
   envelope_from: string // SMTP envelope sender (MAIL FROM)
   recipients: string[] // SMTP envelope recipients (RCPT TO)
@@ -21,7 +81,11 @@ export interface EmailRecord {
   body_text: string | null
   body_html: string | null
   read: boolean
+  starred: boolean
+  thread_id: string
   attachments: Attachment[]
+  tags: string[]
+  warnings: ParseWarning[]
 }
 
 export interface EmailListRecord {
@@ -32,8 +96,18 @@ export interface EmailListRecord {
   envelope_from: string
   recipients: string[]
   read: boolean
+  starred: boolean
   has_attachments: boolean
+  thread_id: string
+  thread_size?: number // only in the thread view
   to_header: string[] | null
+  from: EmailAddress[]
+  to: EmailAddress[]
+  cc: EmailAddress[]
+  reply_to: EmailAddress[]
+  tags: string[]
+  score?: number // search relevance, higher is better
+  snippet?: string // HTML escaped, search matches wrapped in <mark>
 }
 
 export interface RecipientCount {
@@ -41,16 +115,54 @@ export interface RecipientCount {
   count: number
 }
 
+export interface TagCount {
+  name: string
+  count: number
+}
+
+export interface EmailTags {
+  email_id: string
+  tags: string[]
+}
+
+export type BulkAction =
+  | { action: 'mark_read' }
+  | { action: 'mark_unread' }
+  | { action: 'delete' }
+  | { action: 'add_tag'; tag: string }
+  | { action: 'remove_tag'; tag: string }
+
+// Emails are selected by ids or by a search, never both
+export type BulkSelection = { ids: string[] } | { search: string }
+
+export interface SavedSearch {
+  id: string
+  name: string
+  query: string
+  created_at: string
+  updated_at: string
+}
+
+export interface SavedSearchCount {
+  id: string
+  name: string
+  count: number
+  unread: number
+}
+
 export interface EmailCounts {
   inbox: number
   unread: number
   recipients: RecipientCount[]
+  saved_searches: SavedSearchCount[]
 }
 
 export interface PaginationInfo {
   page: number
   per_page: number
-  total_pages: number
+  total_pages?: number
+  total_count?: number
+  next_cursor: string | null
 }
 
 export interface EmailListResponse {
@@ -1,4 +1,15 @@
 import { decodeMimeWords } from 'lettercoder'
+import type { EmailAddress } from '@/types/email'
+
+// Addresses parsed by the backend, names are already decoded
+export function formatAddress({ name, address }: EmailAddress): string {
+  if (name && address) return `${name} <${address}>`
+  return name || address || ''
+}
+
+export function formatAddresses(addresses: EmailAddress[]): string[] {
+  return addresses.map(formatAddress)
+}
 
 export function decodeAddress(address: string): string {
   const parts = address.match(/^(.+?)\s*<(.+)>$/i)